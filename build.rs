@@ -0,0 +1,44 @@
+use std::process::Command;
+
+/// Dependencies whose locked versions are reported by `overbot build-info`
+const REPORTED_DEPENDENCIES: &[(&str, &str)] = &[
+	("rocket", "OVERBOT_ROCKET_VERSION"),
+	("sqlx", "OVERBOT_SQLX_VERSION"),
+];
+
+fn main() {
+	println!("cargo:rerun-if-changed=.git/HEAD");
+	println!("cargo:rerun-if-changed=.git/index");
+	println!("cargo:rerun-if-changed=Cargo.lock");
+
+	let git_hash = Command::new("git")
+		.args(&["rev-parse", "--short", "HEAD"])
+		.output()
+		.ok()
+		.filter(|output| output.status.success())
+		.and_then(|output| String::from_utf8(output.stdout).ok())
+		.map(|hash| hash.trim().to_owned())
+		.filter(|hash| !hash.is_empty())
+		.unwrap_or_else(|| "unknown".to_owned());
+	println!("cargo:rustc-env=OVERBOT_GIT_HASH={}", git_hash);
+
+	let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+	for (name, env) in REPORTED_DEPENDENCIES {
+		println!(
+			"cargo:rustc-env={}={}",
+			env,
+			locked_version(&lock, name).unwrap_or("unknown")
+		);
+	}
+}
+
+/// The version of the `[[package]]` named `name` in `Cargo.lock`
+fn locked_version<'a>(lock: &'a str, name: &str) -> Option<&'a str> {
+	let name_line = format!("name = \"{}\"", name);
+	let mut lines = lock.lines();
+	lines.find(|line| *line == name_line)?;
+	lines
+		.next()?
+		.strip_prefix("version = \"")?
+		.strip_suffix('"')
+}
@@ -1,44 +1,456 @@
 use crate::dash_type_map::DashTypeMap;
 use crate::database::{DbPool, DbTransaction, Migration, Migrations};
-use crate::system::{QuitOnError, System};
+use crate::metrics::{LoginMetric, Metrics};
+use crate::system::{ConfigError, QuitOnError, System, SystemPlugin};
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use hmac::{Hmac, Mac, NewMac};
+use rand::distributions::Alphanumeric;
+use rand::{Rng, RngCore};
+use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 use std::sync::Arc;
-use time::{Duration, OffsetDateTime};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
 use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
 use tracing::*;
 use uuid::Uuid;
 
-#[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
-pub struct AccountsConfig {}
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct AccountsConfig {
+	/// How often expired sessions are deleted from the database
+	session_cleanup_interval: std::time::Duration,
+	/// How many previous password hashes to keep to prevent password reuse
+	password_history: usize,
+	/// Cost parameters for newly hashed passwords, existing hashes keep the parameters they were
+	/// hashed with
+	argon2: Argon2Params,
+	/// Key that TOTP secrets are encrypted with in the database, required, randomly generated when
+	/// the configuration is first written out, changing it makes every enrolled TOTP secret
+	/// unreadable
+	totp_secret_key: String,
+	/// Issuer shown in authenticator apps
+	totp_issuer: String,
+	/// How long a password reset token can be used for after it is created
+	reset_token_valid_duration: std::time::Duration,
+	/// How long a remember me refresh token can renew sessions for, refreshing doesn't extend it
+	refresh_token_valid_duration: std::time::Duration,
+	/// Logins that are given the `admin` role on startup, logins that don't exist yet are skipped
+	seed_admin_logins: Vec<String>,
+	/// Rules new passwords must follow
+	password_policy: PasswordPolicy,
+	/// Rejects new passwords that appear in known breaches
+	breached_password_check: BreachedPasswordCheck,
+	/// Rules new and renamed logins must follow
+	login_names: LoginNamePolicy,
+	/// Consecutive failed logins that lock the account, regardless of where they came from, 0
+	/// never locks
+	lockout_threshold: u32,
+	/// How long a locked account refuses every login, even with the right password
+	lockout_duration: std::time::Duration,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct LoginNamePolicy {
+	/// Fewest characters a login can have
+	min_length: usize,
+	/// Most characters a login can have
+	max_length: usize,
+	/// Allow any unicode letters and digits instead of only ASCII ones, logins must already be NFKC
+	/// normalized and can't mix ASCII letters with other letters so lookalikes can't be registered
+	allow_unicode: bool,
+}
+
+impl Default for LoginNamePolicy {
+	fn default() -> Self {
+		Self {
+			min_length: 1,
+			max_length: 64,
+			allow_unicode: false,
+		}
+	}
+}
+
+impl LoginNamePolicy {
+	pub fn check(&self, login: &str) -> Result<(), AccountsError> {
+		let length = login.chars().count();
+		if length < self.min_length {
+			return Err(AccountsError::LoginNameTooShort(self.min_length));
+		}
+		if length > self.max_length {
+			return Err(AccountsError::LoginNameTooLong(self.max_length));
+		}
+		let valid = if self.allow_unicode {
+			use unicode_normalization::UnicodeNormalization;
+			let normalized = login.nfkc().eq(login.chars());
+			let mixes_scripts = login.chars().any(|c| c.is_ascii_alphabetic())
+				&& login.chars().any(|c| c.is_alphabetic() && !c.is_ascii());
+			normalized && !mixes_scripts && login.chars().all(|c| c.is_alphanumeric() || c == '_')
+		} else {
+			login.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+		};
+		if valid {
+			Ok(())
+		} else {
+			Err(AccountsError::InvalidLoginName(login.to_owned()))
+		}
+	}
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+	/// Fewest characters a password can have
+	min_length: usize,
+	/// Most characters a password can have, hashing very long passwords is needlessly costly
+	max_length: usize,
+	require_lowercase: bool,
+	require_uppercase: bool,
+	require_digit: bool,
+	/// Requires a character that is neither a letter, a digit, nor whitespace
+	require_symbol: bool,
+	/// Rejects passwords containing the account's login, ignoring case
+	ban_login: bool,
+	/// Rejects passwords containing any of these, ignoring case
+	banned_substrings: Vec<String>,
+}
+
+impl Default for PasswordPolicy {
+	fn default() -> Self {
+		Self {
+			min_length: 13,
+			max_length: 1024,
+			require_lowercase: false,
+			require_uppercase: false,
+			require_digit: false,
+			require_symbol: false,
+			ban_login: true,
+			banned_substrings: vec!["password".to_owned(), "overbot".to_owned()],
+		}
+	}
+}
+
+impl PasswordPolicy {
+	/// Describes the first rule the password breaks
+	pub fn check(&self, password: &str, login: Option<&str>) -> Result<(), String> {
+		let length = password.chars().count();
+		if length < self.min_length {
+			return Err(format!(
+				"must be at least {} characters long",
+				self.min_length
+			));
+		}
+		if length > self.max_length {
+			return Err(format!(
+				"must be at most {} characters long",
+				self.max_length
+			));
+		}
+		if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+			return Err("must contain a lowercase letter".to_owned());
+		}
+		if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+			return Err("must contain an uppercase letter".to_owned());
+		}
+		if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+			return Err("must contain a digit".to_owned());
+		}
+		if self.require_symbol
+			&& !password
+				.chars()
+				.any(|c| !c.is_alphanumeric() && !c.is_whitespace())
+		{
+			return Err("must contain a symbol".to_owned());
+		}
+		let lowercase = password.to_lowercase();
+		if self.ban_login {
+			if let Some(login) = login.filter(|login| !login.is_empty()) {
+				if lowercase.contains(&login.to_lowercase()) {
+					return Err("must not contain the login".to_owned());
+				}
+			}
+		}
+		if let Some(banned) = self
+			.banned_substrings
+			.iter()
+			.find(|banned| !banned.is_empty() && lowercase.contains(&banned.to_lowercase()))
+		{
+			return Err(format!("must not contain `{}`", banned));
+		}
+		Ok(())
+	}
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct BreachedPasswordCheck {
+	/// Whether new passwords are looked up at all
+	enabled: bool,
+	/// HaveIBeenPwned compatible range API, the first 5 hex characters of the password's SHA-1
+	/// are appended to it, never the password or its full hash
+	range_url: String,
+	/// How long the lookup can take before the service is treated as unreachable
+	timeout: std::time::Duration,
+	/// Accept the password when the service is unreachable instead of refusing the change
+	fail_open: bool,
+}
+
+impl Default for BreachedPasswordCheck {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			range_url: "https://api.pwnedpasswords.com/range/".to_owned(),
+			timeout: std::time::Duration::from_secs(3),
+			fail_open: true,
+		}
+	}
+}
+
+impl BreachedPasswordCheck {
+	/// Rejects passwords the range API reports as breached, only ever sending the first 5
+	/// characters of the SHA-1 hash (k-anonymity)
+	pub async fn check(&self, password: &str) -> Result<(), AccountError> {
+		if !self.enabled {
+			return Ok(());
+		}
+		use sha1::Digest;
+		let hash: String = sha1::Sha1::digest(password.as_bytes())
+			.iter()
+			.map(|b| format!("{:02X}", b))
+			.collect();
+		let (prefix, suffix) = hash.split_at(5);
+		match tokio::time::timeout(self.timeout, self.fetch_range(prefix)).await {
+			Ok(Ok(range)) => {
+				let breached = range.lines().any(|line| {
+					// `SUFFIX:COUNT`, padding entries have a count of 0
+					let mut parts = line.trim().splitn(2, ':');
+					parts
+						.next()
+						.map_or(false, |s| s.eq_ignore_ascii_case(suffix))
+						&& parts
+							.next()
+							.and_then(|count| count.trim().parse::<u64>().ok())
+							.map_or(true, |count| count > 0)
+				});
+				if breached {
+					Err(AccountError::InvalidNewPassword(
+						"appears in a known data breach".to_owned(),
+					))
+				} else {
+					Ok(())
+				}
+			}
+			Ok(Err(e)) => self.unavailable(e.to_string()),
+			Err(_elapsed) => self.unavailable(format!("timed out after {:?}", self.timeout)),
+		}
+	}
+
+	/// The client is built per check as they're rare, and a failed build is then just the
+	/// service being unavailable
+	async fn fetch_range(&self, prefix: &str) -> reqwest::Result<String> {
+		reqwest::Client::builder()
+			.user_agent(concat!("overbot/", env!("CARGO_PKG_VERSION")))
+			.build()?
+			.get(format!("{}{}", self.range_url, prefix))
+			// Pads the response so its size doesn't hint at the prefix either
+			.header("Add-Padding", "true")
+			.timeout(self.timeout)
+			.send()
+			.await?
+			.error_for_status()?
+			.text()
+			.await
+	}
+
+	fn unavailable(&self, reason: String) -> Result<(), AccountError> {
+		if self.fail_open {
+			warn!(
+				"Breached password check unavailable, accepting the password: {}",
+				reason
+			);
+			Ok(())
+		} else {
+			Err(AccountError::BreachCheckUnavailable(reason))
+		}
+	}
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Argon2Params {
+	/// Memory cost in KiB
+	memory_kib: u32,
+	/// Time cost as the number of iterations
+	iterations: u32,
+	/// Degree of parallelism
+	lanes: u32,
+}
+
+impl Default for Argon2Params {
+	fn default() -> Self {
+		// Same as the argon2 crate defaults
+		Self {
+			memory_kib: 4096,
+			iterations: 3,
+			lanes: 1,
+		}
+	}
+}
+
+impl Argon2Params {
+	pub fn new(memory_kib: u32, iterations: u32, lanes: u32) -> Self {
+		Self {
+			memory_kib,
+			iterations,
+			lanes,
+		}
+	}
+
+	pub fn build(&self) -> Result<Argon2<'static>, AccountError> {
+		Argon2::new(
+			None,
+			self.iterations,
+			self.memory_kib,
+			self.lanes,
+			argon2::Version::V0x13,
+		)
+		.map_err(AccountError::Argon2Params)
+	}
+}
+
+impl Default for AccountsConfig {
+	fn default() -> Self {
+		Self {
+			session_cleanup_interval: std::time::Duration::from_secs(60 * 60),
+			password_history: 5,
+			argon2: Argon2Params::default(),
+			// Never generated here as a missing key would silently be replaced on every load
+			totp_secret_key: String::new(),
+			totp_issuer: "Overbot".to_owned(),
+			reset_token_valid_duration: std::time::Duration::from_secs(60 * 60),
+			refresh_token_valid_duration: std::time::Duration::from_secs(30 * 24 * 60 * 60),
+			seed_admin_logins: vec![],
+			password_policy: PasswordPolicy::default(),
+			breached_password_check: BreachedPasswordCheck::default(),
+			login_names: LoginNamePolicy::default(),
+			lockout_threshold: 10,
+			lockout_duration: std::time::Duration::from_secs(15 * 60),
+		}
+	}
+}
 
 impl AccountsConfig {
+	/// The defaults with a freshly generated `totp_secret_key`, for writing out a new configuration
 	pub fn new() -> Self {
-		Self {}
+		Self {
+			totp_secret_key: rand::thread_rng()
+				.sample_iter(&Alphanumeric)
+				.take(48)
+				.map(char::from)
+				.collect(),
+			..Self::default()
+		}
+	}
+
+	/// Adds every problem with the configuration to `errors`
+	pub fn validate(&self, errors: &mut Vec<ConfigError>) {
+		if self.totp_secret_key.trim().is_empty() {
+			errors.push(ConfigError::new(
+				"accounts.totp_secret_key",
+				"must be set, generate one with the `generate-secret-key` command, and keep it as changing it makes every enrolled TOTP secret unreadable",
+			));
+		}
+	}
+
+	pub fn refresh_token_valid_duration(&self) -> std::time::Duration {
+		self.refresh_token_valid_duration
 	}
 
 	pub async fn runner(
-		_config: AccountsConfig,
-		_db_pool: DbPool,
-		_data: Arc<DashTypeMap>,
+		config: AccountsConfig,
+		db_pool: DbPool,
+		allow_checksum_override: bool,
+		data: Arc<DashTypeMap>,
 		quit: broadcast::Sender<()>,
 	) -> anyhow::Result<()> {
-		quit.subscribe().recv().await?;
+		MIGRATIONS
+			.migrate_up(&db_pool, allow_checksum_override)
+			.await
+			.quit_on_err(&quit)?;
+		config.seed_admins(&db_pool).await.quit_on_err(&quit)?;
+		// A mailer or similar can register its own sender before this point
+		if !data.contains::<Arc<dyn ResetTokenSender>>() {
+			data.insert::<Arc<dyn ResetTokenSender>>(
+				Arc::new(ReturnResetToken) as Arc<dyn ResetTokenSender>
+			)
+			.quit_on_err(&quit)?;
+		}
+		// Inserted last as others wait on it to know the accounts tables are ready
+		data.insert::<Arc<AccountsConfig>>(Arc::new(config.clone()))
+			.quit_on_err(&quit)?;
+		let _registered = AccountsConfigGuard(data.clone());
+		let mut on_quit = quit.subscribe();
+		let mut cleanup = tokio::time::interval(config.session_cleanup_interval);
+		loop {
+			tokio::select! {
+				_ = on_quit.recv() => break,
+				_ = cleanup.tick() => {
+					if let Err(e) = Self::cleanup_sessions(&db_pool).await {
+						error!("Failed cleaning up expired sessions: {}", e);
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	async fn seed_admins(&self, db_pool: &DbPool) -> anyhow::Result<()> {
+		let mut conn = db_pool.begin().await?;
+		for login in &self.seed_admin_logins {
+			if !Accounts::grant_role_by_login(&mut conn, login, AdminRole::NAME).await? {
+				warn!("Account to seed the admin role to doesn't exist: {}", login);
+			}
+		}
+		conn.commit().await?;
+		Ok(())
+	}
+
+	async fn cleanup_sessions(db_pool: &DbPool) -> anyhow::Result<()> {
+		let mut conn = db_pool.begin().await?;
+		let pruned = Accounts::prune_expired_sessions(&mut conn).await?;
+		conn.commit().await?;
+		if pruned > 0 {
+			info!(
+				"Pruned {} expired account sessions and refresh tokens",
+				pruned
+			);
+		}
 		Ok(())
 	}
+}
+
+/// Unregisters the accounts configuration when the runner ends, even by panic, so a restarted
+/// runner can register it again
+struct AccountsConfigGuard(Arc<DashTypeMap>);
 
-	pub async fn spawn(&self, system: &System) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
-		let db_pool = system.db_pool.clone();
-		let quit = system.quit.clone();
-		MIGRATIONS.migrate_up(&db_pool).await.quit_on_err(&quit)?;
-		Ok(tokio::spawn(Self::runner(
+impl Drop for AccountsConfigGuard {
+	fn drop(&mut self) {
+		let _ = self.0.remove::<Arc<AccountsConfig>>();
+	}
+}
+
+#[typetag::serde]
+impl SystemPlugin for AccountsConfig {
+	fn spawn(&self, system: &System) -> Option<JoinHandle<anyhow::Result<()>>> {
+		Some(tokio::spawn(Self::runner(
 			self.clone(),
-			db_pool,
+			system.db_pool.clone(),
+			system.allow_checksum_override(),
 			system.registered_data.clone(),
-			quit,
+			system.quit.clone(),
 		)))
 	}
 }
@@ -48,15 +460,159 @@ pub struct Account {
 	login: Option<String>,
 }
 
+/// A named role an account can have, used to require roles statically such as with
+/// `RequireRole<'_, AdminRole>`
+pub trait Role: Send + Sync + 'static {
+	const NAME: &'static str;
+}
+
+#[derive(Debug)]
+pub struct AdminRole;
+
+impl Role for AdminRole {
+	const NAME: &'static str = "admin";
+}
+
+/// Delivers password reset tokens to whoever owns the account, registered in the `DashTypeMap` as
+/// an `Arc<dyn ResetTokenSender>`
+pub trait ResetTokenSender: Send + Sync {
+	/// Returns the token back if it wasn't delivered out of band, in which case it's up to the
+	/// caller to hand it over
+	fn send(
+		&self,
+		account_id: Uuid,
+		login: &str,
+		token: Uuid,
+		valid_until: OffsetDateTime,
+	) -> anyhow::Result<Option<Uuid>>;
+}
+
+/// Doesn't deliver anything, just hands the token back to the caller
+pub struct ReturnResetToken;
+
+impl ResetTokenSender for ReturnResetToken {
+	fn send(
+		&self,
+		_account_id: Uuid,
+		_login: &str,
+		token: Uuid,
+		_valid_until: OffsetDateTime,
+	) -> anyhow::Result<Option<Uuid>> {
+		Ok(Some(token))
+	}
+}
+
+/// A freshly generated, not yet confirmed, TOTP secret
+#[derive(Debug, serde::Serialize)]
+pub struct TotpEnrollment {
+	/// Base32 secret for manual entry into an authenticator
+	pub secret: String,
+	/// `otpauth://` provisioning URI, meant to be rendered as a QR code
+	pub provisioning_uri: String,
+}
+
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP_SECS: u64 = 30;
+/// How many time steps before and after the current one are still accepted for clock drift
+const TOTP_DRIFT_STEPS: i64 = 1;
+const TOTP_SECRET_BYTES: usize = 20;
+/// RFC 4648 base32, as authenticator apps expect secrets in
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Unpadded base32
+fn base32_encode(bytes: &[u8]) -> String {
+	let mut encoded = String::with_capacity((bytes.len() * 8 + 4) / 5);
+	let mut buffer = 0u16;
+	let mut bits = 0;
+	for &byte in bytes {
+		buffer = (buffer << 8) | byte as u16;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+	}
+	encoded
+}
+
+/// Base32 of either case with any padding, `None` if it has any other characters
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+	let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+	let mut buffer = 0u16;
+	let mut bits = 0;
+	for c in encoded.trim_end_matches('=').bytes() {
+		let value = BASE32_ALPHABET
+			.iter()
+			.position(|&a| a == c.to_ascii_uppercase())?;
+		buffer = (buffer << 5) | value as u16;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			decoded.push((buffer >> bits) as u8);
+		}
+	}
+	Some(decoded)
+}
+
+/// The RFC 6238 code of the secret for the time step, HMAC-SHA1 with dynamic truncation
+fn totp_code(secret: &[u8], step: u64) -> String {
+	let mut mac = Hmac::<sha1::Sha1>::new_varkey(secret).expect("HMAC takes keys of any length");
+	mac.update(&step.to_be_bytes());
+	let hash = mac.finalize().into_bytes();
+	let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+	let truncated = u32::from_be_bytes([
+		hash[offset] & 0x7f,
+		hash[offset + 1],
+		hash[offset + 2],
+		hash[offset + 3],
+	]);
+	format!(
+		"{:0width$}",
+		truncated % 10u32.pow(TOTP_DIGITS as u32),
+		width = TOTP_DIGITS
+	)
+}
+
+/// Percent encodes everything but RFC 3986 unreserved characters, for `otpauth://` URIs
+fn uri_encode(text: &str) -> String {
+	text.bytes()
+		.map(|b| match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+				(b as char).to_string()
+			}
+			_ => format!("%{:02X}", b),
+		})
+		.collect()
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum AccountError {
 	// Why isn't `argon2::password_hash::Error` or any other argon2 errors actually an Error type...
 	#[error("password hashing error: {0}")]
 	PasswordHash(argon2::password_hash::Error),
+	#[error("invalid argon2 parameters: {0}")]
+	Argon2Params(argon2::Error),
 	#[error("password does not match")]
 	PasswordDoesNotMatch,
-	#[error("password cannot be the same as the current password and cannot be too short")]
-	InvalidNewPassword,
+	#[error("invalid new password, it {0}")]
+	InvalidNewPassword(String),
+	#[error("breached password check is unavailable: {0}")]
+	BreachCheckUnavailable(String),
+	#[error("two factor authentication is already enabled")]
+	TotpAlreadyEnabled,
+	#[error("two factor authentication is not enrolled")]
+	TotpNotEnrolled,
+	#[error("invalid two factor code")]
+	InvalidTotpCode,
+	#[error("stored two factor secret is corrupt")]
+	TotpSecretCorrupt,
+	#[error("account metadata is not valid for its type: {0}")]
+	Meta(#[from] serde_json::Error),
+	#[error("account metadata key is read only: {0}")]
+	ReadOnlyMeta(String),
 	#[error("DatabaseError")]
 	DatabaseError(#[from] sqlx::Error),
 }
@@ -66,9 +622,91 @@ impl Account {
 		Self { id, login }
 	}
 
-	pub fn hash_password(password: &str) -> Result<String, AccountError> {
+	pub fn id(&self) -> Uuid {
+		self.id
+	}
+
+	pub fn login(&self) -> Option<&str> {
+		self.login.as_deref()
+	}
+
+	/// Read only metadata key holding `last_login_at` as an ISO 8601 string
+	pub const LAST_LOGIN_AT_META_KEY: &'static str = "last_login_at";
+
+	/// The metadata stored under the key, `None` if there is none, metadata is kept on the base
+	/// account so it survives login changes
+	pub async fn get_meta<T: serde::de::DeserializeOwned>(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		key: &str,
+	) -> Result<Option<T>, AccountError> {
+		let value = sqlx::query_scalar::<_, Option<String>>(
+			r#"
+				SELECT CASE WHEN $2 = $3
+					THEN (SELECT to_jsonb(last_login_at) FROM accounts_locals WHERE removed_at IS NULL AND id = $1)::text
+					ELSE (data -> $2)::text
+				END
+				FROM accounts WHERE id = $1
+			"#,
+		)
+		.bind(self.id)
+		.bind(key)
+		.bind(Self::LAST_LOGIN_AT_META_KEY)
+		.fetch_optional(conn)
+		.await?
+		.flatten();
+		Ok(value
+			.map(|value| serde_json::from_str(&value))
+			.transpose()?)
+	}
+
+	/// Replaces just the key within the metadata, other keys are untouched even by concurrent sets
+	pub async fn set_meta<T: serde::Serialize>(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		key: &str,
+		value: &T,
+	) -> Result<(), AccountError> {
+		if key == Self::LAST_LOGIN_AT_META_KEY {
+			return Err(AccountError::ReadOnlyMeta(key.to_owned()));
+		}
+		let value = serde_json::to_string(value)?;
+		sqlx::query(
+			"UPDATE accounts SET data = jsonb_set(COALESCE(data, '{}'::jsonb), ARRAY[$2], $3::jsonb) WHERE id = $1",
+		)
+		.bind(self.id)
+		.bind(key)
+		.bind(value)
+		.execute(conn)
+		.await?;
+		Ok(())
+	}
+
+	/// When a session was last created by logging in to the account, `None` if never
+	pub async fn last_login_at(
+		&self,
+		conn: &mut DbTransaction<'_>,
+	) -> Result<Option<PrimitiveDateTime>, AccountError> {
+		Ok(sqlx::query_scalar::<_, Option<PrimitiveDateTime>>(
+			"SELECT last_login_at FROM accounts_locals WHERE removed_at IS NULL AND id = $1",
+		)
+		.bind(self.id)
+		.fetch_optional(conn)
+		.await?
+		.flatten())
+	}
+
+	/// The active account with the id
+	pub async fn load_by_id(
+		conn: &mut DbTransaction<'_>,
+		id: Uuid,
+	) -> Result<Account, AccountsError> {
+		Accounts::account_by_id(conn, id).await
+	}
+
+	pub fn hash_password(config: &AccountsConfig, password: &str) -> Result<String, AccountError> {
 		let salt = SaltString::generate(rand::thread_rng());
-		let argon2 = Argon2::default();
+		let argon2 = config.argon2.build()?;
 		let hashed = argon2
 			.hash_password_simple(password.as_bytes(), &salt)
 			.map_err(AccountError::PasswordHash)?;
@@ -76,10 +714,14 @@ impl Account {
 	}
 
 	pub fn password_hash_matches(
+		config: &AccountsConfig,
 		existing_password_hash: &PasswordHash,
 		password: &str,
 	) -> Result<(), AccountError> {
-		Argon2::default()
+		// The hash parameters are read from the PHC string, so older hashes still verify
+		config
+			.argon2
+			.build()?
 			.verify_password(password.as_bytes(), &existing_password_hash)
 			.map_err(|_| AccountError::PasswordDoesNotMatch)?;
 		Ok(())
@@ -89,6 +731,7 @@ impl Account {
 	pub async fn verify_password(
 		&self,
 		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
 		password: Option<&str>,
 	) -> Result<(), AccountError> {
 		if let Some(password) = password {
@@ -101,10 +744,7 @@ impl Account {
 			.ok_or(AccountError::PasswordDoesNotMatch)?;
 			let existing_password_hash = PasswordHash::new(&existing_password_hash_string)
 				.map_err(AccountError::PasswordHash)?;
-			Argon2::default()
-				.verify_password(password.as_bytes(), &existing_password_hash)
-				.map_err(|_| AccountError::PasswordDoesNotMatch)?;
-			Ok(())
+			Self::password_hash_matches(config, &existing_password_hash, password)
 		} else {
 			sqlx::query("SELECT id FROM accounts_locals WHERE removed_at IS NULL AND id IS NULL")
 				.fetch_one(conn)
@@ -114,64 +754,351 @@ impl Account {
 		}
 	}
 
+	fn totp_secret(secret: &str) -> Result<Vec<u8>, AccountError> {
+		base32_decode(secret).ok_or(AccountError::TotpSecretCorrupt)
+	}
+
+	/// Returns the time step the code is valid for, checking the current step and the steps
+	/// within the drift window but never a step at or before the last one used
+	fn match_totp_step(
+		secret: &str,
+		code: &str,
+		last_used_step: Option<i64>,
+	) -> Result<i64, AccountError> {
+		let secret = Self::totp_secret(secret)?;
+		let code = code.trim();
+		let current_step = OffsetDateTime::now_utc().unix_timestamp() / TOTP_STEP_SECS as i64;
+		((current_step - TOTP_DRIFT_STEPS)..=(current_step + TOTP_DRIFT_STEPS))
+			.filter(|&step| step >= 0 && last_used_step.map_or(true, |last| step > last))
+			.find(|&step| totp_code(&secret, step as u64) == code)
+			.ok_or(AccountError::InvalidTotpCode)
+	}
+
+	/// Returns the decrypted secret, last used step, and if it has been confirmed yet
+	async fn load_totp(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+	) -> Result<Option<(String, Option<i64>, bool)>, AccountError> {
+		Ok(sqlx::query_as::<_, (String, Option<i64>, bool)>(
+			"SELECT pgp_sym_decrypt(secret, $2), last_used_step, confirmed_at IS NOT NULL FROM accounts_totp WHERE id = $1",
+		)
+		.bind(self.id)
+		.bind(&config.totp_secret_key)
+		.fetch_optional(conn)
+		.await?)
+	}
+
+	/// Records the step as used, failing if it was concurrently used already
+	async fn use_totp_step(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		step: i64,
+		confirm: bool,
+	) -> Result<(), AccountError> {
+		sqlx::query(
+			r#"
+				UPDATE accounts_totp
+				SET last_used_step = $2,
+					confirmed_at = CASE WHEN $3 THEN now() ELSE confirmed_at END
+				WHERE id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+				RETURNING 1;
+			"#,
+		)
+		.bind(self.id)
+		.bind(step)
+		.bind(confirm)
+		.fetch_optional(conn)
+		.await?
+		.ok_or(AccountError::InvalidTotpCode)?;
+		Ok(())
+	}
+
+	pub async fn totp_enabled(&self, conn: &mut DbTransaction<'_>) -> Result<bool, AccountError> {
+		Ok(sqlx::query_scalar::<_, bool>(
+			"SELECT EXISTS (SELECT 1 FROM accounts_totp WHERE id = $1 AND confirmed_at IS NOT NULL)",
+		)
+		.bind(self.id)
+		.fetch_one(conn)
+		.await?)
+	}
+
+	/// Generates a new secret, replacing any unconfirmed one, TOTP isn't required at login until
+	/// it is confirmed with `confirm_totp`
+	pub async fn enroll_totp(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+	) -> Result<TotpEnrollment, AccountError> {
+		if self.totp_enabled(&mut *conn).await? {
+			return Err(AccountError::TotpAlreadyEnabled);
+		}
+		let mut secret_bytes = [0u8; TOTP_SECRET_BYTES];
+		rand::thread_rng().fill_bytes(&mut secret_bytes);
+		let secret = base32_encode(&secret_bytes);
+		info!("Enrolling TOTP for: {}", self.id);
+		sqlx::query(
+			r#"
+				INSERT INTO accounts_totp (id, secret) VALUES ($1, pgp_sym_encrypt($2, $3))
+				ON CONFLICT (id) DO UPDATE
+				SET secret = EXCLUDED.secret, last_used_step = NULL, inserted_at = now()
+				WHERE accounts_totp.confirmed_at IS NULL;
+			"#,
+		)
+		.bind(self.id)
+		.bind(&secret)
+		.bind(&config.totp_secret_key)
+		.execute(conn)
+		.await?;
+		let label = self
+			.login
+			.clone()
+			.unwrap_or_else(|| self.id.to_hyphenated().to_string());
+		let provisioning_uri = format!(
+			"otpauth://totp/{issuer}:{label}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+			issuer = uri_encode(&config.totp_issuer),
+			label = uri_encode(&label),
+			secret = secret,
+			digits = TOTP_DIGITS,
+			period = TOTP_STEP_SECS,
+		);
+		Ok(TotpEnrollment {
+			secret,
+			provisioning_uri,
+		})
+	}
+
+	/// Enables TOTP for the account once a code from the enrolled secret is supplied
+	pub async fn confirm_totp(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		code: &str,
+	) -> Result<(), AccountError> {
+		let (secret, last_used_step, confirmed) = self
+			.load_totp(&mut *conn, config)
+			.await?
+			.ok_or(AccountError::TotpNotEnrolled)?;
+		if confirmed {
+			return Err(AccountError::TotpAlreadyEnabled);
+		}
+		let step = Self::match_totp_step(&secret, code, last_used_step)?;
+		self.use_totp_step(conn, step, true).await?;
+		info!("TOTP enabled for: {}", self.id);
+		Ok(())
+	}
+
+	/// Verifies a code against the confirmed secret, each time step can only be used once
+	pub async fn verify_totp(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		code: &str,
+	) -> Result<(), AccountError> {
+		let (secret, last_used_step, confirmed) = self
+			.load_totp(&mut *conn, config)
+			.await?
+			.ok_or(AccountError::TotpNotEnrolled)?;
+		if !confirmed {
+			return Err(AccountError::TotpNotEnrolled);
+		}
+		let step = Self::match_totp_step(&secret, code, last_used_step)?;
+		self.use_totp_step(conn, step, false).await
+	}
+
+	/// Soft deletes the account, its login is freed up for new accounts, the password is removed,
+	/// and all of its sessions and refresh tokens are revoked, all as part of the passed in
+	/// transaction
+	pub async fn remove(&self, conn: &mut DbTransaction<'_>) -> Result<(), AccountError> {
+		info!(
+			"Removing account {}({})",
+			self.id,
+			self.login.as_ref().map(|s| s.as_ref()).unwrap_or("")
+		);
+		sqlx::query(
+			r#"
+				UPDATE accounts_locals
+				SET removed_at = now(), password_hash = NULL, updated_at = now()
+				WHERE removed_at IS NULL AND id = $1
+				RETURNING 1;
+			"#,
+		)
+		.bind(self.id)
+		.fetch_one(&mut *conn)
+		.await?;
+		sqlx::query("DELETE FROM accounts_sessions WHERE id = $1")
+			.bind(self.id)
+			.execute(&mut *conn)
+			.await?;
+		// Otherwise a remembered login could create new sessions once the account is restored
+		sqlx::query("DELETE FROM accounts_refresh_tokens WHERE id = $1")
+			.bind(self.id)
+			.execute(conn)
+			.await?;
+		info!("Removed account: {}", self.id);
+		Ok(())
+	}
+
+	pub async fn add_role(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		role: &str,
+	) -> Result<(), AccountError> {
+		info!("Adding role {} to: {}", role, self.id);
+		sqlx::query("INSERT INTO accounts_roles (id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+			.bind(self.id)
+			.bind(role)
+			.execute(conn)
+			.await?;
+		Ok(())
+	}
+
+	/// Returns if the account actually had the role
+	pub async fn remove_role(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		role: &str,
+	) -> Result<bool, AccountError> {
+		info!("Removing role {} from: {}", role, self.id);
+		let removed = sqlx::query("DELETE FROM accounts_roles WHERE id = $1 AND role = $2")
+			.bind(self.id)
+			.bind(role)
+			.execute(conn)
+			.await?
+			.rows_affected();
+		Ok(removed > 0)
+	}
+
+	pub async fn has_role(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		role: &str,
+	) -> Result<bool, AccountError> {
+		Accounts::has_role(conn, self.id, role).await
+	}
+
+	/// Renames the account, the new login must be valid and not in use by any other account,
+	/// compared case insensitively like the unique index
+	pub async fn change_login(
+		&mut self,
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		new_login: &str,
+	) -> Result<(), AccountsError> {
+		Accounts::is_valid_name(config, new_login)?;
+		let taken = sqlx::query_scalar::<_, bool>(
+			"SELECT EXISTS (SELECT 1 FROM accounts_locals WHERE removed_at IS NULL AND lower(login) = lower($1) AND id <> $2)",
+		)
+		.bind(new_login)
+		.bind(self.id)
+		.fetch_one(&mut *conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?;
+		if taken {
+			return Err(AccountsError::AccountAlreadyExists);
+		}
+		info!(
+			"Changing login of {}({}) to {}",
+			self.id,
+			self.login.as_ref().map(|s| s.as_ref()).unwrap_or(""),
+			new_login
+		);
+		sqlx::query(
+			r#"
+				UPDATE accounts_locals
+				SET login = $2, updated_at = now()
+				WHERE removed_at IS NULL AND id = $1
+				RETURNING 1;
+			"#,
+		)
+		.bind(self.id)
+		.bind(new_login)
+		.fetch_one(conn)
+		.await
+		// Still possible to race another rename or registration to the same login
+		.map_err(AccountsError::from_unique_violation)?;
+		self.login = Some(new_login.to_owned());
+		Ok(())
+	}
+
 	pub async fn set_password(
 		&self,
 		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
 		existing_password: Option<&str>,
 		new_password: Option<&str>,
 	) -> Result<(), AccountError> {
-		if existing_password == new_password {
-			// return Err(AccountError::InvalidNewPassword);
-		}
 		if let Some(new_password) = new_password {
-			if new_password.len() <= 12 {
-				return Err(AccountError::InvalidNewPassword);
+			if existing_password == Some(new_password) {
+				return Err(AccountError::InvalidNewPassword(
+					"cannot be the same as the current password".to_owned(),
+				));
 			}
-			// TODO:  Maybe a basic dictionary or DB dump check here as well?
+			config
+				.password_policy
+				.check(new_password, self.login())
+				.map_err(AccountError::InvalidNewPassword)?;
+			config.breached_password_check.check(new_password).await?;
 		}
 		info!(
 			"Updating password for {}({})",
 			self.id,
 			self.login.as_ref().map(|s| s.as_ref()).unwrap_or("")
 		);
+		let (current_hash, mut history) = sqlx::query_as::<_, (Option<String>, Vec<String>)>(
+			r#"
+				SELECT password_hash, ARRAY(
+					SELECT jsonb_array_elements_text(COALESCE(data -> 'password_history', '[]'::jsonb))
+				)
+				FROM accounts_locals
+				WHERE removed_at IS NULL AND id = $1
+			"#,
+		)
+		.bind(self.id)
+		.fetch_one(&mut *conn)
+		.await?;
 		if let Some(new_password) = new_password {
+			for previous_hash in current_hash.iter().chain(history.iter()) {
+				let previous_hash =
+					PasswordHash::new(previous_hash).map_err(AccountError::PasswordHash)?;
+				if Self::password_hash_matches(config, &previous_hash, new_password).is_ok() {
+					return Err(AccountError::InvalidNewPassword(
+						"cannot be the same as the current or a recent password".to_owned(),
+					));
+				}
+			}
+		}
+		if let Some(current_hash) = current_hash {
+			history.insert(0, current_hash);
+		}
+		history.truncate(config.password_history);
+		let hashed_new_password = if let Some(new_password) = new_password {
 			info!("Changing password for: {}", self.id);
-			let hashed_new_password = Self::hash_password(new_password)?;
-			// TODO:  Update the data field with history perhaps?
-			sqlx::query(
-				r#"
-					UPDATE accounts_locals
-					SET password_hash = $2, updated_at = now()
-					WHERE removed_at IS NULL AND id = $1
-					RETURNING 1;
-				"#,
-			)
-			.bind(self.id)
-			.bind(hashed_new_password)
-			.fetch_one(conn)
-			.await?;
-			info!("Changed password for: {}", self.id);
-			Ok(())
+			Some(Self::hash_password(config, new_password)?)
 		} else {
 			info!(
 				"Removing password to make the account unable to be logged in to: {}",
 				self.id
 			);
-			// TODO:  Update the data field with history perhaps?
-			sqlx::query(
-				r#"
-					UPDATE accounts_locals
-					SET password_hash = NULL, updated_at = now()
-					WHERE removed_at IS NULL AND id = $1
-					RETURNING 1;
-				"#,
-			)
-			.bind(self.id)
-			.fetch_one(conn)
-			.await?;
-			info!("Password removed: {}", self.id);
-			Ok(())
-		}
+			None
+		};
+		sqlx::query(
+			r#"
+				UPDATE accounts_locals
+				SET password_hash = $2,
+					data = jsonb_set(COALESCE(data, '{}'::jsonb), '{password_history}', to_jsonb($3::text[])),
+					updated_at = now()
+				WHERE removed_at IS NULL AND id = $1
+				RETURNING 1;
+			"#,
+		)
+		.bind(self.id)
+		.bind(hashed_new_password)
+		.bind(history)
+		.fetch_one(conn)
+		.await?;
+		info!("Password updated for: {}", self.id);
+		Ok(())
 	}
 }
 
@@ -179,103 +1106,593 @@ pub struct Accounts {}
 
 #[derive(Debug, thiserror::Error)]
 pub enum AccountsError {
-	#[error("given login name does not follow an allowed format: {0}")]
+	#[error("given login name contains characters that are not allowed: {0}")]
 	InvalidLoginName(String),
+	#[error("given login name is shorter than {0} characters")]
+	LoginNameTooShort(usize),
+	#[error("given login name is longer than {0} characters")]
+	LoginNameTooLong(usize),
 	#[error("account already exists")]
 	AccountAlreadyExists,
 	#[error("invalid login or password")]
 	InvalidLoginOrPassword,
+	#[error("invalid two factor code")]
+	InvalidTwoFactorCode,
+	#[error("account is locked after too many failed logins")]
+	AccountLocked,
+	#[error("account not found")]
+	AccountNotFound,
+	#[error("invalid or expired reset token")]
+	InvalidResetToken,
+	#[error("invalid or expired refresh token")]
+	InvalidRefreshToken,
+	#[error("failed sending reset token: {0}")]
+	ResetTokenSend(#[source] anyhow::Error),
+	#[error("{0}")]
+	Account(#[from] AccountError),
 	#[error("database error")]
 	DatabaseError(#[source] sqlx::Error),
 }
 
-impl Accounts {
-	fn is_valid_name(login: &str) -> Result<(), AccountsError> {
-		if login.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
-			Ok(())
-		} else {
-			Err(AccountsError::InvalidLoginName(login.to_owned()))
+/// The result of a login attempt with valid credentials
+#[derive(Debug)]
+pub enum LoginOutcome {
+	Session(AccountSession),
+	/// The account has TOTP enabled and no code was given, no session was created
+	TwoFactorRequired,
+}
+
+impl AccountsError {
+	/// Unique index violations mean the account or login is already in use
+	fn from_unique_violation(error: sqlx::Error) -> Self {
+		match &error {
+			sqlx::Error::Database(db_error) if db_error.code().as_deref() == Some("23505") => {
+				AccountsError::AccountAlreadyExists
+			}
+			_ => AccountsError::DatabaseError(error),
+		}
+	}
+}
+
+impl Accounts {
+	fn is_valid_name(config: &AccountsConfig, login: &str) -> Result<(), AccountsError> {
+		config.login_names.check(login)
+	}
+
+	async fn create_new_base_account_single(
+		conn: &mut DbTransaction<'_>,
+	) -> Result<Uuid, AccountsError> {
+		Ok(
+			sqlx::query_scalar::<_, Uuid>("INSERT INTO accounts DEFAULT VALUES RETURNING id;")
+				.fetch_one(conn)
+				.await
+				.map_err(AccountsError::DatabaseError)?,
+		)
+	}
+
+	async fn create_new_base_account(conn: &mut DbTransaction<'_>) -> Result<Uuid, AccountsError> {
+		if let Ok(result) = Self::create_new_base_account_single(conn).await {
+			Ok(result)
+		} else {
+			warn!("Actually got a UUIDv4 collision on `create_new_base_account`!");
+			// Retry just once in the amazingly unlikely case there's a UUID collision
+			Self::create_new_base_account_single(conn).await
+		}
+	}
+
+	pub async fn create_account(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		login: &str,
+	) -> Result<Account, AccountsError> {
+		info!("Creating account: {}", login);
+		Self::is_valid_name(config, login)?;
+		let id = Self::create_new_base_account(conn).await?;
+		let _result =
+			sqlx::query("INSERT INTO accounts_locals (id, login) VALUES ($1, $2) RETURNING id;")
+				.bind(id)
+				.bind(login)
+				.fetch_one(conn)
+				.await
+				.map_err(|_| AccountsError::AccountAlreadyExists)?;
+		info!("Created account: {}", login);
+		Ok(Account::new(id, Some(login.to_owned())))
+	}
+
+	/// Creates every account with its password in the one transaction, each in its own savepoint
+	/// so a failing row is rolled back on its own and the rest are still created, the results are
+	/// in the same order as the given accounts
+	pub async fn create_accounts_bulk(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		accounts: &[(&str, &str)],
+	) -> Vec<Result<Account, AccountsError>> {
+		info!("Creating {} accounts in bulk", accounts.len());
+		let mut results = Vec::with_capacity(accounts.len());
+		for (login, password) in accounts {
+			results.push(Self::create_account_savepoint(conn, config, login, password).await);
+		}
+		results
+	}
+
+	async fn create_account_savepoint(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		login: &str,
+		password: &str,
+	) -> Result<Account, AccountsError> {
+		use sqlx::Connection;
+		let mut savepoint = conn.begin().await.map_err(AccountsError::DatabaseError)?;
+		let account = Self::create_account(&mut savepoint, config, login).await?;
+		account
+			.set_password(&mut savepoint, config, None, Some(password))
+			.await?;
+		// Dropping the savepoint on an error above rolls back just this account
+		savepoint
+			.commit()
+			.await
+			.map_err(AccountsError::DatabaseError)?;
+		Ok(account)
+	}
+
+	/// Restores the most recently removed state of the account, the password stays removed so it
+	/// needs to be set again before the account can be logged in to
+	pub async fn restore(conn: &mut DbTransaction<'_>, id: Uuid) -> Result<Account, AccountsError> {
+		info!("Restoring account: {}", id);
+		// Both partial unique indexes reject this if the account or its login is in use again
+		let login = sqlx::query_scalar::<_, String>(
+			r#"
+				UPDATE accounts_locals
+				SET removed_at = NULL
+				WHERE id = $1 AND updated_at = (
+					SELECT max(updated_at) FROM accounts_locals WHERE id = $1 AND removed_at IS NOT NULL
+				)
+				RETURNING login;
+			"#,
+		)
+		.bind(id)
+		.fetch_optional(conn)
+		.await
+		.map_err(AccountsError::from_unique_violation)?
+		.ok_or(AccountsError::AccountNotFound)?;
+		info!("Restored account: {}({})", id, login);
+		Ok(Account::new(id, Some(login)))
+	}
+
+	pub async fn has_role(
+		conn: &mut DbTransaction<'_>,
+		id: Uuid,
+		role: &str,
+	) -> Result<bool, AccountError> {
+		Ok(sqlx::query_scalar::<_, bool>(
+			"SELECT EXISTS (SELECT 1 FROM accounts_roles WHERE id = $1 AND role = $2)",
+		)
+		.bind(id)
+		.bind(role)
+		.fetch_one(conn)
+		.await?)
+	}
+
+	/// Grants the role to the active account with the login, returns `false` if there is none
+	pub async fn grant_role_by_login(
+		conn: &mut DbTransaction<'_>,
+		login: &str,
+		role: &str,
+	) -> Result<bool, AccountsError> {
+		if let Some(account) = Self::find_by_login(&mut *conn, login).await? {
+			account.add_role(conn, role).await?;
+			Ok(true)
+		} else {
+			Ok(false)
+		}
+	}
+
+	/// The active account with the login, compared case insensitively like the unique index
+	pub async fn find_by_login(
+		conn: &mut DbTransaction<'_>,
+		login: &str,
+	) -> Result<Option<Account>, AccountsError> {
+		Ok(sqlx::query_as::<_, (Uuid, String)>(
+			"SELECT id, login FROM accounts_locals WHERE removed_at IS NULL AND lower(login) = lower($1)",
+		)
+		.bind(login)
+		.fetch_optional(conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?
+		.map(|(id, login)| Account::new(id, Some(login))))
+	}
+
+	/// Creates a new account with the password and the admin role, such as for bootstrapping
+	pub async fn create_admin(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		login: &str,
+		password: &str,
+	) -> Result<Account, AccountsError> {
+		let account = Self::create_account(&mut *conn, config, login).await?;
+		account
+			.set_password(&mut *conn, config, None, Some(password))
+			.await?;
+		account.add_role(conn, AdminRole::NAME).await?;
+		Ok(account)
+	}
+
+	pub async fn account_by_id(
+		conn: &mut DbTransaction<'_>,
+		id: Uuid,
+	) -> Result<Account, AccountsError> {
+		let login = sqlx::query_scalar::<_, String>(
+			"SELECT login FROM accounts_locals WHERE removed_at IS NULL AND id = $1",
+		)
+		.bind(id)
+		.fetch_optional(conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?
+		.ok_or(AccountsError::AccountNotFound)?;
+		Ok(Account::new(id, Some(login)))
+	}
+
+	/// Checks the password, counting a mismatch toward the lockout, the failure count is only reset
+	/// by `login_session` once the rest of the login succeeds too
+	pub async fn login_account(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		login: &str,
+		password: &str,
+	) -> Result<Account, AccountsError> {
+		// Logins are unique ignoring case, so they're found ignoring case too
+		let (id, stored_login, password_hash, locked) =
+			sqlx::query_as::<_, (Uuid, String, String, bool)>(
+				"SELECT id, login, password_hash, COALESCE(locked_until > now(), false) FROM accounts_locals WHERE removed_at IS NULL AND lower(login) = lower($1)",
+			)
+			.bind(login)
+			.fetch_one(&mut *conn)
+			.await
+			.map_err(|_| AccountsError::InvalidLoginOrPassword)?;
+		if locked {
+			return Err(AccountsError::AccountLocked);
+		}
+		let matches = PasswordHash::new(&password_hash).map_or(false, |existing_password_hash| {
+			Account::password_hash_matches(config, &existing_password_hash, password).is_ok()
+		});
+		if !matches {
+			Self::record_failed_login(conn, config, id).await?;
+			return Err(AccountsError::InvalidLoginOrPassword);
 		}
+		Ok(Account::new(id, Some(stored_login)))
 	}
 
-	async fn create_new_base_account_single(
+	/// Counts a failed login, locking the account once the threshold is reached, the count then
+	/// starts over for when the lock expires
+	async fn record_failed_login(
 		conn: &mut DbTransaction<'_>,
-	) -> Result<Uuid, AccountsError> {
-		Ok(
-			sqlx::query_scalar::<_, Uuid>("INSERT INTO accounts DEFAULT VALUES RETURNING id;")
-				.fetch_one(conn)
+		config: &AccountsConfig,
+		id: Uuid,
+	) -> Result<(), AccountsError> {
+		if config.lockout_threshold == 0 {
+			return Ok(());
+		}
+		let threshold = i32::try_from(config.lockout_threshold).unwrap_or(i32::MAX);
+		let locked_until = OffsetDateTime::now_utc() + config.lockout_duration;
+		let locked = sqlx::query_scalar::<_, bool>(
+			r#"
+				UPDATE accounts_locals
+				SET failed_login_count = CASE WHEN failed_login_count + 1 >= $2 THEN 0 ELSE failed_login_count + 1 END,
+					locked_until = CASE WHEN failed_login_count + 1 >= $2 THEN $3 ELSE locked_until END
+				WHERE removed_at IS NULL AND id = $1
+				RETURNING failed_login_count = 0;
+			"#,
+		)
+		.bind(id)
+		.bind(threshold)
+		.bind(locked_until)
+		.fetch_optional(conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?;
+		if locked == Some(true) {
+			warn!(
+				"Locked account {} until {} after {} failed logins",
+				id, locked_until, config.lockout_threshold
+			);
+		}
+		Ok(())
+	}
+
+	pub async fn prune_expired_sessions(
+		conn: &mut DbTransaction<'_>,
+	) -> Result<u64, AccountsError> {
+		let sessions = sqlx::query("DELETE FROM accounts_sessions WHERE valid_until < now()")
+			.execute(&mut *conn)
+			.await
+			.map_err(AccountsError::DatabaseError)?
+			.rows_affected();
+		let refresh_tokens =
+			sqlx::query("DELETE FROM accounts_refresh_tokens WHERE valid_until < now()")
+				.execute(conn)
 				.await
-				.map_err(AccountsError::DatabaseError)?,
+				.map_err(AccountsError::DatabaseError)?
+				.rows_affected();
+		Ok(sessions + refresh_tokens)
+	}
+
+	pub async fn list_sessions(
+		conn: &mut DbTransaction<'_>,
+		account_id: Uuid,
+	) -> Result<Vec<SessionInfo>, AccountsError> {
+		Ok(sqlx::query_as::<_, (Uuid, PrimitiveDateTime, PrimitiveDateTime)>(
+			"SELECT token, inserted_at, valid_until FROM accounts_sessions WHERE id = $1 AND valid_until > now() ORDER BY inserted_at DESC",
 		)
+		.bind(account_id)
+		.fetch_all(conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?
+		.into_iter()
+		.map(|(token, inserted_at, valid_until)| SessionInfo {
+			token,
+			inserted_at,
+			valid_until,
+		})
+		.collect())
 	}
 
-	async fn create_new_base_account(conn: &mut DbTransaction<'_>) -> Result<Uuid, AccountsError> {
-		if let Ok(result) = Self::create_new_base_account_single(conn).await {
-			Ok(result)
-		} else {
-			warn!("Actually got a UUIDv4 collision on `create_new_base_account`!");
-			// Retry just once in the amazingly unlikely case there's a UUID collision
-			Self::create_new_base_account_single(conn).await
-		}
+	/// Returns if a session was actually revoked
+	pub async fn revoke_session(
+		conn: &mut DbTransaction<'_>,
+		account_id: Uuid,
+		token: Uuid,
+	) -> Result<bool, AccountsError> {
+		let revoked = sqlx::query("DELETE FROM accounts_sessions WHERE id = $1 AND token = $2")
+			.bind(account_id)
+			.bind(token)
+			.execute(conn)
+			.await
+			.map_err(AccountsError::DatabaseError)?
+			.rows_affected();
+		Ok(revoked > 0)
 	}
 
-	pub async fn create_account(
+	/// Returns how many sessions were revoked
+	pub async fn revoke_all_sessions(
 		conn: &mut DbTransaction<'_>,
-		login: &str,
-	) -> Result<Account, AccountsError> {
-		info!("Creating account: {}", login);
-		Self::is_valid_name(login)?;
-		let id = Self::create_new_base_account(conn).await?;
-		let _result =
-			sqlx::query("INSERT INTO accounts_locals (id, login) VALUES ($1, $2) RETURNING id;")
-				.bind(id)
-				.bind(login)
-				.fetch_one(conn)
-				.await
-				.map_err(|_| AccountsError::AccountAlreadyExists)?;
-		info!("Created account: {}", login);
-		Ok(Account::new(id, Some(login.to_owned())))
+		account_id: Uuid,
+	) -> Result<u64, AccountsError> {
+		info!("Revoking all sessions of: {}", account_id);
+		// Otherwise a remembered login could just create a new session
+		sqlx::query("DELETE FROM accounts_refresh_tokens WHERE id = $1")
+			.bind(account_id)
+			.execute(&mut *conn)
+			.await
+			.map_err(AccountsError::DatabaseError)?;
+		Ok(sqlx::query("DELETE FROM accounts_sessions WHERE id = $1")
+			.bind(account_id)
+			.execute(conn)
+			.await
+			.map_err(AccountsError::DatabaseError)?
+			.rows_affected())
 	}
 
-	pub async fn login_account(
+	/// Creates a single use password reset token for the login
+	pub async fn create_reset_token(
 		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
 		login: &str,
-		password: &str,
-	) -> Result<Account, AccountsError> {
-		let (id, password_hash) = sqlx::query_as::<_, (Uuid, String)>(
-			"SELECT id, password_hash FROM accounts_locals WHERE removed_at IS NULL AND login = $1",
+	) -> Result<(Account, Uuid, OffsetDateTime), AccountsError> {
+		let account = Self::find_by_login(&mut *conn, login)
+			.await?
+			.ok_or(AccountsError::AccountNotFound)?;
+		let id = account.id();
+		let valid_until = OffsetDateTime::now_utc() + config.reset_token_valid_duration;
+		let token = sqlx::query_scalar::<_, Uuid>(
+			"INSERT INTO accounts_reset_tokens (id, valid_until) VALUES ($1, $2) RETURNING token",
 		)
-		.bind(login)
+		.bind(id)
+		.bind(valid_until)
 		.fetch_one(conn)
 		.await
-		.map_err(|_| AccountsError::InvalidLoginOrPassword)?;
-		let existing_password_hash =
-			PasswordHash::new(&password_hash).map_err(|_| AccountsError::InvalidLoginOrPassword)?;
-		Account::password_hash_matches(&existing_password_hash, password)
-			.map_err(|_| AccountsError::InvalidLoginOrPassword)?;
-		Ok(Account::new(id, Some(login.to_owned())))
+		.map_err(AccountsError::DatabaseError)?;
+		info!(
+			"Created password reset token for: {}({})",
+			id,
+			account.login().unwrap_or(login)
+		);
+		Ok((account, token, valid_until))
+	}
+
+	/// Creates a reset token and hands it to the sender, returns the token if the sender didn't
+	/// deliver it itself
+	pub async fn request_password_reset(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		sender: &dyn ResetTokenSender,
+		login: &str,
+	) -> Result<Option<Uuid>, AccountsError> {
+		let (account, token, valid_until) = Self::create_reset_token(conn, config, login).await?;
+		sender
+			.send(
+				account.id,
+				account.login().unwrap_or_default(),
+				token,
+				valid_until,
+			)
+			.map_err(AccountsError::ResetTokenSend)
+	}
+
+	/// Sets the new password if the token is valid, the token and every other outstanding token
+	/// of the account are used up and all existing sessions of the account are revoked
+	pub async fn consume_reset_token(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		token: Uuid,
+		new_password: &str,
+	) -> Result<(), AccountsError> {
+		let id = sqlx::query_scalar::<_, Uuid>(
+			r#"
+				UPDATE accounts_reset_tokens
+				SET used_at = now()
+				WHERE token = $1 AND used_at IS NULL AND valid_until > now()
+				RETURNING id;
+			"#,
+		)
+		.bind(token)
+		.fetch_optional(&mut *conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?
+		.ok_or(AccountsError::InvalidResetToken)?;
+		let account = Self::account_by_id(&mut *conn, id).await?;
+		account
+			.set_password(&mut *conn, config, None, Some(new_password))
+			.await?;
+		sqlx::query(
+			"UPDATE accounts_reset_tokens SET used_at = now() WHERE id = $1 AND used_at IS NULL",
+		)
+		.bind(id)
+		.execute(&mut *conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?;
+		Self::revoke_all_sessions(conn, id).await?;
+		Ok(())
 	}
 
 	pub async fn login_session(
 		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		login: &str,
+		password: &str,
+		totp_code: Option<&str>,
+		valid_duration: Duration,
+	) -> Result<LoginOutcome, AccountsError> {
+		let outcome =
+			Self::create_login_session(conn, config, login, password, totp_code, valid_duration)
+				.await;
+		Metrics::global().record_login(match &outcome {
+			Ok(LoginOutcome::Session(_)) => LoginMetric::Success,
+			Ok(LoginOutcome::TwoFactorRequired) => LoginMetric::TwoFactorRequired,
+			Err(_) => LoginMetric::Failure,
+		});
+		outcome
+	}
+
+	async fn create_login_session(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
 		login: &str,
 		password: &str,
+		totp_code: Option<&str>,
+		valid_duration: Duration,
+	) -> Result<LoginOutcome, AccountsError> {
+		let account = Self::login_account(&mut *conn, config, login, password).await?;
+		let totp_enabled = account
+			.totp_enabled(&mut *conn)
+			.await
+			.map_err(|_| AccountsError::InvalidLoginOrPassword)?;
+		if totp_enabled {
+			match totp_code {
+				None => return Ok(LoginOutcome::TwoFactorRequired),
+				Some(code) => {
+					if account.verify_totp(&mut *conn, config, code).await.is_err() {
+						Self::record_failed_login(conn, config, account.id).await?;
+						return Err(AccountsError::InvalidTwoFactorCode);
+					}
+				}
+			}
+		}
+		sqlx::query(
+			"UPDATE accounts_locals SET last_login_at = now(), failed_login_count = 0, locked_until = NULL WHERE removed_at IS NULL AND id = $1",
+		)
+		.bind(account.id)
+		.execute(&mut *conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?;
+		Ok(LoginOutcome::Session(
+			Self::create_session(conn, account.id, valid_duration).await?,
+		))
+	}
+
+	async fn create_session(
+		conn: &mut DbTransaction<'_>,
+		id: Uuid,
 		valid_duration: Duration,
 	) -> Result<AccountSession, AccountsError> {
-		let account = Self::login_account(conn, login, password).await?;
 		let valid_until = OffsetDateTime::now_utc() + valid_duration;
-		let session = sqlx::query_scalar(
+		let token = sqlx::query_scalar(
 			"INSERT INTO accounts_sessions (id, valid_until) VALUES ($1, $2) RETURNING token;",
 		)
-		.bind(account.id)
+		.bind(id)
 		.bind(valid_until)
 		.fetch_one(conn)
 		.await
 		.map_err(AccountsError::DatabaseError)?;
-		Ok(AccountSession {
-			id: account.id,
-			token: session,
-		})
+		Ok(AccountSession { id, token })
+	}
+
+	/// Creates a remember me refresh token that can be traded for new sessions with
+	/// `refresh_session` until it expires
+	pub async fn create_refresh_token(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		id: Uuid,
+	) -> Result<Uuid, AccountsError> {
+		let valid_until = OffsetDateTime::now_utc() + config.refresh_token_valid_duration;
+		sqlx::query_scalar::<_, Uuid>(
+			"INSERT INTO accounts_refresh_tokens (id, valid_until) VALUES ($1, $2) RETURNING token",
+		)
+		.bind(id)
+		.bind(valid_until)
+		.fetch_one(conn)
+		.await
+		.map_err(AccountsError::DatabaseError)
+	}
+
+	/// Trades the refresh token for a new session and a new refresh token expiring when the old
+	/// one would have, the old one can never be used again so a replayed token is refused
+	pub async fn refresh_session(
+		conn: &mut DbTransaction<'_>,
+		refresh_token: Uuid,
+		valid_duration: Duration,
+	) -> Result<(AccountSession, Uuid), AccountsError> {
+		let (id, valid_until, still_valid) =
+			sqlx::query_as::<_, (Uuid, PrimitiveDateTime, bool)>(
+				"DELETE FROM accounts_refresh_tokens WHERE token = $1 RETURNING id, valid_until, valid_until > now()",
+			)
+			.bind(refresh_token)
+			.fetch_optional(&mut *conn)
+			.await
+			.map_err(AccountsError::DatabaseError)?
+			.ok_or(AccountsError::InvalidRefreshToken)?;
+		if !still_valid {
+			return Err(AccountsError::InvalidRefreshToken);
+		}
+		// Removed accounts can't be refreshed into
+		Self::account_by_id(&mut *conn, id)
+			.await
+			.map_err(|_| AccountsError::InvalidRefreshToken)?;
+		let session = Self::create_session(&mut *conn, id, valid_duration).await?;
+		let new_refresh_token = sqlx::query_scalar::<_, Uuid>(
+			"INSERT INTO accounts_refresh_tokens (id, valid_until) VALUES ($1, $2) RETURNING token",
+		)
+		.bind(id)
+		.bind(valid_until)
+		.fetch_one(conn)
+		.await
+		.map_err(AccountsError::DatabaseError)?;
+		info!("Refreshed a remembered session for: {}", id);
+		Ok((session, new_refresh_token))
+	}
+
+	/// Returns if the refresh token existed
+	pub async fn revoke_refresh_token(
+		conn: &mut DbTransaction<'_>,
+		refresh_token: Uuid,
+	) -> Result<bool, AccountsError> {
+		Ok(
+			sqlx::query("DELETE FROM accounts_refresh_tokens WHERE token = $1")
+				.bind(refresh_token)
+				.execute(conn)
+				.await
+				.map_err(AccountsError::DatabaseError)?
+				.rows_affected()
+				> 0,
+		)
 	}
 }
 
@@ -295,15 +1712,58 @@ impl AccountSession {
 	}
 
 	pub async fn validate(&self, conn: &mut DbTransaction<'_>) -> anyhow::Result<()> {
+		// `fetch_one` errors when no row matches, rejecting unknown and expired sessions
 		sqlx::query(
-			"SELECT 1 FROM accounts_sessions WHERE id = &1 AND token = $2 AND valid_until <= now()",
+			"SELECT 1 FROM accounts_sessions WHERE id = $1 AND token = $2 AND valid_until > now()",
 		)
 		.bind(self.id)
 		.bind(self.token)
-		.execute(conn)
+		.fetch_one(conn)
 		.await?;
 		Ok(())
 	}
+
+	pub async fn revoke(&self, conn: &mut DbTransaction<'_>) -> Result<(), AccountsError> {
+		sqlx::query("DELETE FROM accounts_sessions WHERE id = $1 AND token = $2")
+			.bind(self.id)
+			.bind(self.token)
+			.execute(conn)
+			.await
+			.map_err(AccountsError::DatabaseError)?;
+		Ok(())
+	}
+}
+
+/// An active session of an account, the token is only ever exposed masked
+#[derive(Debug)]
+pub struct SessionInfo {
+	token: Uuid,
+	pub inserted_at: PrimitiveDateTime,
+	pub valid_until: PrimitiveDateTime,
+}
+
+impl SessionInfo {
+	const UNMASKED_CHARS: usize = 8;
+
+	pub fn token(&self) -> Uuid {
+		self.token
+	}
+
+	/// Only the tail of the token, enough to tell sessions apart but useless to authenticate with
+	pub fn masked_token(&self) -> String {
+		let token = self.token.to_hyphenated().to_string();
+		let (hidden, shown) = token.split_at(token.len() - Self::UNMASKED_CHARS);
+		let mut masked: String = hidden
+			.chars()
+			.map(|c| if c == '-' { '-' } else { '*' })
+			.collect();
+		masked.push_str(shown);
+		masked
+	}
+
+	pub fn matches_masked(&self, masked: &str) -> bool {
+		self.masked_token() == masked
+	}
 }
 
 impl Display for AccountSession {
@@ -325,7 +1785,7 @@ impl FromStr for AccountSession {
 	}
 }
 
-const MIGRATIONS: Migrations = Migrations::new(
+pub(crate) const MIGRATIONS: Migrations = Migrations::new(
 	"Accounts",
 	&[
 		Migration::new("Create accounts table")
@@ -377,6 +1837,574 @@ const MIGRATIONS: Migrations = Migrations::new(
 				) WITH ( OIDS=FALSE );
 				"#).down(r#"
 				DROP TABLE accounts_sessions;
+				"#),
+		Migration::new("Create accounts_totp table").up(r#"
+				CREATE TABLE accounts_totp (
+					id uuid NOT NULL,
+					secret bytea NOT NULL,
+					last_used_step bigint,
+					inserted_at timestamp without time zone NOT NULL DEFAULT now(),
+					confirmed_at timestamp without time zone,
+					CONSTRAINT accounts_totp_pkey PRIMARY KEY (id),
+					CONSTRAINT accounts_totp_id_fkey FOREIGN KEY (id) REFERENCES accounts (id) MATCH SIMPLE ON UPDATE CASCADE ON DELETE CASCADE
+				) WITH ( OIDS=FALSE );
+				"#).down(r#"
+				DROP TABLE accounts_totp;
+				"#),
+		Migration::new("Create accounts_reset_tokens table").up(r#"
+				CREATE TABLE accounts_reset_tokens (
+					token uuid NOT NULL DEFAULT gen_random_uuid(),
+					id uuid NOT NULL,
+					inserted_at timestamp without time zone NOT NULL DEFAULT now(),
+					valid_until timestamp without time zone NOT NULL,
+					used_at timestamp without time zone,
+					CONSTRAINT accounts_reset_tokens_pkey PRIMARY KEY (token),
+					CONSTRAINT accounts_reset_tokens_id_fkey FOREIGN KEY (id) REFERENCES accounts (id) MATCH SIMPLE ON UPDATE CASCADE ON DELETE CASCADE
+				) WITH ( OIDS=FALSE );
+				CREATE INDEX accounts_reset_tokens_id_index ON accounts_reset_tokens USING btree (id);
+				"#).down(r#"
+				DROP INDEX accounts_reset_tokens_id_index;
+				DROP TABLE accounts_reset_tokens;
+				"#),
+		Migration::new("Create accounts_roles table").up(r#"
+				CREATE TABLE accounts_roles (
+					id uuid NOT NULL,
+					role text NOT NULL,
+					inserted_at timestamp without time zone NOT NULL DEFAULT now(),
+					CONSTRAINT accounts_roles_pkey PRIMARY KEY (id, role),
+					CONSTRAINT accounts_roles_id_fkey FOREIGN KEY (id) REFERENCES accounts (id) MATCH SIMPLE ON UPDATE CASCADE ON DELETE CASCADE
+				) WITH ( OIDS=FALSE );
+				CREATE INDEX accounts_roles_role_index ON accounts_roles USING btree (role);
+				"#).down(r#"
+				DROP INDEX accounts_roles_role_index;
+				DROP TABLE accounts_roles;
+				"#),
+		Migration::new("Add accounts_locals last_login_at").up(r#"
+				ALTER TABLE accounts_locals ADD COLUMN last_login_at timestamp without time zone;
+				"#).down(r#"
+				ALTER TABLE accounts_locals DROP COLUMN last_login_at;
+				"#),
+		Migration::new("Add accounts_locals lockout").up(r#"
+				ALTER TABLE accounts_locals ADD COLUMN failed_login_count integer NOT NULL DEFAULT 0;
+				ALTER TABLE accounts_locals ADD COLUMN locked_until timestamp without time zone;
+				"#).down(r#"
+				ALTER TABLE accounts_locals DROP COLUMN locked_until;
+				ALTER TABLE accounts_locals DROP COLUMN failed_login_count;
+				"#),
+		Migration::new("Create accounts_refresh_tokens table").up(r#"
+				CREATE TABLE accounts_refresh_tokens (
+					token uuid NOT NULL DEFAULT gen_random_uuid(),
+					id uuid NOT NULL,
+					inserted_at timestamp without time zone NOT NULL DEFAULT now(),
+					valid_until timestamp without time zone NOT NULL,
+					CONSTRAINT accounts_refresh_tokens_pkey PRIMARY KEY (token),
+					CONSTRAINT accounts_refresh_tokens_id_fkey FOREIGN KEY (id) REFERENCES accounts (id) MATCH SIMPLE ON UPDATE CASCADE ON DELETE CASCADE
+				) WITH ( OIDS=FALSE );
+				CREATE INDEX accounts_refresh_tokens_id_index ON accounts_refresh_tokens USING btree (id);
+				"#).down(r#"
+				DROP INDEX accounts_refresh_tokens_id_index;
+				DROP TABLE accounts_refresh_tokens;
+				"#),
+		Migration::new("Default accounts_sessions token").up(r#"
+				ALTER TABLE accounts_sessions ALTER COLUMN token SET DEFAULT gen_random_uuid();
+				"#).down(r#"
+				ALTER TABLE accounts_sessions ALTER COLUMN token DROP DEFAULT;
 				"#)
 	],
 );
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database::test_support::test_pool;
+
+	const TEST_PASSWORD: &str = "correct horse battery staple";
+
+	/// A new account with a unique login and `TEST_PASSWORD`
+	async fn new_account(conn: &mut DbTransaction<'_>, config: &AccountsConfig) -> Account {
+		let login = format!("test_{}", Uuid::new_v4().to_simple());
+		let account = Accounts::create_account(&mut *conn, config, &login)
+			.await
+			.unwrap();
+		account
+			.set_password(conn, config, None, Some(TEST_PASSWORD))
+			.await
+			.unwrap();
+		account
+	}
+
+	/// Serves `body` to every request on a local port, sending back each request's path
+	async fn mock_range_api(body: &'static str) -> (String, tokio::sync::mpsc::Receiver<String>) {
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/range/", listener.local_addr().unwrap());
+		let (paths, received) = tokio::sync::mpsc::channel(8);
+		tokio::spawn(async move {
+			loop {
+				let (mut stream, _) = listener.accept().await.unwrap();
+				let mut request = Vec::new();
+				let mut buf = [0u8; 1024];
+				while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+					let read = stream.read(&mut buf).await.unwrap();
+					if read == 0 {
+						break;
+					}
+					request.extend_from_slice(&buf[..read]);
+				}
+				let request = String::from_utf8_lossy(&request);
+				let path = request.split(' ').nth(1).unwrap_or_default().to_owned();
+				let _ = paths.send(path).await;
+				let response = format!(
+					"HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+					body.len(),
+					body
+				);
+				stream.write_all(response.as_bytes()).await.unwrap();
+			}
+		});
+		(url, received)
+	}
+
+	fn breach_check(range_url: String, fail_open: bool) -> BreachedPasswordCheck {
+		BreachedPasswordCheck {
+			enabled: true,
+			range_url,
+			timeout: std::time::Duration::from_secs(2),
+			fail_open,
+		}
+	}
+
+	#[tokio::test]
+	async fn breach_check_only_sends_the_hash_prefix_and_rejects_breached_passwords() {
+		// SHA-1 of "password" is 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8
+		let (url, mut paths) =
+			mock_range_api("0018A45C4D1DEF81644B54AB7F969B88D65:0\r\n1E4C9B93F3F0682250B6CF8331B7EE68FD8:3861493\r\n").await;
+		let check = breach_check(url, false);
+		match check.check("password").await {
+			Err(AccountError::InvalidNewPassword(_)) => (),
+			other => panic!("breached password was not rejected: {:?}", other),
+		}
+		assert_eq!(paths.recv().await.unwrap(), "/range/5BAA6");
+		check.check(TEST_PASSWORD).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn breach_check_ignores_zero_count_padding() {
+		let (url, _paths) = mock_range_api("1E4C9B93F3F0682250B6CF8331B7EE68FD8:0\r\n").await;
+		breach_check(url, false).check("password").await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn unreachable_breach_check_fails_open_only_when_configured() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/range/", listener.local_addr().unwrap());
+		drop(listener);
+		breach_check(url.clone(), true)
+			.check("password")
+			.await
+			.unwrap();
+		match breach_check(url, false).check("password").await {
+			Err(AccountError::BreachCheckUnavailable(_)) => (),
+			other => panic!("unreachable check was not refused: {:?}", other),
+		}
+	}
+
+	async fn login(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		login: &str,
+	) -> Result<LoginOutcome, AccountsError> {
+		Accounts::login_session(conn, config, login, TEST_PASSWORD, None, Duration::hours(1)).await
+	}
+
+	#[test]
+	fn totp_codes_match_the_rfc_6238_sha1_vectors() {
+		// The RFC 6238 appendix B secret and times, truncated to 6 digits
+		let secret = base32_decode(&base32_encode(b"12345678901234567890")).unwrap();
+		assert_eq!(secret, b"12345678901234567890");
+		assert_eq!(totp_code(&secret, 59 / TOTP_STEP_SECS), "287082");
+		assert_eq!(totp_code(&secret, 1111111109 / TOTP_STEP_SECS), "081804");
+		assert_eq!(totp_code(&secret, 2000000000 / TOTP_STEP_SECS), "279037");
+		assert_eq!(
+			base32_encode(b"12345678901234567890"),
+			"GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+		);
+		assert!(base32_decode("not base32!").is_none());
+	}
+
+	#[test]
+	fn missing_totp_secret_key_is_rejected() {
+		let mut errors = Vec::new();
+		AccountsConfig::default().validate(&mut errors);
+		assert_eq!(errors.len(), 1);
+		let mut errors = Vec::new();
+		AccountsConfig::new().validate(&mut errors);
+		assert!(errors.is_empty());
+		// Generated only when asked for, loading never makes a new one
+		assert_ne!(
+			AccountsConfig::new().totp_secret_key,
+			AccountsConfig::new().totp_secret_key
+		);
+		assert_eq!(AccountsConfig::default().totp_secret_key, "");
+	}
+
+	#[test]
+	fn hashes_with_the_configured_argon2_params() {
+		let config = AccountsConfig {
+			argon2: Argon2Params::new(8192, 2, 2),
+			..AccountsConfig::default()
+		};
+		let hashed = Account::hash_password(&config, TEST_PASSWORD).unwrap();
+		assert!(hashed.contains("$m=8192,t=2,p=2$"), "{}", hashed);
+		let hash = PasswordHash::new(&hashed).unwrap();
+		// Still verifies with different params configured as they're read from the hash
+		Account::password_hash_matches(&AccountsConfig::default(), &hash, TEST_PASSWORD).unwrap();
+	}
+
+	#[tokio::test]
+	async fn logged_in_session_validates() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		let session = match login(&mut conn, &config, account.login().unwrap()).await {
+			Ok(LoginOutcome::Session(session)) => session,
+			outcome => panic!("expected a session, got {:?}", outcome),
+		};
+		assert_eq!(session.id(), account.id());
+		session.validate(&mut conn).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn expired_session_does_not_validate() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		let session = Accounts::create_session(&mut conn, account.id(), Duration::seconds(-60))
+			.await
+			.unwrap();
+		assert!(session.validate(&mut conn).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn nonexistent_session_does_not_validate() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		let session = AccountSession {
+			id: account.id(),
+			token: Uuid::new_v4(),
+		};
+		assert!(session.validate(&mut conn).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn removed_login_can_be_recreated_and_old_refresh_tokens_are_revoked() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let mut conn = pool.begin().await.unwrap();
+		let removed = new_account(&mut conn, &config).await;
+		let login = removed.login().unwrap().to_owned();
+		let refresh_token = Accounts::create_refresh_token(&mut conn, &config, removed.id())
+			.await
+			.unwrap();
+		removed.remove(&mut conn).await.unwrap();
+		assert!(Accounts::find_by_login(&mut conn, &login)
+			.await
+			.unwrap()
+			.is_none());
+
+		let recreated = Accounts::create_account(&mut conn, &config, &login)
+			.await
+			.unwrap();
+		assert_ne!(recreated.id(), removed.id());
+		assert_eq!(
+			Accounts::find_by_login(&mut conn, &login)
+				.await
+				.unwrap()
+				.map(|account| account.id()),
+			Some(recreated.id())
+		);
+		assert!(matches!(
+			Accounts::refresh_session(&mut conn, refresh_token, Duration::hours(1)).await,
+			Err(AccountsError::InvalidRefreshToken)
+		));
+		// The login is taken again so the removed account can't come back with it, last as the
+		// unique violation aborts the transaction
+		assert!(matches!(
+			Accounts::restore(&mut conn, removed.id()).await,
+			Err(AccountsError::AccountAlreadyExists)
+		));
+	}
+
+	#[tokio::test]
+	async fn renamed_account_logs_in_with_the_new_login() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let mut conn = pool.begin().await.unwrap();
+		let mut account = new_account(&mut conn, &config).await;
+		let old_login = account.login().unwrap().to_owned();
+		let new_login = format!("renamed_{}", Uuid::new_v4().to_simple());
+		account
+			.change_login(&mut conn, &config, &new_login)
+			.await
+			.unwrap();
+		assert_eq!(account.login(), Some(new_login.as_str()));
+		match login(&mut conn, &config, &new_login).await {
+			Ok(LoginOutcome::Session(session)) => assert_eq!(session.id(), account.id()),
+			outcome => panic!("expected a session, got {:?}", outcome),
+		}
+		assert!(matches!(
+			login(&mut conn, &config, &old_login).await,
+			Err(AccountsError::InvalidLoginOrPassword)
+		));
+	}
+
+	#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+	struct Preferences {
+		theme: String,
+		volume: u8,
+		muted_channels: Vec<String>,
+	}
+
+	#[tokio::test]
+	async fn meta_round_trips_a_struct_without_touching_other_keys() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		assert_eq!(
+			account
+				.get_meta::<Preferences>(&mut conn, "preferences")
+				.await
+				.unwrap(),
+			None
+		);
+		let preferences = Preferences {
+			theme: "dark".to_owned(),
+			volume: 7,
+			muted_channels: vec!["#spam".to_owned()],
+		};
+		account
+			.set_meta(&mut conn, "preferences", &preferences)
+			.await
+			.unwrap();
+		account
+			.set_meta(&mut conn, "nickname", &"bot")
+			.await
+			.unwrap();
+		assert_eq!(
+			account.get_meta(&mut conn, "preferences").await.unwrap(),
+			Some(preferences)
+		);
+		assert_eq!(
+			account
+				.get_meta::<String>(&mut conn, "nickname")
+				.await
+				.unwrap(),
+			Some("bot".to_owned())
+		);
+	}
+
+	#[tokio::test]
+	async fn last_login_at_is_read_only_meta_set_by_logging_in() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		assert_eq!(
+			account
+				.get_meta::<String>(&mut conn, Account::LAST_LOGIN_AT_META_KEY)
+				.await
+				.unwrap(),
+			None
+		);
+		login(&mut conn, &config, account.login().unwrap())
+			.await
+			.unwrap();
+		let at: String = account
+			.get_meta(&mut conn, Account::LAST_LOGIN_AT_META_KEY)
+			.await
+			.unwrap()
+			.unwrap();
+		assert!(
+			PrimitiveDateTime::parse(&at[..19], "%FT%T").is_ok(),
+			"not an ISO 8601 timestamp: {}",
+			at
+		);
+		assert!(matches!(
+			account
+				.set_meta(&mut conn, Account::LAST_LOGIN_AT_META_KEY, &"never")
+				.await,
+			Err(AccountError::ReadOnlyMeta(_))
+		));
+	}
+
+	fn lockout_config() -> AccountsConfig {
+		AccountsConfig {
+			lockout_threshold: 3,
+			..AccountsConfig::new()
+		}
+	}
+
+	async fn fail_logins(
+		conn: &mut DbTransaction<'_>,
+		config: &AccountsConfig,
+		login: &str,
+		times: u32,
+	) {
+		for _ in 0..times {
+			let outcome =
+				Accounts::login_session(conn, config, login, "wrong", None, Duration::hours(1))
+					.await;
+			assert!(
+				matches!(outcome, Err(AccountsError::InvalidLoginOrPassword)),
+				"{:?}",
+				outcome
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn failed_logins_lock_the_account() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = lockout_config();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		fail_logins(&mut conn, &config, account.login().unwrap(), 3).await;
+		assert!(matches!(
+			login(&mut conn, &config, account.login().unwrap()).await,
+			Err(AccountsError::AccountLocked)
+		));
+	}
+
+	#[tokio::test]
+	async fn locked_account_unlocks_once_the_lock_expires() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = lockout_config();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		fail_logins(&mut conn, &config, account.login().unwrap(), 3).await;
+		// `now()` is fixed for the whole transaction, so the lock is expired by hand
+		sqlx::query(
+			"UPDATE accounts_locals SET locked_until = now() - interval '1 second' WHERE id = $1",
+		)
+		.bind(account.id())
+		.execute(&mut conn)
+		.await
+		.unwrap();
+		assert!(matches!(
+			login(&mut conn, &config, account.login().unwrap()).await,
+			Ok(LoginOutcome::Session(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn successful_login_resets_the_failure_count() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = lockout_config();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		fail_logins(&mut conn, &config, account.login().unwrap(), 2).await;
+		login(&mut conn, &config, account.login().unwrap())
+			.await
+			.unwrap();
+		fail_logins(&mut conn, &config, account.login().unwrap(), 2).await;
+		assert!(matches!(
+			login(&mut conn, &config, account.login().unwrap()).await,
+			Ok(LoginOutcome::Session(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn failed_two_factor_codes_count_toward_the_lock() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = lockout_config();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		let enrollment = account.enroll_totp(&mut conn, &config).await.unwrap();
+		let secret = Account::totp_secret(&enrollment.secret).unwrap();
+		let step = OffsetDateTime::now_utc().unix_timestamp() as u64 / TOTP_STEP_SECS;
+		account
+			.confirm_totp(&mut conn, &config, &totp_code(&secret, step))
+			.await
+			.unwrap();
+		for _ in 0..3 {
+			let outcome = Accounts::login_session(
+				&mut conn,
+				&config,
+				account.login().unwrap(),
+				TEST_PASSWORD,
+				Some("not a code"),
+				Duration::hours(1),
+			)
+			.await;
+			assert!(
+				matches!(outcome, Err(AccountsError::InvalidTwoFactorCode)),
+				"{:?}",
+				outcome
+			);
+		}
+		assert!(matches!(
+			login(&mut conn, &config, account.login().unwrap()).await,
+			Err(AccountsError::AccountLocked)
+		));
+	}
+
+	#[tokio::test]
+	async fn login_ignores_case_and_returns_the_stored_login() {
+		let pool = match test_pool(&[&MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let mut conn = pool.begin().await.unwrap();
+		let account = new_account(&mut conn, &config).await;
+		let stored_login = account.login().unwrap().to_owned();
+		let logged_in = Accounts::login_account(
+			&mut conn,
+			&config,
+			&stored_login.to_uppercase(),
+			TEST_PASSWORD,
+		)
+		.await
+		.unwrap();
+		assert_eq!(logged_in.id(), account.id());
+		assert_eq!(logged_in.login(), Some(stored_login.as_str()));
+	}
+}
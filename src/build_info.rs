@@ -0,0 +1,22 @@
+//! Details of how this binary was built, for pinning down the environment in bug reports
+
+/// The short git hash the binary was built from, `unknown` when built outside of a checkout
+pub const GIT_HASH: &str = env!("OVERBOT_GIT_HASH");
+/// The `rocket` version locked in `Cargo.lock`
+pub const ROCKET_VERSION: &str = env!("OVERBOT_ROCKET_VERSION");
+/// The `sqlx` version locked in `Cargo.lock`
+pub const SQLX_VERSION: &str = env!("OVERBOT_SQLX_VERSION");
+
+/// A multiline summary of the version, git hash, and notable dependency versions of this build
+pub fn build_info() -> String {
+	format!(
+		"{} v{} ({})\n{}\nEmbedded PostgreSQL {}\nRocket v{}\nSQLx v{}",
+		env!("CARGO_PKG_NAME"),
+		env!("CARGO_PKG_VERSION"),
+		GIT_HASH,
+		env!("CARGO_PKG_DESCRIPTION"),
+		crate::database::EMBEDDED_POSTGRES_MAJOR_VERSION,
+		ROCKET_VERSION,
+		SQLX_VERSION,
+	)
+}
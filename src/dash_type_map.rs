@@ -4,7 +4,8 @@ use std::option::Option::None;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 
 #[derive(Debug)]
 pub enum DashTypeMapErrors {
@@ -35,20 +36,77 @@ impl std::fmt::Display for DashTypeMapErrors {
 	}
 }
 
-#[derive(Debug, Default)]
+/// The type of a stored value composed with a name so multiple values of one type can coexist,
+/// the unnamed API uses the empty name
+pub type DashTypeKey = (TypeId, String);
+
+fn key_of<V: 'static>(name: &str) -> DashTypeKey {
+	(TypeId::of::<V>(), name.to_owned())
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ChangeKind {
+	Inserted,
+	Removed,
+}
+
+/// How many changes a subscriber can fall behind before it starts missing them
+const CHANGE_CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Debug)]
 pub struct DashTypeMap(
-	dashmap::DashMap<TypeId, Box<dyn Any + Send + Sync>>,
+	dashmap::DashMap<DashTypeKey, Box<dyn Any + Send + Sync>>,
 	crossbeam::queue::SegQueue<Waker>,
+	broadcast::Sender<(DashTypeKey, ChangeKind)>,
+	/// When entries inserted with a TTL expire
+	dashmap::DashMap<DashTypeKey, Instant>,
+	/// Names of every type ever inserted, a `TypeId` alone can't be shown to anyone
+	dashmap::DashMap<TypeId, &'static str>,
 );
 
+impl Default for DashTypeMap {
+	fn default() -> Self {
+		Self(
+			Default::default(),
+			Default::default(),
+			broadcast::channel(CHANGE_CHANNEL_CAPACITY).0,
+			Default::default(),
+			Default::default(),
+		)
+	}
+}
+
 impl DashTypeMap {
 	pub fn new() -> Self {
 		Default::default()
 	}
 
+	/// Receives the key of every insert and remove from now on, a lagging receiver misses the
+	/// oldest changes
+	pub fn subscribe(&self) -> broadcast::Receiver<(DashTypeKey, ChangeKind)> {
+		self.2.subscribe()
+	}
+
+	/// The name of a type that has been inserted, as given by `std::any::type_name`
+	pub fn type_name(&self, type_id: &TypeId) -> Option<&'static str> {
+		self.4.get(type_id).map(|name| *name)
+	}
+
+	fn record_type_name<V: 'static>(&self) {
+		self.4
+			.entry(TypeId::of::<V>())
+			.or_insert_with(type_name::<V>);
+	}
+
+	fn notify_change(&self, key: DashTypeKey, kind: ChangeKind) {
+		self.process_change_wakers();
+		// No subscribers is fine
+		let _ = self.2.send((key, kind));
+	}
+
 	pub fn as_dashmap(
 		&self,
-	) -> &dashmap::DashMap<std::any::TypeId, Box<dyn std::any::Any + Send + Sync>> {
+	) -> &dashmap::DashMap<DashTypeKey, Box<dyn std::any::Any + Send + Sync>> {
 		&self.0
 	}
 
@@ -62,11 +120,72 @@ impl DashTypeMap {
 		}
 	}
 
+	/// Removes the entry if its TTL has passed, as if it was removed normally
+	fn expire_if_due(&self, key: &DashTypeKey) {
+		let due = self
+			.3
+			.get(key)
+			.map_or(false, |expires_at| *expires_at <= Instant::now());
+		if due
+			&& self
+				.3
+				.remove_if(key, |_key, expires_at| *expires_at <= Instant::now())
+				.is_some()
+			&& self.0.remove(key).is_some()
+		{
+			self.notify_change(key.clone(), ChangeKind::Removed);
+		}
+	}
+
+	/// Removes every entry whose TTL has passed, returns how many were removed
+	pub fn reap_expired(&self) -> usize {
+		let now = Instant::now();
+		let due: Vec<DashTypeKey> = self
+			.3
+			.iter()
+			.filter(|entry| *entry.value() <= now)
+			.map(|entry| entry.key().clone())
+			.collect();
+		for key in &due {
+			self.expire_if_due(key);
+		}
+		due.len()
+	}
+
+	/// Reaps expired entries every `interval` until quit, expired entries are already treated as
+	/// missing on access so this just frees them and wakes anything waiting on their removal
+	pub fn spawn_reaper(
+		map: Arc<Self>,
+		interval: Duration,
+		mut quit: broadcast::Receiver<()>,
+	) -> tokio::task::JoinHandle<()> {
+		let mut interval = tokio::time::interval(interval);
+		tokio::spawn(async move {
+			loop {
+				tokio::select! {
+					_ = quit.recv() => break,
+					_ = interval.tick() => {
+						map.reap_expired();
+					}
+				}
+			}
+		})
+	}
+
 	pub fn contains<K: 'static>(&self) -> bool {
-		self.contains_key(&TypeId::of::<K>())
+		self.contains_named::<K>("")
+	}
+
+	pub fn contains_named<K: 'static>(&self, name: &str) -> bool {
+		self.contains_dash_key(&key_of::<K>(name))
 	}
 
 	pub fn contains_key(&self, key: &TypeId) -> bool {
+		self.contains_dash_key(&(*key, String::new()))
+	}
+
+	pub fn contains_dash_key(&self, key: &DashTypeKey) -> bool {
+		self.expire_if_due(key);
 		self.0.contains_key(key)
 	}
 
@@ -74,21 +193,110 @@ impl DashTypeMap {
 		&self,
 		value: impl Into<Box<V>>,
 	) -> Result<(), DashTypeMapErrors> {
-		let key = TypeId::of::<V>();
-		if self.contains_key(&key) {
-			return Err(DashTypeMapErrors::AlreadyExists);
+		self.insert_named::<V>("", value)
+	}
+
+	pub fn insert_named<V: 'static + Send + Sync>(
+		&self,
+		name: &str,
+		value: impl Into<Box<V>>,
+	) -> Result<(), DashTypeMapErrors> {
+		self.insert_named_expiring::<V>(name, value, None)
+	}
+
+	/// Inserts a value that is removed once `ttl` has passed, until then it behaves like any
+	/// other entry
+	pub fn insert_with_ttl<V: 'static + Send + Sync>(
+		&self,
+		value: impl Into<Box<V>>,
+		ttl: Duration,
+	) -> Result<(), DashTypeMapErrors> {
+		self.insert_named_with_ttl::<V>("", value, ttl)
+	}
+
+	pub fn insert_named_with_ttl<V: 'static + Send + Sync>(
+		&self,
+		name: &str,
+		value: impl Into<Box<V>>,
+		ttl: Duration,
+	) -> Result<(), DashTypeMapErrors> {
+		self.insert_named_expiring::<V>(name, value, Some(Instant::now() + ttl))
+	}
+
+	fn insert_named_expiring<V: 'static + Send + Sync>(
+		&self,
+		name: &str,
+		value: impl Into<Box<V>>,
+		expires_at: Option<Instant>,
+	) -> Result<(), DashTypeMapErrors> {
+		let key = key_of::<V>(name);
+		self.expire_if_due(&key);
+		match self.0.entry(key.clone()) {
+			dashmap::mapref::entry::Entry::Occupied(_) => {
+				return Err(DashTypeMapErrors::AlreadyExists)
+			}
+			dashmap::mapref::entry::Entry::Vacant(entry) => {
+				if let Some(expires_at) = expires_at {
+					self.3.insert(key.clone(), expires_at);
+				} else {
+					self.3.remove(&key);
+				}
+				entry.insert(value.into());
+			}
 		}
-		self.0.insert(key, value.into());
-		self.process_change_wakers();
+		self.record_type_name::<V>();
+		self.notify_change(key, ChangeKind::Inserted);
 		Ok(())
 	}
 
+	/// Returns the stored `Arc<V>`, first inserting the result of `fun` if there is none, all
+	/// atomically so concurrent callers all get the same value
+	pub fn get_or_insert_with<V: 'static + Send + Sync, F: FnOnce() -> V>(&self, fun: F) -> Arc<V> {
+		self.get_or_insert_named_with::<V, F>("", fun)
+	}
+
+	pub fn get_or_insert_named_with<V: 'static + Send + Sync, F: FnOnce() -> V>(
+		&self,
+		name: &str,
+		fun: F,
+	) -> Arc<V> {
+		let key = key_of::<Arc<V>>(name);
+		self.expire_if_due(&key);
+		let (value, inserted) = match self.0.entry(key.clone()) {
+			dashmap::mapref::entry::Entry::Occupied(entry) => {
+				let value = entry
+					.get()
+					.downcast_ref::<Arc<V>>()
+					.expect("internal data state failure, any type does not match actual type")
+					.clone();
+				(value, false)
+			}
+			dashmap::mapref::entry::Entry::Vacant(entry) => {
+				let value = Arc::new(fun());
+				entry.insert(Box::new(value.clone()));
+				(value, true)
+			}
+		};
+		if inserted {
+			self.record_type_name::<Arc<V>>();
+			self.notify_change(key, ChangeKind::Inserted);
+		}
+		value
+	}
+
 	pub fn remove<V: 'static + Send + Sync>(&self) -> Result<Box<V>, DashTypeMapErrors> {
-		let (_key, value) = self
-			.0
-			.remove(&TypeId::of::<V>())
-			.ok_or(DashTypeMapErrors::DoesNotExist)?;
-		self.process_change_wakers();
+		self.remove_named::<V>("")
+	}
+
+	pub fn remove_named<V: 'static + Send + Sync>(
+		&self,
+		name: &str,
+	) -> Result<Box<V>, DashTypeMapErrors> {
+		let key = key_of::<V>(name);
+		self.expire_if_due(&key);
+		let (key, value) = self.0.remove(&key).ok_or(DashTypeMapErrors::DoesNotExist)?;
+		self.3.remove(&key);
+		self.notify_change(key, ChangeKind::Removed);
 		let value = value
 			.downcast::<V>()
 			.expect("internal data state failure, any type does not match actual type");
@@ -99,10 +307,17 @@ impl DashTypeMap {
 		&self,
 		fun: F,
 	) -> Result<R, DashTypeMapErrors> {
-		let value = self
-			.0
-			.get(&TypeId::of::<V>())
-			.ok_or(DashTypeMapErrors::DoesNotExist)?;
+		self.with_named::<V, R, F>("", fun)
+	}
+
+	pub fn with_named<V: 'static + Send + Sync, R, F: FnOnce(&V) -> R>(
+		&self,
+		name: &str,
+		fun: F,
+	) -> Result<R, DashTypeMapErrors> {
+		let key = key_of::<V>(name);
+		self.expire_if_due(&key);
+		let value = self.0.get(&key).ok_or(DashTypeMapErrors::DoesNotExist)?;
 		let value = &**value;
 		let value = value
 			.downcast_ref::<V>()
@@ -115,9 +330,19 @@ impl DashTypeMap {
 		&self,
 		fun: F,
 	) -> Result<R, DashTypeMapErrors> {
+		self.with_mut_named::<V, R, F>("", fun)
+	}
+
+	pub fn with_mut_named<V: 'static + Send + Sync, R, F: FnOnce(&mut V) -> R>(
+		&self,
+		name: &str,
+		fun: F,
+	) -> Result<R, DashTypeMapErrors> {
+		let key = key_of::<V>(name);
+		self.expire_if_due(&key);
 		let mut value = self
 			.0
-			.get_mut(&TypeId::of::<V>())
+			.get_mut(&key)
 			.ok_or(DashTypeMapErrors::DoesNotExist)?;
 		let value = &mut **value;
 		let value = value
@@ -128,13 +353,28 @@ impl DashTypeMap {
 	}
 
 	pub fn clone_if_arc<V: 'static + Send + Sync>(&self) -> Result<Arc<V>, DashTypeMapErrors> {
-		self.with::<Arc<V>, Arc<V>, _>(Clone::clone)
+		self.clone_named_if_arc::<V>("")
+	}
+
+	pub fn clone_named_if_arc<V: 'static + Send + Sync>(
+		&self,
+		name: &str,
+	) -> Result<Arc<V>, DashTypeMapErrors> {
+		self.with_named::<Arc<V>, Arc<V>, _>(name, Clone::clone)
 	}
 
 	pub fn wait_for_existence_of(
 		&self,
 		key: TypeId,
 		timeout: Duration,
+	) -> DashTypeMapWaiterExistence {
+		self.wait_for_existence_of_named((key, String::new()), timeout)
+	}
+
+	pub fn wait_for_existence_of_named(
+		&self,
+		key: DashTypeKey,
+		timeout: Duration,
 	) -> DashTypeMapWaiterExistence {
 		DashTypeMapWaiterExistence(Box::pin(tokio::time::sleep(timeout)), self, key, true)
 	}
@@ -143,32 +383,133 @@ impl DashTypeMap {
 		&self,
 		key: TypeId,
 		timeout: Duration,
+	) -> DashTypeMapWaiterExistence {
+		self.wait_for_removal_of_named((key, String::new()), timeout)
+	}
+
+	pub fn wait_for_removal_of_named(
+		&self,
+		key: DashTypeKey,
+		timeout: Duration,
 	) -> DashTypeMapWaiterExistence {
 		DashTypeMapWaiterExistence(Box::pin(tokio::time::sleep(timeout)), self, key, false)
 	}
 
+	/// Resolves to `true` once every key exists, or `false` on timeout
+	pub fn wait_for_existence_of_all(
+		&self,
+		keys: &[TypeId],
+		timeout: Duration,
+	) -> DashTypeMapWaiterExistenceSet {
+		self.wait_for_keys(unnamed_keys(keys), true, WaitFor::All, timeout)
+	}
+
+	/// Resolves to `true` once any of the keys exists, or `false` on timeout
+	pub fn wait_for_existence_of_any(
+		&self,
+		keys: &[TypeId],
+		timeout: Duration,
+	) -> DashTypeMapWaiterExistenceSet {
+		self.wait_for_keys(unnamed_keys(keys), true, WaitFor::Any, timeout)
+	}
+
+	/// Resolves to `true` once all (or any) of the keys exist (or don't if `exists` is `false`),
+	/// or `false` on timeout, an empty key set resolves immediately
+	pub fn wait_for_keys(
+		&self,
+		keys: Vec<DashTypeKey>,
+		exists: bool,
+		wait_for: WaitFor,
+		timeout: Duration,
+	) -> DashTypeMapWaiterExistenceSet {
+		DashTypeMapWaiterExistenceSet {
+			timeout: Box::pin(tokio::time::sleep(timeout)),
+			map: self,
+			keys,
+			exists,
+			wait_for,
+		}
+	}
+
 	pub async fn wait_clone_if_arc<V: 'static + Send + Sync>(
 		&self,
 		timeout: Duration,
 	) -> Result<Arc<V>, DashTypeMapErrors> {
-		let mut arc = self.clone_if_arc::<V>();
+		self.wait_clone_named_if_arc::<V>("", timeout).await
+	}
+
+	pub async fn wait_clone_named_if_arc<V: 'static + Send + Sync>(
+		&self,
+		name: &str,
+		timeout: Duration,
+	) -> Result<Arc<V>, DashTypeMapErrors> {
+		let mut arc = self.clone_named_if_arc::<V>(name);
 		while let Err(DashTypeMapErrors::DoesNotExist) = arc {
 			if !self
-				.wait_for_existence_of(TypeId::of::<Arc<V>>(), timeout)
+				.wait_for_existence_of_named(key_of::<Arc<V>>(name), timeout)
 				.await
 			{
 				return Err(DashTypeMapErrors::Timeout);
 			}
-			arc = self.clone_if_arc::<V>()
+			arc = self.clone_named_if_arc::<V>(name)
 		}
 		arc
 	}
 }
 
+fn unnamed_keys(keys: &[TypeId]) -> Vec<DashTypeKey> {
+	keys.iter().map(|key| (*key, String::new())).collect()
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum WaitFor {
+	All,
+	Any,
+}
+
+pub struct DashTypeMapWaiterExistenceSet<'s> {
+	timeout: Pin<Box<tokio::time::Sleep>>,
+	map: &'s DashTypeMap,
+	keys: Vec<DashTypeKey>,
+	exists: bool,
+	wait_for: WaitFor,
+}
+
+impl<'s> DashTypeMapWaiterExistenceSet<'s> {
+	fn is_satisfied(&self) -> bool {
+		let mut matching = self
+			.keys
+			.iter()
+			.map(|key| self.map.contains_dash_key(key) == self.exists);
+		match self.wait_for {
+			WaitFor::All => matching.all(|m| m),
+			WaitFor::Any => self.keys.is_empty() || matching.any(|m| m),
+		}
+	}
+}
+
+impl<'s> Future for DashTypeMapWaiterExistenceSet<'s> {
+	type Output = bool;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		if self.is_satisfied() {
+			Poll::Ready(true)
+		} else {
+			match self.timeout.as_mut().poll(cx) {
+				Poll::Ready(()) => Poll::Ready(false),
+				Poll::Pending => {
+					self.map.add_change_waker(cx.waker().clone());
+					Poll::Pending
+				}
+			}
+		}
+	}
+}
+
 pub struct DashTypeMapWaiterExistence<'s>(
 	Pin<Box<tokio::time::Sleep>>,
 	&'s DashTypeMap,
-	TypeId,
+	DashTypeKey,
 	bool,
 );
 
@@ -176,7 +517,7 @@ impl<'s> Future for DashTypeMapWaiterExistence<'s> {
 	type Output = bool;
 
 	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-		if self.1.contains_key(&self.2) == self.3 {
+		if self.1.contains_dash_key(&self.2) == self.3 {
 			Poll::Ready(true)
 		} else {
 			match self.0.as_mut().poll(cx) {
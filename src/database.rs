@@ -1,14 +1,19 @@
+use crate::system::ConfigError;
 use anyhow::{bail, Context};
 use pg_embed::fetch::{Architecture, FetchSettings, OperationSystem, PG_V13};
 use pg_embed::postgres::{PgEmbed, PgSettings};
-use sqlx::postgres::PgPoolOptions;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
 use sqlx::{Executor, PgPool, Transaction};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use tracing::*;
 
+/// The major PostgreSQL version an `Embedded` connection runs, must match the `PG_V13` fetched
+pub const EMBEDDED_POSTGRES_MAJOR_VERSION: u32 = 13;
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub enum ConnectionType {
 	External(String),
@@ -29,7 +34,7 @@ pub enum ConnectionLock {
 }
 
 impl ConnectionLock {
-	fn as_uri(&self) -> &str {
+	pub fn as_uri(&self) -> &str {
 		match self {
 			ConnectionLock::External(conn_string) => &conn_string,
 			ConnectionLock::Embedded(pg) => &pg.db_uri,
@@ -173,6 +178,71 @@ impl ConnectionType {
 pub struct DatabaseConfig {
 	connection: ConnectionType,
 	max_connections: u8,
+	/// Connections the pool keeps open even when idle
+	#[serde(default = "default_min_connections")]
+	min_connections: u8,
+	/// How long to wait to acquire a connection from the pool before failing
+	#[serde(default = "default_acquire_timeout")]
+	acquire_timeout: Duration,
+	/// How many times to retry connecting the pool before giving up, the embedded database setup
+	/// itself is never retried
+	#[serde(default = "default_connect_retries")]
+	connect_retries: u32,
+	/// Delay before the first connection retry, doubled on each following attempt
+	#[serde(default = "default_connect_retry_delay")]
+	connect_retry_delay: Duration,
+	/// TLS mode for `External` connections, overrides any `sslmode` in the URI when set
+	#[serde(default)]
+	ssl_mode: Option<SslMode>,
+	/// CA certificate file to verify the server against for `External` connections, overrides
+	/// any `sslrootcert` in the URI when set
+	#[serde(default)]
+	ssl_root_cert: Option<PathBuf>,
+	/// When an applied migration's SQL no longer matches its stored checksum, store the new
+	/// checksum and continue instead of failing.  Only meant for cosmetic edits like comments
+	/// during development, the edited SQL is never run.
+	#[serde(default)]
+	allow_checksum_override: bool,
+}
+
+/// Mirrors postgres' `sslmode` connection parameter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum SslMode {
+	Disable,
+	Allow,
+	Prefer,
+	Require,
+	VerifyCa,
+	VerifyFull,
+}
+
+impl From<SslMode> for PgSslMode {
+	fn from(mode: SslMode) -> Self {
+		match mode {
+			SslMode::Disable => PgSslMode::Disable,
+			SslMode::Allow => PgSslMode::Allow,
+			SslMode::Prefer => PgSslMode::Prefer,
+			SslMode::Require => PgSslMode::Require,
+			SslMode::VerifyCa => PgSslMode::VerifyCa,
+			SslMode::VerifyFull => PgSslMode::VerifyFull,
+		}
+	}
+}
+
+fn default_min_connections() -> u8 {
+	1
+}
+
+fn default_acquire_timeout() -> Duration {
+	Duration::from_secs(30)
+}
+
+fn default_connect_retries() -> u32 {
+	5
+}
+
+fn default_connect_retry_delay() -> Duration {
+	Duration::from_secs(1)
 }
 
 impl DatabaseConfig {
@@ -201,6 +271,13 @@ impl DatabaseConfig {
 		Self {
 			connection,
 			max_connections,
+			min_connections: default_min_connections(),
+			acquire_timeout: default_acquire_timeout(),
+			connect_retries: default_connect_retries(),
+			connect_retry_delay: default_connect_retry_delay(),
+			ssl_mode: None,
+			ssl_root_cert: None,
+			allow_checksum_override: false,
 		}
 	}
 
@@ -208,17 +285,137 @@ impl DatabaseConfig {
 		Self {
 			connection: ConnectionType::External(uri.into()),
 			max_connections,
+			min_connections: default_min_connections(),
+			acquire_timeout: default_acquire_timeout(),
+			connect_retries: default_connect_retries(),
+			connect_retry_delay: default_connect_retry_delay(),
+			ssl_mode: None,
+			ssl_root_cert: None,
+			allow_checksum_override: false,
+		}
+	}
+
+	/// Adds every problem with the configuration to `errors`
+	pub fn validate(&self, errors: &mut Vec<ConfigError>) {
+		if self.max_connections == 0 {
+			errors.push(ConfigError::new(
+				"database.max_connections",
+				"must be at least 1",
+			));
+		}
+		if self.min_connections > self.max_connections {
+			errors.push(ConfigError::new(
+				"database.min_connections",
+				format!(
+					"({}) is greater than max_connections ({})",
+					self.min_connections, self.max_connections
+				),
+			));
+		}
+		match &self.connection {
+			ConnectionType::External(uri) if uri.trim().is_empty() => errors.push(
+				ConfigError::new("database.connection", "external URI is empty"),
+			),
+			// Ports above 32767 wrap negative as the embedded port is an `i16`
+			ConnectionType::Embedded { port, .. } if *port <= 0 => errors.push(ConfigError::new(
+				"database.connection",
+				format!(
+					"embedded port must be between 1 and {}, got {}, higher ports wrap negative",
+					i16::MAX,
+					port
+				),
+			)),
+			_ => (),
+		}
+		if let Some(ssl_root_cert) = &self.ssl_root_cert {
+			if !ssl_root_cert.is_file() {
+				errors.push(ConfigError::new(
+					"database.ssl_root_cert",
+					format!("is not a readable file: {:?}", ssl_root_cert),
+				));
+			}
+		}
+	}
+
+	/// Passed on to every `Migrations::migrate_up`
+	pub fn allow_checksum_override(&self) -> bool {
+		self.allow_checksum_override
+	}
+
+	/// The `pg_dump` bundled with the embedded database, or the one on the `PATH` otherwise
+	pub fn pg_dump_path(&self) -> PathBuf {
+		let executable = if cfg!(target_os = "windows") {
+			"pg_dump.exe"
+		} else {
+			"pg_dump"
+		};
+		match &self.connection {
+			ConnectionType::Embedded { root_path, .. } => root_path
+				.join("embedded_postgres")
+				.join("bin")
+				.join(executable),
+			_ => PathBuf::from(executable),
+		}
+	}
+
+	fn connect_options(&self, uri: &str) -> anyhow::Result<PgConnectOptions> {
+		let mut options =
+			PgConnectOptions::from_str(uri).context("invalid database connection URI")?;
+		// The embedded database is local and never set up with TLS
+		if let ConnectionType::External(_) = &self.connection {
+			if let Some(ssl_mode) = self.ssl_mode {
+				options = options.ssl_mode(ssl_mode.into());
+			}
+			if let Some(ssl_root_cert) = &self.ssl_root_cert {
+				options = options.ssl_root_cert(ssl_root_cert);
+			}
+		}
+		Ok(options)
+	}
+
+	async fn connect_pool(&self, uri: &str) -> anyhow::Result<PgPool> {
+		let options = self.connect_options(uri)?;
+		let mut delay = self.connect_retry_delay;
+		let mut attempt = 0;
+		loop {
+			match PgPoolOptions::new()
+				.max_connections(self.max_connections as u32)
+				.min_connections(self.min_connections as u32)
+				// sqlx names its pool acquire timeout `connect_timeout`
+				.connect_timeout(self.acquire_timeout)
+				.connect_with(options.clone())
+				.await
+			{
+				Ok(pool) => return Ok(pool),
+				Err(e) if attempt < self.connect_retries => {
+					attempt += 1;
+					warn!(
+						"Failed connecting to the database (attempt {} of {}), retrying in {:?}: {}",
+						attempt, self.connect_retries, delay, e
+					);
+					tokio::time::sleep(delay).await;
+					delay *= 2;
+				}
+				Err(e) => {
+					return Err(e).with_context(|| {
+						format!(
+							"failed connecting to the database after {} retries",
+							self.connect_retries
+						)
+					})
+				}
+			}
 		}
 	}
 
 	pub async fn create_database_pool(&self) -> anyhow::Result<(ConnectionLock, DbPool)> {
 		info!("Initializing postgresql database connection");
+		if self.allow_checksum_override {
+			warn!("Migration checksum overrides are allowed, edited migrations will not be caught");
+		}
 		let connection = self.connection.init_conn_string().await?;
 
-		let pool = PgPoolOptions::new()
-			.max_connections(self.max_connections as u32)
-			.connect(connection.as_uri())
-			.await?;
+		let pool = self.connect_pool(connection.as_uri()).await?;
 
 		let pool: DbPool = Arc::new(pool);
 		migrate_migration_table(&pool)
@@ -253,11 +450,50 @@ async fn migrate_migration_table(pool: &PgPool) -> anyhow::Result<()> {
 	Ok(())
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+	#[error("migration gap in {module}: version {missing_version} was never applied but later versions were")]
+	MigrationGap {
+		module: String,
+		missing_version: i64,
+	},
+	#[error("checksum drift in {module} for version {version}: the migration was edited after it was applied, stored {expected} but the code is now {found}; revert the edit, add a new migration instead, or if the edit is only cosmetic set `allow_checksum_override` in the database config")]
+	ChecksumDrift {
+		module: String,
+		version: i64,
+		expected: String,
+		found: String,
+	},
+}
+
+fn checksum_hex(checksum: &[u8]) -> String {
+	checksum
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
 #[derive(Clone)]
 pub struct Migration<'d, 'su, 'sd> {
 	pub description: &'d str,
 	pub sql_up: &'su str,
 	pub sql_down: &'sd str,
+	/// Statements run one at a time after `sql_up`, for ones that can't share a batch
+	pub steps_up: &'su [&'su str],
+	/// Statements run one at a time after `sql_down`, for ones that can't share a batch
+	pub steps_down: &'sd [&'sd str],
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct MigrationStatus {
+	pub version: i64,
+	pub description: String,
+	/// Whether this version has been applied to the database
+	pub applied: bool,
+	/// Whether the stored checksum matches the checksum in code, always `true` if not applied
+	pub checksum_matches: bool,
+	/// The database has this version applied but it no longer exists in code
+	pub orphaned: bool,
 }
 
 pub struct Migrations<'n, 'm, 'd, 'su, 'sd> {
@@ -271,6 +507,8 @@ impl<'d, 'su, 'sd> Migration<'d, 'su, 'sd> {
 			description,
 			sql_up: "",
 			sql_down: "",
+			steps_up: &[],
+			steps_down: &[],
 		}
 	}
 
@@ -297,11 +535,31 @@ impl<'d, 'su, 'sd> Migration<'d, 'su, 'sd> {
 		}
 	}
 
+	/// Each statement is executed individually, in order, so a failure names the failing step.
+	pub const fn steps(self, steps_up: &'su [&'su str], steps_down: &'sd [&'sd str]) -> Self {
+		Self {
+			steps_up,
+			steps_down,
+			..self
+		}
+	}
+
 	pub fn checksum(&self) -> [u8; 64] {
 		use sha2::Digest;
-		sha2::Sha512::default()
+		let mut hasher = sha2::Sha512::default()
 			.chain(self.sql_up.as_bytes())
-			.chain(self.sql_down.as_bytes())
+			.chain(self.sql_down.as_bytes());
+		// Steps are delimited so moving text between them still changes the checksum, and
+		// migrations without steps keep the checksum they always had
+		for step in self.steps_up {
+			hasher.update(b"\0up\0");
+			hasher.update(step.as_bytes());
+		}
+		for step in self.steps_down {
+			hasher.update(b"\0down\0");
+			hasher.update(step.as_bytes());
+		}
+		hasher
 			.finalize()
 			.as_slice()
 			.try_into()
@@ -314,7 +572,52 @@ impl<'d, 'su, 'sd> Migration<'d, 'su, 'sd> {
 		conn: &mut Transaction<'_, sqlx::Postgres>,
 	) -> anyhow::Result<()> {
 		info!("Migrate up {}", module);
-		conn.execute(self.sql_up.as_ref()).await?;
+		if !self.sql_up.is_empty() {
+			conn.execute(self.sql_up.as_ref()).await?;
+		}
+		self.execute_steps(module, "up", self.steps_up, conn).await
+	}
+
+	async fn migrate_down(
+		&self,
+		module: &str,
+		conn: &mut Transaction<'_, sqlx::Postgres>,
+	) -> anyhow::Result<()> {
+		info!("Migrate down {}", module);
+		if !self.sql_down.is_empty() {
+			conn.execute(self.sql_down.as_ref()).await?;
+		}
+		self.execute_steps(module, "down", self.steps_down, conn)
+			.await
+	}
+
+	async fn execute_steps(
+		&self,
+		module: &str,
+		direction: &str,
+		steps: &[&str],
+		conn: &mut Transaction<'_, sqlx::Postgres>,
+	) -> anyhow::Result<()> {
+		for (idx, step) in steps.iter().enumerate() {
+			debug!(
+				"Migrate {} {} step {}/{}",
+				direction,
+				module,
+				idx + 1,
+				steps.len()
+			);
+			conn.execute(*step).await.with_context(|| {
+				format!(
+					"migration `{}` in {} failed {} on step {} of {}: {}",
+					self.description,
+					module,
+					direction,
+					idx + 1,
+					steps.len(),
+					step
+				)
+			})?;
+		}
 		Ok(())
 	}
 }
@@ -324,9 +627,42 @@ impl<'n, 'm, 'd, 'su, 'sd> Migrations<'n, 'm, 'd, 'su, 'sd> {
 		Self { module, migrations }
 	}
 
-	pub async fn migrate_up(&self, pool: &PgPool) -> anyhow::Result<()> {
+	/// With `allow_checksum_override` an applied migration whose SQL was edited has its checksum
+	/// updated instead of failing
+	pub async fn migrate_up(
+		&self,
+		pool: &PgPool,
+		allow_checksum_override: bool,
+	) -> anyhow::Result<()> {
+		self.run_up(pool, false, allow_checksum_override)
+			.await
+			.map(drop)
+	}
+
+	/// Runs every pending migration exactly as `migrate_up` would, logging the SQL, but rolls it
+	/// all back instead of committing, so it's validated against the live schema without changing
+	/// it.  Returns the descriptions of the pending migrations in the order they would run.
+	pub async fn plan(
+		&self,
+		pool: &PgPool,
+		allow_checksum_override: bool,
+	) -> anyhow::Result<Vec<String>> {
+		self.run_up(pool, true, allow_checksum_override).await
+	}
+
+	async fn run_up(
+		&self,
+		pool: &PgPool,
+		dry_run: bool,
+		allow_checksum_override: bool,
+	) -> anyhow::Result<Vec<String>> {
+		let mut pending = Vec::new();
 		if !self.migrations.is_empty() {
-			info!("Migrating all up on {}", &self.module);
+			if dry_run {
+				info!("Planning migrations up on {}", &self.module);
+			} else {
+				info!("Migrating all up on {}", &self.module);
+			}
 			// Why is the `conn.transaction` call wrapper boxing a future?!?  Wasteful...
 			let mut conn = pool.begin().await?;
 			// Why doesn't sqlx support decoding to unsigned integers?!
@@ -342,6 +678,13 @@ impl<'n, 'm, 'd, 'su, 'sd> Migrations<'n, 'm, 'd, 'su, 'sd> {
 				if let Some((version, checksum)) = current.pop() {
 					if checksum.len() != 64 {
 						bail!("Migration database checksum length is invalid for module {} with version {}", &self.module, version);
+					} else if version > mig_version {
+						// Versions are applied in order, so a later version existing means this one
+						// was skipped, likely by a partially failed prior run
+						bail!(MigrationError::MigrationGap {
+							module: self.module.to_owned(),
+							missing_version: mig_version,
+						});
 					} else if version != mig_version {
 						bail!(
 							"Version mismatch in {}: {} -> {}",
@@ -350,15 +693,49 @@ impl<'n, 'm, 'd, 'su, 'sd> Migrations<'n, 'm, 'd, 'su, 'sd> {
 							mig_version
 						);
 					} else if checksum != mig.checksum() {
-						bail!(
-							"Checksum mismatch in {} for version {}: {:?} -> {:?}",
-							&self.module,
+						let drift = MigrationError::ChecksumDrift {
+							module: self.module.to_owned(),
 							version,
-							&checksum,
-							mig.checksum()
-						);
+							expected: checksum_hex(&checksum),
+							found: checksum_hex(&mig.checksum()),
+						};
+						if !allow_checksum_override {
+							bail!(drift);
+						}
+						if dry_run {
+							warn!(
+								"Would override {}, storing the new checksum, the edited SQL would NOT be run",
+								drift
+							);
+						} else {
+							error!(
+								"OVERRIDING {}, storing the new checksum, the edited SQL is NOT run",
+								drift
+							);
+							sqlx::query("UPDATE _migrations SET checksum = $3, description = $4 WHERE module = $1 AND version = $2")
+								.bind(self.module)
+								.bind(version)
+								.bind(mig.checksum().as_ref())
+								.bind(mig.description)
+								.execute(&mut conn)
+								.await?;
+						}
 					}
 				} else {
+					if dry_run {
+						info!(
+							"Would migrate {} to version {}, `{}`:\n{}{}",
+							&self.module,
+							mig_version,
+							mig.description,
+							mig.sql_up,
+							mig.steps_up
+								.iter()
+								.map(|step| format!("\n{}", step))
+								.collect::<String>()
+						);
+					}
+					pending.push(mig.description.to_owned());
 					mig.migrate_up(&self.module, &mut conn).await?;
 					sqlx::query("INSERT INTO _migrations(module, version, checksum, description) VALUES ($1, $2, $3, $4)")
 						.bind(self.module)
@@ -369,8 +746,243 @@ impl<'n, 'm, 'd, 'su, 'sd> Migrations<'n, 'm, 'd, 'su, 'sd> {
 						.await?;
 				}
 			}
-			conn.commit().await?;
+			if dry_run {
+				conn.rollback().await?;
+				info!(
+					"Planned {} pending migrations on {}, rolled back",
+					pending.len(),
+					&self.module
+				);
+			} else {
+				conn.commit().await?;
+			}
+		}
+		Ok(pending)
+	}
+
+	/// Rolls back every applied migration with a version above `target_version`, newest first,
+	/// all within a single transaction.  A `target_version` of `None` rolls back everything.
+	pub async fn migrate_down(
+		&self,
+		pool: &PgPool,
+		target_version: Option<i64>,
+	) -> anyhow::Result<()> {
+		info!(
+			"Migrating down on {} to version {:?}",
+			&self.module, target_version
+		);
+		let mut conn = pool.begin().await?;
+		let applied = sqlx::query_as::<_, (i64, Vec<u8>)>(
+			"SELECT version, checksum FROM _migrations WHERE module = $1 AND version > $2 ORDER BY version DESC",
+		)
+		.bind(self.module)
+		.bind(target_version.unwrap_or(-1))
+		.fetch_all(&mut conn)
+		.await?;
+		for (version, checksum) in applied {
+			let mig = usize::try_from(version)
+				.ok()
+				.and_then(|idx| self.migrations.get(idx))
+				.with_context(|| {
+					format!(
+						"Migration in {} for version {} does not exist in code, unable to migrate down",
+						&self.module, version
+					)
+				})?;
+			if checksum.len() != 64 {
+				bail!(
+					"Migration database checksum length is invalid for module {} with version {}",
+					&self.module,
+					version
+				);
+			} else if checksum != mig.checksum() {
+				// Always strict, the down SQL that would run is the edited one
+				bail!(MigrationError::ChecksumDrift {
+					module: self.module.to_owned(),
+					version,
+					expected: checksum_hex(&checksum),
+					found: checksum_hex(&mig.checksum()),
+				});
+			}
+			mig.migrate_down(&self.module, &mut conn).await?;
+			sqlx::query("DELETE FROM _migrations WHERE module = $1 AND version = $2")
+				.bind(self.module)
+				.bind(version)
+				.execute(&mut conn)
+				.await?;
 		}
+		conn.commit().await?;
 		Ok(())
 	}
+
+	/// Reports the applied and pending state of every migration in this module without modifying
+	/// the database, including any applied versions that no longer exist in code.
+	pub async fn status(&self, pool: &PgPool) -> anyhow::Result<Vec<MigrationStatus>> {
+		let applied = sqlx::query_as::<_, (i64, Vec<u8>, String)>(
+			"SELECT version, checksum, description FROM _migrations WHERE module = $1 ORDER BY version ASC",
+		)
+		.bind(self.module)
+		.fetch_all(pool)
+		.await?;
+		let mut statuses: Vec<MigrationStatus> = self
+			.migrations
+			.iter()
+			.enumerate()
+			.map(|(version, mig)| MigrationStatus {
+				version: version as i64,
+				description: mig.description.to_owned(),
+				applied: false,
+				checksum_matches: true,
+				orphaned: false,
+			})
+			.collect();
+		for (version, checksum, description) in applied {
+			match usize::try_from(version)
+				.ok()
+				.and_then(|idx| Some((self.migrations.get(idx)?, statuses.get_mut(idx)?)))
+			{
+				Some((mig, status)) => {
+					status.applied = true;
+					status.checksum_matches = checksum == mig.checksum();
+				}
+				None => statuses.push(MigrationStatus {
+					version,
+					description,
+					applied: true,
+					checksum_matches: false,
+					orphaned: true,
+				}),
+			}
+		}
+		Ok(statuses)
+	}
+}
+
+/// Helpers for tests that need a real database, which is only available when
+/// `OVERBOT_TEST_DATABASE_URL` is set, tests return early without it
+#[cfg(test)]
+pub mod test_support {
+	use super::*;
+
+	const TEST_DATABASE_URL_ENV: &str = "OVERBOT_TEST_DATABASE_URL";
+	/// Advisory lock held while migrating so concurrently running tests don't race each other
+	const MIGRATION_LOCK_KEY: i64 = 0x6f76_6572_626f_74;
+
+	pub fn test_database_url() -> Option<String> {
+		let url = std::env::var(TEST_DATABASE_URL_ENV).ok();
+		if url.is_none() {
+			eprintln!(
+				"{} is not set, skipping database test",
+				TEST_DATABASE_URL_ENV
+			);
+		}
+		url
+	}
+
+	/// A pool on the test database with the migration table and the given migrations applied
+	pub async fn test_pool(migrations: &[&Migrations<'_, '_, '_, '_, '_>]) -> Option<DbPool> {
+		let url = test_database_url()?;
+		let pool = PgPoolOptions::new()
+			.max_connections(4)
+			.connect(&url)
+			.await
+			.expect("failed connecting to the test database");
+		let mut lock = pool
+			.acquire()
+			.await
+			.expect("failed acquiring the migration lock connection");
+		sqlx::query("SELECT pg_advisory_lock($1)")
+			.bind(MIGRATION_LOCK_KEY)
+			.execute(&mut lock)
+			.await
+			.expect("failed taking the migration lock");
+		migrate_migration_table(&pool)
+			.await
+			.expect("failed migrating the migration table");
+		for migrations in migrations {
+			migrations
+				.migrate_up(&pool, false)
+				.await
+				.expect("failed migrating the test database");
+		}
+		sqlx::query("SELECT pg_advisory_unlock($1)")
+			.bind(MIGRATION_LOCK_KEY)
+			.execute(&mut lock)
+			.await
+			.expect("failed releasing the migration lock");
+		Some(Arc::new(pool))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Instant;
+	use tokio::net::{TcpListener, TcpStream};
+
+	fn retrying_config(uri: &str) -> DatabaseConfig {
+		let mut config = DatabaseConfig::new_external(1, uri);
+		config.connect_retries = 3;
+		config.connect_retry_delay = Duration::from_millis(50);
+		config.acquire_timeout = Duration::from_secs(2);
+		config
+	}
+
+	/// A local port with nothing listening on it
+	async fn closed_port() -> u16 {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		listener.local_addr().unwrap().port()
+	}
+
+	#[tokio::test]
+	async fn connect_pool_gives_up_after_backing_off_every_retry() {
+		let port = closed_port().await;
+		let uri = format!("postgres://overbot@127.0.0.1:{}/overbot", port);
+		let config = retrying_config(&uri);
+		let start = Instant::now();
+		let error = config.connect_pool(&uri).await.unwrap_err();
+		// 50ms + 100ms + 200ms of doubling delays between the 4 attempts
+		assert!(start.elapsed() >= Duration::from_millis(350));
+		assert!(error.to_string().contains("after 3 retries"), "{:?}", error);
+	}
+
+	#[tokio::test]
+	async fn connect_pool_retries_until_a_delayed_database_is_up() {
+		let test_url = match test_support::test_database_url() {
+			Some(test_url) => test_url,
+			None => return,
+		};
+		let target = reqwest::Url::parse(&test_url).unwrap();
+		let target = format!(
+			"{}:{}",
+			target.host_str().unwrap_or("localhost"),
+			target.port().unwrap_or(5432)
+		);
+		let port = closed_port().await;
+		let mut uri = reqwest::Url::parse(&test_url).unwrap();
+		uri.set_host(Some("127.0.0.1")).unwrap();
+		uri.set_port(Some(port)).unwrap();
+		// Only starts forwarding to the real database after the first attempts were refused
+		tokio::spawn(async move {
+			tokio::time::sleep(Duration::from_millis(120)).await;
+			let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+			loop {
+				let (mut inbound, _) = listener.accept().await.unwrap();
+				let target = target.clone();
+				tokio::spawn(async move {
+					let mut outbound = TcpStream::connect(target).await.unwrap();
+					let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+				});
+			}
+		});
+		let config = retrying_config(uri.as_str());
+		let start = Instant::now();
+		let pool = config.connect_pool(uri.as_str()).await.unwrap();
+		assert!(start.elapsed() >= Duration::from_millis(120));
+		let one = sqlx::query_scalar::<_, i32>("SELECT 1")
+			.fetch_one(&pool)
+			.await
+			.unwrap();
+		assert_eq!(one, 1);
+	}
 }
@@ -0,0 +1,185 @@
+//! This is an appender that hands records to another appender on a dedicated thread, so slow
+//! sinks like files on a busy disk don't stall the threads that log
+
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log4rs::append::Append;
+use log4rs::config::{Deserialize, Deserializers};
+use serde_value::Value;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::log::{Level, Record};
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AsyncAppenderConfig {
+	appender: Appender,
+	/// How many records can wait on the wrapped appender, defaults to 1024
+	buffer: Option<usize>,
+	/// What to do when the buffer is full, defaults to `drop_oldest`
+	overflow: Option<Overflow>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+	/// Drop the oldest waiting record to make room, logging never blocks
+	DropOldest,
+	/// Block the logging thread until there is room, no record is ever lost
+	Block,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+struct Appender {
+	kind: String,
+	config: Value,
+}
+
+impl<'de> serde::Deserialize<'de> for Appender {
+	fn deserialize<D>(d: D) -> Result<Appender, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
+
+		let kind = match map.remove(&Value::String("kind".to_owned())) {
+			Some(kind) => kind.deserialize_into().map_err(|e| e.to_error())?,
+			None => return Err(serde::de::Error::missing_field("kind")),
+		};
+
+		Ok(Appender {
+			kind,
+			config: Value::Map(map),
+		})
+	}
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct AsyncAppenderDeserializer;
+
+impl Deserialize for AsyncAppenderDeserializer {
+	type Trait = dyn Append;
+
+	type Config = AsyncAppenderConfig;
+
+	fn deserialize(
+		&self,
+		config: AsyncAppenderConfig,
+		deserializers: &Deserializers,
+	) -> anyhow::Result<Box<dyn Append>> {
+		let appender: Box<dyn Append> =
+			deserializers.deserialize(&config.appender.kind, config.appender.config)?;
+		let (sender, receiver) = channel::bounded(config.buffer.unwrap_or(1024).max(1));
+		let dropped = Arc::new(AtomicU64::new(0));
+		{
+			let receiver = receiver.clone();
+			let dropped = dropped.clone();
+			std::thread::Builder::new()
+				.name("log-async-appender".to_owned())
+				.spawn(move || drain(appender, receiver, dropped))?;
+		}
+		Ok(Box::new(AsyncAppender {
+			sender,
+			receiver,
+			overflow: config.overflow.unwrap_or(Overflow::DropOldest),
+			dropped,
+		}))
+	}
+}
+
+enum Message {
+	Record(OwnedRecord),
+	Flush,
+}
+
+/// A record that outlives the logging call
+struct OwnedRecord {
+	level: Level,
+	target: String,
+	module_path: Option<String>,
+	file: Option<String>,
+	line: Option<u32>,
+	msg: String,
+}
+
+/// Appends everything sent, in order, until every sender is gone.  Errors are only printed as
+/// logging them could feed back into this appender.
+fn drain(appender: Box<dyn Append>, receiver: Receiver<Message>, dropped: Arc<AtomicU64>) {
+	for message in receiver {
+		match message {
+			Message::Record(record) => {
+				let result = appender.append(
+					&Record::builder()
+						.level(record.level)
+						.target(&record.target)
+						.module_path(record.module_path.as_deref())
+						.file(record.file.as_deref())
+						.line(record.line)
+						.args(format_args!("{}", record.msg))
+						.build(),
+				);
+				if let Err(e) = result {
+					eprintln!("log4rs async appender: {:?}", e);
+				}
+			}
+			Message::Flush => appender.flush(),
+		}
+		let dropped = dropped.swap(0, Ordering::Relaxed);
+		if dropped > 0 {
+			eprintln!(
+				"log4rs async appender: buffer was full, dropped {} records",
+				dropped
+			);
+		}
+	}
+	appender.flush();
+}
+
+#[derive(Debug)]
+pub struct AsyncAppender {
+	sender: Sender<Message>,
+	/// Only used to drop the oldest records on overflow
+	receiver: Receiver<Message>,
+	overflow: Overflow,
+	dropped: Arc<AtomicU64>,
+}
+
+impl AsyncAppender {
+	fn send(&self, mut message: Message) -> anyhow::Result<()> {
+		match self.overflow {
+			Overflow::Block => self.sender.send(message)?,
+			Overflow::DropOldest => loop {
+				match self.sender.try_send(message) {
+					Ok(()) => break,
+					Err(TrySendError::Full(returned)) => {
+						message = returned;
+						if let Ok(Message::Record(_)) = self.receiver.try_recv() {
+							self.dropped.fetch_add(1, Ordering::Relaxed);
+						}
+					}
+					Err(TrySendError::Disconnected(_)) => {
+						anyhow::bail!("async appender thread is gone")
+					}
+				}
+			},
+		}
+		Ok(())
+	}
+}
+
+impl Append for AsyncAppender {
+	fn append(&self, record: &Record) -> anyhow::Result<()> {
+		self.send(Message::Record(OwnedRecord {
+			level: record.level(),
+			target: record.target().to_owned(),
+			module_path: record.module_path().map(str::to_owned),
+			file: record.file().map(str::to_owned),
+			line: record.line(),
+			msg: record.args().to_string(),
+		}))
+	}
+
+	fn flush(&self) {
+		let _ = self.send(Message::Flush);
+	}
+}
@@ -1,11 +1,14 @@
 //! This is an appender that rolls a file on launch and then delegates to another appender
 
+use crate::logger::conditional_map::target_has_prefix;
 use log4rs::append::Append;
 use log4rs::config::{Deserialize, Deserializers};
 use log4rs::encode::{Encode, EncoderConfig, Write};
 use serde_value::Value;
 use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::{Arc, RwLock};
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
 use tracing::log::{Level, Record};
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
@@ -14,6 +17,11 @@ pub struct CacheAppenderConfig {
 	name: String,
 	count: usize,
 	encoder: Option<EncoderConfig>,
+	/// Only records with a target within one of these are cached, every target when `None`
+	include_targets: Option<Vec<String>>,
+	/// Records with a target within one of these are never cached
+	#[serde(default)]
+	exclude_targets: Vec<String>,
 }
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
@@ -54,6 +62,7 @@ impl Deserialize for CacheAppenderDeserializer {
 		config: CacheAppenderConfig,
 		deserializers: &Deserializers,
 	) -> anyhow::Result<Box<dyn Append>> {
+		let publisher = Cache::publisher(config.name.clone(), config.count.max(1));
 		let cache = Cache::get_or_create(config.name);
 		{
 			let mut cache = cache.write().expect("poisoned lock");
@@ -66,8 +75,11 @@ impl Deserialize for CacheAppenderDeserializer {
 		};
 		Ok(Box::new(CacheAppender {
 			cache,
+			publisher,
 			count: config.count,
 			encoder,
+			include_targets: config.include_targets,
+			exclude_targets: config.exclude_targets,
 		}))
 	}
 }
@@ -75,22 +87,48 @@ impl Deserialize for CacheAppenderDeserializer {
 #[derive(Debug)]
 pub struct CacheAppender {
 	cache: Arc<RwLock<VecDeque<CachedLogRecord>>>,
+	publisher: broadcast::Sender<CachedLogRecord>,
 	count: usize,
 	encoder: Box<dyn Encode>,
+	include_targets: Option<Vec<String>>,
+	exclude_targets: Vec<String>,
+}
+
+impl CacheAppender {
+	fn is_target_cached(&self, target: &str) -> bool {
+		self.include_targets.as_ref().map_or(true, |include| {
+			include
+				.iter()
+				.any(|prefix| target_has_prefix(target, prefix))
+		}) && !self
+			.exclude_targets
+			.iter()
+			.any(|prefix| target_has_prefix(target, prefix))
+	}
 }
 
 impl Append for CacheAppender {
 	fn append(&self, record: &Record) -> anyhow::Result<()> {
+		if !self.is_target_cached(record.target()) {
+			return Ok(());
+		}
 		let mut cache = self.cache.write().expect("poisoned lock");
 		let mut last = None;
 		while cache.len() >= self.count {
 			last = cache.pop_front();
 		}
+		// Reuses the evicted record's allocations
 		let mut last = last.unwrap_or_default();
-		last.0 = record.level();
-		last.1.clear();
+		last.level = record.level();
+		last.time = OffsetDateTime::now_utc();
+		last.target.clear();
+		last.target.push_str(record.target());
+		last.module = record.module_path().map(ToOwned::to_owned);
+		last.msg.clear();
 		self.encoder
-			.encode(&mut StringEncoder(&mut last.1), record)?;
+			.encode(&mut StringEncoder(&mut last.msg), record)?;
+		// Never blocks, lagging subscribers just lose the oldest records and no subscribers is fine
+		let _ = self.publisher.send(last.clone());
 		cache.push_back(last);
 		Ok(())
 	}
@@ -111,28 +149,55 @@ impl<'a> std::io::Write for StringEncoder<'a> {
 }
 impl<'a> Write for StringEncoder<'a> {}
 
-#[derive(Debug)]
-pub struct CachedLogRecord(Level, String);
+#[derive(Clone, Debug)]
+pub struct CachedLogRecord {
+	level: Level,
+	time: OffsetDateTime,
+	target: String,
+	module: Option<String>,
+	/// The record as encoded by the appender's encoder
+	msg: String,
+}
 
 impl Default for CachedLogRecord {
 	fn default() -> Self {
-		Self(Level::max(), String::new())
+		Self {
+			level: Level::max(),
+			time: OffsetDateTime::unix_epoch(),
+			target: String::new(),
+			module: None,
+			msg: String::new(),
+		}
 	}
 }
 
 impl CachedLogRecord {
 	pub fn level(&self) -> Level {
-		self.0
+		self.level
+	}
+
+	/// When the record was cached
+	pub fn time(&self) -> OffsetDateTime {
+		self.time
+	}
+
+	pub fn target(&self) -> &str {
+		&self.target
+	}
+
+	pub fn module(&self) -> Option<&str> {
+		self.module.as_deref()
 	}
 
 	pub fn msg(&self) -> &str {
-		&self.1
+		&self.msg
 	}
 }
 
 #[derive(Default)]
 pub struct Cache {
 	map: RwLock<HashMap<String, Arc<RwLock<VecDeque<CachedLogRecord>>>>>,
+	publishers: RwLock<HashMap<String, broadcast::Sender<CachedLogRecord>>>,
 }
 
 lazy_static::lazy_static! {
@@ -147,4 +212,43 @@ impl Cache {
 			.or_insert_with(|| Arc::new(RwLock::new(VecDeque::new())))
 			.clone()
 	}
+
+	fn publisher(name: String, capacity: usize) -> broadcast::Sender<CachedLogRecord> {
+		CACHE_MAP
+			.publishers
+			.write()
+			.expect("poisoned lock")
+			.entry(name)
+			.or_insert_with(|| broadcast::channel(capacity).0)
+			.clone()
+	}
+
+	/// Receives every record appended to the named cache from now on, `None` if no such cache
+	pub fn subscribe(name: &str) -> Option<broadcast::Receiver<CachedLogRecord>> {
+		CACHE_MAP
+			.publishers
+			.read()
+			.expect("poisoned lock")
+			.get(name)
+			.map(broadcast::Sender::subscribe)
+	}
+
+	/// The newest `limit` records of the named cache that are at least as severe as `min_level`,
+	/// oldest first, empty if no such cache exists
+	pub fn snapshot(name: &str, min_level: Level, limit: usize) -> Vec<CachedLogRecord> {
+		let cache = match CACHE_MAP.map.read().expect("poisoned lock").get(name) {
+			Some(cache) => cache.clone(),
+			None => return Vec::new(),
+		};
+		let cache = cache.read().expect("poisoned lock");
+		let mut records: Vec<CachedLogRecord> = cache
+			.iter()
+			.rev()
+			.filter(|record| record.level() <= min_level)
+			.take(limit)
+			.cloned()
+			.collect();
+		records.reverse();
+		records
+	}
 }
@@ -1,10 +1,58 @@
-use std::collections::HashMap;
-use std::sync::atomic::AtomicBool;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
+use tracing::log::{LevelFilter, Metadata};
 
 #[derive(Default)]
 pub struct ConditionalMap {
 	map: RwLock<HashMap<String, Arc<AtomicBool>>>,
+	levels: RwLock<Levels>,
+}
+
+/// Every level filter in front of the log4rs loggers, the loggers themselves let everything
+/// through so these can be changed while running
+#[derive(Default)]
+struct Levels {
+	/// The logger levels from `log4rs.ron`, the root logger is the empty target
+	configured: BTreeMap<String, LevelFilter>,
+	/// Caps the configured levels, but not the overrides
+	cap: Option<LevelFilter>,
+	/// Set while running, these win over the configured levels for every target they cover
+	overrides: BTreeMap<String, LevelFilter>,
+}
+
+impl Levels {
+	fn level_for(&self, target: &str) -> LevelFilter {
+		if let Some(level) = most_specific(&self.overrides, target) {
+			return level;
+		}
+		let configured = most_specific(&self.configured, target).unwrap_or(LevelFilter::Trace);
+		self.cap.map_or(configured, |cap| configured.min(cap))
+	}
+
+	/// The most verbose level any target can have, records above it are skipped before they're
+	/// even formatted
+	fn max_level(&self) -> LevelFilter {
+		let configured = self
+			.configured
+			.values()
+			.copied()
+			.max()
+			.unwrap_or(LevelFilter::Trace);
+		let configured = self.cap.map_or(configured, |cap| configured.min(cap));
+		self.overrides
+			.values()
+			.copied()
+			.fold(configured, LevelFilter::max)
+	}
+}
+
+fn most_specific(levels: &BTreeMap<String, LevelFilter>, target: &str) -> Option<LevelFilter> {
+	levels
+		.iter()
+		.filter(|(prefix, _)| target_has_prefix(target, prefix))
+		.max_by_key(|(prefix, _)| prefix.len())
+		.map(|(_, level)| *level)
 }
 
 lazy_static::lazy_static! {
@@ -30,4 +78,137 @@ impl ConditionalMap {
 			.or_insert_with(|| Arc::new(AtomicBool::new(default)))
 			.clone()
 	}
+
+	/// Every registered switch id, sorted
+	pub fn all_ids() -> Vec<String> {
+		let mut ids: Vec<String> = CONDITIONAL_MAP
+			.map
+			.read()
+			.expect("poisoned CONDITIONAL_MAP lock")
+			.keys()
+			.cloned()
+			.collect();
+		ids.sort();
+		ids
+	}
+
+	/// Turns every registered switch on or off at once
+	pub fn set_all(enabled: bool) {
+		for switch in CONDITIONAL_MAP
+			.map
+			.read()
+			.expect("poisoned CONDITIONAL_MAP lock")
+			.values()
+		{
+			switch.store(enabled, Ordering::SeqCst);
+		}
+	}
+
+	/// Sets the max level for a log target and every target under it (`a::b` covers `a::b::c`),
+	/// the empty target covers everything.  It wins over the `log4rs.ron` logger levels, so a
+	/// target can be raised to `Trace` while running.
+	pub fn set_level(target: impl Into<String>, level: LevelFilter) {
+		let mut levels = Self::write_levels();
+		levels.overrides.insert(target.into(), level);
+		tracing::log::set_max_level(levels.max_level());
+	}
+
+	/// The level set for exactly this target, if any
+	pub fn get_level(target: &str) -> Option<LevelFilter> {
+		Self::read_levels().overrides.get(target).copied()
+	}
+
+	pub fn clear_level(target: &str) -> Option<LevelFilter> {
+		let mut levels = Self::write_levels();
+		let cleared = levels.overrides.remove(target);
+		tracing::log::set_max_level(levels.max_level());
+		cleared
+	}
+
+	/// The levels set while running
+	pub fn levels() -> Vec<(String, LevelFilter)> {
+		Self::read_levels()
+			.overrides
+			.iter()
+			.map(|(target, level)| (target.clone(), *level))
+			.collect()
+	}
+
+	/// Replaces the logger levels from `log4rs.ron`, done when logging is initialized
+	pub fn set_configured_levels(configured: BTreeMap<String, LevelFilter>) {
+		let mut levels = Self::write_levels();
+		levels.configured = configured;
+		tracing::log::set_max_level(levels.max_level());
+	}
+
+	/// Caps the configured logger levels, the levels set while running can still go above it
+	pub fn set_level_cap(cap: Option<LevelFilter>) {
+		let mut levels = Self::write_levels();
+		levels.cap = cap;
+		tracing::log::set_max_level(levels.max_level());
+	}
+
+	/// Whether the level of the record's target allows it, the level set while running for the
+	/// most specific target covering it, or else the configured logger level
+	pub fn is_level_enabled(metadata: &Metadata) -> bool {
+		metadata.level() <= Self::read_levels().level_for(metadata.target())
+	}
+
+	fn read_levels() -> std::sync::RwLockReadGuard<'static, Levels> {
+		CONDITIONAL_MAP
+			.levels
+			.read()
+			.expect("poisoned CONDITIONAL_MAP lock")
+	}
+
+	fn write_levels() -> std::sync::RwLockWriteGuard<'static, Levels> {
+		CONDITIONAL_MAP
+			.levels
+			.write()
+			.expect("poisoned CONDITIONAL_MAP lock")
+	}
+}
+
+/// Whether `target` is `prefix` or a module within it, the empty prefix covers every target
+pub fn target_has_prefix(target: &str, prefix: &str) -> bool {
+	prefix.is_empty()
+		|| target == prefix
+		|| (target.starts_with(prefix) && target[prefix.len()..].starts_with("::"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn levels(configured: &[(&str, LevelFilter)]) -> Levels {
+		Levels {
+			configured: configured
+				.iter()
+				.map(|(target, level)| (target.to_string(), *level))
+				.collect(),
+			..Levels::default()
+		}
+	}
+
+	#[test]
+	fn configured_levels_apply_to_the_most_specific_logger() {
+		let levels = levels(&[("", LevelFilter::Info), ("hyper", LevelFilter::Warn)]);
+		assert_eq!(levels.level_for("overbot::web"), LevelFilter::Info);
+		assert_eq!(levels.level_for("hyper::client"), LevelFilter::Warn);
+		assert_eq!(levels.level_for("hyperlocal"), LevelFilter::Info);
+		assert_eq!(levels.max_level(), LevelFilter::Info);
+	}
+
+	#[test]
+	fn overrides_raise_a_target_above_its_configured_level_and_the_cap() {
+		let mut levels = levels(&[("", LevelFilter::Info), ("overbot", LevelFilter::Warn)]);
+		levels.cap = Some(LevelFilter::Warn);
+		levels
+			.overrides
+			.insert("overbot::web".to_owned(), LevelFilter::Trace);
+		assert_eq!(levels.level_for("overbot::web::auth"), LevelFilter::Trace);
+		assert_eq!(levels.level_for("overbot::irc"), LevelFilter::Warn);
+		assert_eq!(levels.level_for("sqlx"), LevelFilter::Warn);
+		assert_eq!(levels.max_level(), LevelFilter::Trace);
+	}
 }
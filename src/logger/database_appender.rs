@@ -0,0 +1,272 @@
+//! This is an appender that persists log records to the `_logs` table through a background task,
+//! the database isn't available when logging is initialized so records are buffered until the
+//! database pool is registered and the writer attaches
+
+use crate::dash_type_map::DashTypeMap;
+use crate::database::{Migration, Migrations};
+use anyhow::Context;
+use log4rs::append::Append;
+use log4rs::config::{Deserialize, Deserializers};
+use sqlx::PgPool;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, mpsc};
+use tracing::log::Record;
+use tracing::*;
+
+/// How long to wait for the database pool to be registered before giving up on a writer
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+/// The most records written in one transaction
+const MAX_BATCH: usize = 256;
+/// How long to wait after a failed write or prune before touching the database again, records
+/// keep buffering meanwhile
+const FAILURE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseAppenderConfig {
+	/// How many records to hold while the database isn't attached or is behind, further records
+	/// are dropped rather than blocking, defaults to 4096
+	buffer: Option<usize>,
+	/// Records older than this many seconds are pruned, kept forever if missing, must be at least 1
+	retention_secs: Option<u64>,
+}
+
+struct LogRow {
+	logged_at: OffsetDateTime,
+	level: &'static str,
+	target: String,
+	module: Option<String>,
+	message: String,
+}
+
+struct DatabaseLogWriter {
+	records: mpsc::Receiver<LogRow>,
+	dropped: Arc<AtomicU64>,
+	retention: Option<Duration>,
+}
+
+lazy_static::lazy_static! {
+	/// Writers created by the appender deserializer waiting to be attached to the database
+	static ref PENDING_WRITERS: Mutex<Vec<DatabaseLogWriter>> = Mutex::new(Vec::new());
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct DatabaseAppenderDeserializer;
+
+impl Deserialize for DatabaseAppenderDeserializer {
+	type Trait = dyn Append;
+
+	type Config = DatabaseAppenderConfig;
+
+	fn deserialize(
+		&self,
+		config: DatabaseAppenderConfig,
+		_deserializers: &Deserializers,
+	) -> anyhow::Result<Box<dyn Append>> {
+		anyhow::ensure!(
+			config.retention_secs != Some(0),
+			"database appender `retention_secs` must be at least 1, leave it out to keep records forever"
+		);
+		let (sender, records) = mpsc::channel(config.buffer.unwrap_or(4096).max(1));
+		let dropped = Arc::new(AtomicU64::new(0));
+		PENDING_WRITERS
+			.lock()
+			.expect("poisoned lock")
+			.push(DatabaseLogWriter {
+				records,
+				dropped: dropped.clone(),
+				retention: config.retention_secs.map(Duration::from_secs),
+			});
+		Ok(Box::new(DatabaseAppender { sender, dropped }))
+	}
+}
+
+#[derive(Debug)]
+pub struct DatabaseAppender {
+	sender: mpsc::Sender<LogRow>,
+	dropped: Arc<AtomicU64>,
+}
+
+impl Append for DatabaseAppender {
+	fn append(&self, record: &Record) -> anyhow::Result<()> {
+		// The database driver logs its own queries, persisting those would feed back forever
+		if record.target().starts_with("sqlx") {
+			return Ok(());
+		}
+		let row = LogRow {
+			logged_at: OffsetDateTime::now_utc(),
+			level: record.level().as_str(),
+			target: record.target().to_owned(),
+			module: record.module_path().map(str::to_owned),
+			message: record.args().to_string(),
+		};
+		if self.sender.try_send(row).is_err() {
+			self.dropped.fetch_add(1, Ordering::Relaxed);
+		}
+		Ok(())
+	}
+
+	fn flush(&self) {}
+}
+
+/// Spawns a writer for every configured database appender, each waits for the `PgPool` to be
+/// registered in `data`, then flushes what was buffered and keeps writing until `quit`
+pub fn attach_writers(
+	data: Arc<DashTypeMap>,
+	quit: broadcast::Sender<()>,
+	allow_checksum_override: bool,
+) {
+	let writers = std::mem::take(&mut *PENDING_WRITERS.lock().expect("poisoned lock"));
+	for writer in writers {
+		let data = data.clone();
+		let on_quit = quit.subscribe();
+		tokio::spawn(async move {
+			if let Err(e) = writer.run(data, on_quit, allow_checksum_override).await {
+				error!("Database log writer failed: {:?}", e);
+			}
+		});
+	}
+}
+
+impl DatabaseLogWriter {
+	async fn run(
+		mut self,
+		data: Arc<DashTypeMap>,
+		mut on_quit: broadcast::Receiver<()>,
+		allow_checksum_override: bool,
+	) -> anyhow::Result<()> {
+		let pool = data.wait_clone_if_arc::<PgPool>(ATTACH_TIMEOUT).await?;
+		MIGRATIONS
+			.migrate_up(&pool, allow_checksum_override)
+			.await?;
+		info!("Database log writer attached");
+		let mut prune = tokio::time::interval(
+			self.retention
+				.map_or(Duration::from_secs(60 * 60), |retention| {
+					retention.min(Duration::from_secs(60 * 60))
+				}),
+		);
+		loop {
+			let result = tokio::select! {
+				_ = on_quit.recv() => break,
+				row = self.records.recv() => match row {
+					Some(row) => self.write_batch(&pool, row).await,
+					None => break,
+				},
+				_ = prune.tick(), if self.retention.is_some() => self.prune(&pool).await,
+			};
+			// Logged straight to stderr as logging it would only queue more records for here, the
+			// writer keeps going so a database restart doesn't end audit logging for good
+			if let Err(e) = result {
+				eprintln!("Database log writer: {:?}", e);
+				tokio::time::sleep(FAILURE_DELAY).await;
+			}
+		}
+		// Flush whatever was still buffered at shutdown
+		while let Ok(row) = self.records.try_recv() {
+			if let Err(e) = self.write_batch(&pool, row).await {
+				eprintln!("Database log writer: {:?}", e);
+				break;
+			}
+		}
+		Ok(())
+	}
+
+	async fn write_batch(&mut self, pool: &PgPool, first: LogRow) -> anyhow::Result<()> {
+		let mut batch = vec![first];
+		while batch.len() < MAX_BATCH {
+			match self.records.try_recv() {
+				Ok(row) => batch.push(row),
+				Err(_) => break,
+			}
+		}
+		let count = batch.len();
+		Self::insert(pool, batch)
+			.await
+			.with_context(|| format!("failed writing {} log records, they are dropped", count))?;
+		let dropped = self.dropped.swap(0, Ordering::Relaxed);
+		if dropped > 0 {
+			warn!(
+				"Database log appender buffer was full, dropped {} records",
+				dropped
+			);
+		}
+		Ok(())
+	}
+
+	async fn insert(pool: &PgPool, batch: Vec<LogRow>) -> anyhow::Result<()> {
+		let mut conn = pool.begin().await?;
+		for row in batch {
+			sqlx::query(
+				"INSERT INTO _logs (logged_at, level, target, module, message) VALUES ($1, $2, $3, $4, $5)",
+			)
+			.bind(row.logged_at)
+			.bind(row.level)
+			.bind(row.target)
+			.bind(row.module)
+			.bind(row.message)
+			.execute(&mut conn)
+			.await?;
+		}
+		conn.commit().await?;
+		Ok(())
+	}
+
+	async fn prune(&self, pool: &PgPool) -> anyhow::Result<()> {
+		if let Some(retention) = self.retention {
+			let cutoff = OffsetDateTime::now_utc() - retention;
+			let pruned = sqlx::query("DELETE FROM _logs WHERE logged_at < $1")
+				.bind(cutoff)
+				.execute(pool)
+				.await
+				.context("failed pruning old log records")?
+				.rows_affected();
+			if pruned > 0 {
+				debug!("Pruned {} old log records from the database", pruned);
+			}
+		}
+		Ok(())
+	}
+}
+
+const MIGRATIONS: Migrations = Migrations::new(
+	"Logs",
+	&[Migration::new("Create _logs table")
+		.up(r#"
+			CREATE TABLE _logs (
+				id bigserial NOT NULL,
+				logged_at timestamp with time zone NOT NULL,
+				level text NOT NULL,
+				target text NOT NULL,
+				module text,
+				message text NOT NULL,
+				CONSTRAINT _logs_pkey PRIMARY KEY (id)
+			) WITH ( OIDS=FALSE );
+			CREATE INDEX _logs_logged_at_idx ON _logs (logged_at);
+			"#)
+		.down(
+			r#"
+			DROP TABLE _logs;
+			"#,
+		)],
+);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn zero_retention_is_rejected() {
+		let config = DatabaseAppenderConfig {
+			buffer: None,
+			retention_secs: Some(0),
+		};
+		let error = DatabaseAppenderDeserializer
+			.deserialize(config, &Deserializers::new())
+			.unwrap_err();
+		assert!(error.to_string().contains("retention_secs"), "{}", error);
+	}
+}
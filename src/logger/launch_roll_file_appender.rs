@@ -13,6 +13,23 @@ pub struct LaunchRollFileAppenderConfig {
 	appender: Appender,
 	path: String,
 	launch_roller: Roller,
+	/// Also rolls the file while running whenever it grows past a size, otherwise it's only rolled
+	/// at launch
+	#[serde(default)]
+	size_trigger: Option<SizeTrigger>,
+	/// Gzips the rolled files, appends `.gz` to the roller patterns if they don't already have it
+	#[serde(default)]
+	compress: bool,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SizeTrigger {
+	/// Size in bytes, or a string with a unit like `"10 mb"`
+	limit: Value,
+	/// Defaults to the `launch_roller`
+	#[serde(default)]
+	roller: Option<Roller>,
 }
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
@@ -65,6 +82,29 @@ impl<'de> serde::Deserialize<'de> for Roller {
 	}
 }
 
+impl Roller {
+	/// The `fixed_window` roller gzips whenever its pattern ends with `.gz`, so its window count
+	/// applies to the compressed files the same as it would to uncompressed ones
+	fn compressed(self) -> Self {
+		let config = match self.config {
+			Value::Map(mut map) => {
+				let key = Value::String("pattern".to_owned());
+				if let Some(Value::String(pattern)) = map.get_mut(&key) {
+					if !pattern.ends_with(".gz") {
+						pattern.push_str(".gz");
+					}
+				}
+				Value::Map(map)
+			}
+			config => config,
+		};
+		Self {
+			kind: self.kind,
+			config,
+		}
+	}
+}
+
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 struct Policy {
 	kind: String,
@@ -100,18 +140,127 @@ impl Deserialize for RollFileOnLaunchAppenderDeserializer {
 
 	fn deserialize(
 		&self,
-		config: LaunchRollFileAppenderConfig,
+		mut config: LaunchRollFileAppenderConfig,
 		deserializers: &Deserializers,
 	) -> anyhow::Result<Box<dyn Append>> {
+		if config.compress {
+			config.launch_roller = config.launch_roller.compressed();
+			if let Some(size_trigger) = config.size_trigger.as_mut() {
+				size_trigger.roller = size_trigger.roller.take().map(Roller::compressed);
+			}
+		}
 		let path = Path::new(&config.path);
 		if path.exists() && path.is_file() {
-			let launch_roller: Box<dyn Roll> = deserializers
-				.deserialize(&config.launch_roller.kind, config.launch_roller.config)?;
+			let launch_roller: Box<dyn Roll> = deserializers.deserialize(
+				&config.launch_roller.kind,
+				config.launch_roller.config.clone(),
+			)?;
 			launch_roller.roll(path)?;
 		}
 
-		let appender = deserializers.deserialize(&config.appender.kind, config.appender.config)?;
+		let appender = match config.size_trigger {
+			None => deserializers.deserialize(&config.appender.kind, config.appender.config)?,
+			Some(size_trigger) => {
+				// Reuse the encoder of the wrapped appender for the rolling file appender that
+				// replaces it
+				let encoder = match &config.appender.config {
+					Value::Map(map) => map.get(&Value::String("encoder".to_owned())).cloned(),
+					_ => None,
+				};
+				let roller = size_trigger.roller.unwrap_or(config.launch_roller);
+				let policy = value_map(vec![
+					("kind", Value::String("compound".to_owned())),
+					(
+						"trigger",
+						value_map(vec![
+							("kind", Value::String("size".to_owned())),
+							("limit", size_trigger.limit),
+						]),
+					),
+					("roller", with_kind(roller.kind, roller.config)),
+				]);
+				let mut rolling = vec![
+					("path", Value::String(config.path)),
+					("append", Value::Bool(true)),
+					("policy", policy),
+				];
+				if let Some(encoder) = encoder {
+					rolling.push(("encoder", encoder));
+				}
+				deserializers.deserialize("rolling_file", value_map(rolling))?
+			}
+		};
 
 		Ok(appender)
 	}
 }
+
+fn value_map(entries: Vec<(&str, Value)>) -> Value {
+	Value::Map(
+		entries
+			.into_iter()
+			.map(|(key, value)| (Value::String(key.to_owned()), value))
+			.collect(),
+	)
+}
+
+fn with_kind(kind: String, config: Value) -> Value {
+	match config {
+		Value::Map(mut map) => {
+			map.insert(Value::String("kind".to_owned()), Value::String(kind));
+			Value::Map(map)
+		}
+		_ => value_map(vec![("kind", Value::String(kind))]),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Read;
+
+	#[test]
+	fn compressed_launch_roll_gzips_the_previous_log() {
+		let dir = std::env::temp_dir().join(format!(
+			"overbot-launch-roll-{}-{}",
+			std::process::id(),
+			time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+		));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("latest.log");
+		std::fs::write(&path, "previous launch\n").unwrap();
+		let path_value = Value::String(path.to_string_lossy().into_owned());
+		let config = LaunchRollFileAppenderConfig {
+			appender: Appender {
+				kind: "file".to_owned(),
+				config: value_map(vec![("path", path_value)]),
+			},
+			path: path.to_string_lossy().into_owned(),
+			launch_roller: Roller {
+				kind: "fixed_window".to_owned(),
+				config: value_map(vec![
+					(
+						"pattern",
+						Value::String(dir.join("previous-{}.log").to_string_lossy().into_owned()),
+					),
+					("count", Value::U64(3)),
+				]),
+			},
+			size_trigger: None,
+			compress: true,
+		};
+		RollFileOnLaunchAppenderDeserializer
+			.deserialize(config, &Deserializers::default())
+			.unwrap();
+
+		let rolled = dir.join("previous-0.log.gz");
+		assert!(rolled.is_file(), "{:?} was not rolled", rolled);
+		assert!(!dir.join("previous-0.log").exists());
+		let mut contents = String::new();
+		flate2::read::GzDecoder::new(std::fs::File::open(&rolled).unwrap())
+			.read_to_string(&mut contents)
+			.unwrap();
+		assert_eq!(contents, "previous launch\n");
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}
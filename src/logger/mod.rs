@@ -1,12 +1,18 @@
+pub mod async_appender;
 pub mod cache_appender;
 pub mod conditional_append_appender;
 pub mod conditional_map;
+pub mod database_appender;
 pub mod launch_roll_file_appender;
+pub mod switchable_encoder;
+pub mod syslog_appender;
 
+use crate::logger::conditional_map::ConditionalMap;
 use log4rs::config::runtime::ConfigErrors;
-use log4rs::config::{Config, Deserializers, RawConfig};
+use log4rs::config::{Config, Deserializers, RawConfig, Root};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use tracing::log::SetLoggerError;
+use tracing::log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
 
 const DEFAULT_LOGGING_DEFINITION_RON: &'static str = r#"(
 	// Default values for loggers
@@ -153,6 +159,19 @@ const DEFAULT_LOGGING_DEFINITION_RON: &'static str = r#"(
 				"count": 5,
 				"pattern": "log/previous-{}.log",
 			},
+			// Set to `true` to gzip the rolled files, `.gz` is appended to the roller patterns.
+			// "compress": true,
+			// Optionally also roll while running once the file grows past `limit` bytes (or a
+			// string with a unit like "10 mb"), `roller` is optional and defaults to the
+			// `launch_roller`.
+			// "size_trigger": Some({
+			// 	"limit": 10485760,
+			// 	"roller": Some({
+			// 		"kind": "fixed_window",
+			// 		"count": 5,
+			// 		"pattern": "log/previous-{}.log",
+			// 	}),
+			// }),
 			// This is the appender that is then run after the `launch_roller` is run.
 			"appender": {
 				// A file appender just outputs to a file.
@@ -162,12 +181,59 @@ const DEFAULT_LOGGING_DEFINITION_RON: &'static str = r#"(
 				// An encoder such as described above.  If this is set as a `json` encode kind then
 				// it takes no other arguments, instead the entire log message is logged as
 				// structured json.
+				// The `switchable` encoder wraps a `pattern` and a `json` encoder and can be
+				// switched between them while running, such as by the TUI `json on` command, by
+				// toggling the `id` in the conditional map, defaulting to "json_logs".
 				"encoder": {
-					"kind": "pattern",
-					"pattern": "{d} [{t}:{I}:{T}] {h({l})} {M}: {m}{n}",
+					"kind": "switchable",
+					"id": Some("json_logs"),
+					// Start out encoding with the `json` encoder instead.
+					"default_json": Some(false),
+					"pattern": {
+						"kind": "pattern",
+						"pattern": "{d} [{t}:{I}:{T}] {h({l})} {M}: {m}{n}",
+					},
+					"json": {
+						"kind": "json",
+					},
 				},
 			},
 		},
+		// Forwards to syslog, add "syslog" to the root `appenders` to enable it.  Sends to the
+		// local `/dev/log` unless a remote `udp` or `tcp` address like `Some("10.0.0.1:514")` is
+		// given.  `facility` is a syslog facility name like "daemon", "user", or "local0".
+		// "syslog": {
+		// 	"kind": "syslog",
+		// 	"facility": "daemon",
+		// 	"udp": None,
+		// 	"tcp": None,
+		// 	"ident": Some("overbot"),
+		// 	"encoder": Some({
+		// 		"kind": "pattern",
+		// 		"pattern": "{M}: {m}",
+		// 	}),
+		// },
+		// Wraps another appender so it runs on its own thread, logging only queues the record.
+		// `overflow` is either "drop_oldest" to never block, or "block" to never lose records
+		// when more than `buffer` records are waiting.  Timestamps in the wrapped appender's
+		// encoder are from when the record is written, not when it was logged.
+		// "async_log_file": {
+		// 	"kind": "async",
+		// 	"buffer": Some(1024),
+		// 	"overflow": Some("drop_oldest"),
+		// 	"appender": {
+		// 		"kind": "file",
+		// 		"path": "log/async.log",
+		// 	},
+		// },
+		// Persists records to the `_logs` database table, add "database" to the root `appenders`
+		// to enable it.  Records are buffered until the database is up, up to `buffer` of them,
+		// and are deleted after `retention_secs` if it's given.
+		// "database": {
+		// 	"kind": "database",
+		// 	"buffer": Some(4096),
+		// 	"retention_secs": Some(2592000),
+		// },
 		"tui_log_view": {
 			// This is a named cache logger, used for the TUI log view, so don't remove this if you
 			// might ever use the TUI log view.
@@ -181,6 +247,11 @@ const DEFAULT_LOGGING_DEFINITION_RON: &'static str = r#"(
 				"kind": "pattern",
 				"pattern": "{d(%H:%M:%S%.3f)} {h({l})} {M}: {m}",
 			},
+			// Optionally only cache records whose target is within one of these, such as
+			// "overbot" for "overbot::web", everything is cached when this is `None`.
+			// "include_targets": Some(["overbot"]),
+			// And never cache records whose target is within one of these.
+			// "exclude_targets": ["sqlx::query", "rocket::server"],
 		},
 	},
 
@@ -245,17 +316,44 @@ pub fn init_logging(config_dir: Option<&Path>) -> Result<(), Error> {
 				}
 				path
 			};
-			let config = config_from_ron_file(&logger_config_path, &deserializers())?;
-			log4rs::init_config(config)?;
+			let (config, levels) = config_from_ron_file(&logger_config_path, &deserializers())?;
+			install(config, levels)?;
 		}
 		None => {
-			let config = config_from_ron_string(DEFAULT_LOGGING_DEFINITION_RON, &deserializers())?;
-			log4rs::init_config(config)?;
+			let (config, levels) =
+				config_from_ron_string(DEFAULT_LOGGING_DEFINITION_RON, &deserializers())?;
+			install(config, levels)?;
 		}
 	};
 	Ok(())
 }
 
+/// Lets every record through the log4rs loggers and filters by the `ConditionalMap` levels in
+/// front of them instead, so those levels can be changed while running
+struct LevelFilteredLogger(log4rs::Logger);
+
+impl Log for LevelFilteredLogger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		ConditionalMap::is_level_enabled(metadata) && self.0.enabled(metadata)
+	}
+
+	fn log(&self, record: &Record) {
+		if ConditionalMap::is_level_enabled(record.metadata()) {
+			self.0.log(record);
+		}
+	}
+
+	fn flush(&self) {
+		self.0.flush();
+	}
+}
+
+fn install(config: Config, levels: BTreeMap<String, LevelFilter>) -> Result<(), Error> {
+	ConditionalMap::set_configured_levels(levels);
+	tracing::log::set_boxed_logger(Box::new(LevelFilteredLogger(log4rs::Logger::new(config))))?;
+	Ok(())
+}
+
 fn deserializers() -> Deserializers {
 	let mut deserializers = Deserializers::new();
 	deserializers.insert(
@@ -267,29 +365,61 @@ fn deserializers() -> Deserializers {
 		conditional_append_appender::ConditionallyAppendAppenderDeserializer,
 	);
 	deserializers.insert("cache_logger", cache_appender::CacheAppenderDeserializer);
+	deserializers.insert("syslog", syslog_appender::SyslogAppenderDeserializer);
+	deserializers.insert("database", database_appender::DatabaseAppenderDeserializer);
+	deserializers.insert("async", async_appender::AsyncAppenderDeserializer);
+	deserializers.insert(
+		"switchable",
+		switchable_encoder::SwitchableEncoderDeserializer,
+	);
 	deserializers
 }
 
+/// The configuration with every logger at `Trace`, along with the levels they were configured at
+type LeveledConfig = (Config, BTreeMap<String, LevelFilter>);
+
 fn config_from_ron_file(
 	ron_path: impl AsRef<Path>,
 	deserializers: &Deserializers,
-) -> Result<Config, Error> {
+) -> Result<LeveledConfig, Error> {
 	let ron = std::fs::read_to_string(ron_path)?;
 	config_from_ron_string(&ron, deserializers).map_err(Into::into)
 }
 
-fn config_from_ron_string(ron: &str, deserializers: &Deserializers) -> Result<Config, ron::Error> {
+fn config_from_ron_string(
+	ron: &str,
+	deserializers: &Deserializers,
+) -> Result<LeveledConfig, ron::Error> {
 	let raw_config: RawConfig = ron::from_str(ron)?;
 
 	let (appenders, mut errors) = raw_config.appenders_lossy(&deserializers);
 	errors.handle();
 
+	// The levels are applied by `LevelFilteredLogger` instead so they can be changed at runtime
+	let root = raw_config.root();
+	let mut levels = BTreeMap::new();
+	levels.insert(String::new(), root.max_log_level());
+	let loggers: Vec<log4rs::config::Logger> = raw_config
+		.loggers()
+		.into_iter()
+		.map(|logger| {
+			levels.insert(logger.name().to_owned(), logger.max_log_level());
+			log4rs::config::Logger::builder()
+				.appenders(logger.appenders().iter().cloned())
+				.additive(logger.additive())
+				.build(logger.name(), LevelFilter::Trace)
+		})
+		.collect();
+	let root = Root::builder()
+		.appenders(root.appenders().iter().cloned())
+		.build(LevelFilter::Trace);
+
 	let (config, mut errors) = Config::builder()
 		.appenders(appenders)
-		.loggers(raw_config.loggers())
-		.build_lossy(raw_config.root());
+		.loggers(loggers)
+		.build_lossy(root);
 
 	errors.handle();
 
-	Ok(config)
+	Ok((config, levels))
 }
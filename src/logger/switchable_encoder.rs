@@ -0,0 +1,92 @@
+//! This is an encoder that switches between a `pattern` and a `json` encoder at runtime
+
+use crate::logger::conditional_map::ConditionalMap;
+use log4rs::config::{Deserialize, Deserializers};
+use log4rs::encode::{self, Encode};
+use serde_value::Value;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::log::Record;
+
+/// The conditional map id the switch uses when the config doesn't give one
+pub const DEFAULT_SWITCH_ID: &str = "json_logs";
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SwitchableEncoderConfig {
+	pattern: Encoder,
+	json: Encoder,
+	id: Option<String>,
+	default_json: Option<bool>,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+struct Encoder {
+	kind: String,
+	config: Value,
+}
+
+impl<'de> serde::Deserialize<'de> for Encoder {
+	fn deserialize<D>(d: D) -> Result<Encoder, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
+
+		let kind = match map.remove(&Value::String("kind".to_owned())) {
+			Some(kind) => kind.deserialize_into().map_err(|e| e.to_error())?,
+			None => return Err(serde::de::Error::missing_field("kind")),
+		};
+
+		Ok(Encoder {
+			kind,
+			config: Value::Map(map),
+		})
+	}
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct SwitchableEncoderDeserializer;
+
+impl Deserialize for SwitchableEncoderDeserializer {
+	type Trait = dyn Encode;
+
+	type Config = SwitchableEncoderConfig;
+
+	fn deserialize(
+		&self,
+		config: SwitchableEncoderConfig,
+		deserializers: &Deserializers,
+	) -> anyhow::Result<Box<dyn Encode>> {
+		let pattern = deserializers.deserialize(&config.pattern.kind, config.pattern.config)?;
+		let json = deserializers.deserialize(&config.json.kind, config.json.config)?;
+
+		Ok(Box::new(SwitchableEncoder {
+			pattern,
+			json,
+			use_json: ConditionalMap::get_or_create_by_id(
+				config.id.unwrap_or_else(|| DEFAULT_SWITCH_ID.to_owned()),
+				config.default_json.unwrap_or(false),
+			),
+		}))
+	}
+}
+
+#[derive(Debug)]
+pub struct SwitchableEncoder {
+	pattern: Box<dyn Encode>,
+	json: Box<dyn Encode>,
+	use_json: Arc<AtomicBool>,
+}
+
+impl Encode for SwitchableEncoder {
+	fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> anyhow::Result<()> {
+		// Read once so a record is always encoded entirely by one encoder, even mid switch
+		if self.use_json.load(Ordering::Relaxed) {
+			self.json.encode(w, record)
+		} else {
+			self.pattern.encode(w, record)
+		}
+	}
+}
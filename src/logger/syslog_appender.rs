@@ -0,0 +1,296 @@
+//! This is an appender that forwards log records to syslog, locally or over udp or tcp
+
+use log4rs::append::Append;
+use log4rs::config::{Deserialize, Deserializers};
+use log4rs::encode::{Encode, EncoderConfig, Write};
+use std::io::Write as _;
+use std::net::{TcpStream, UdpSocket};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use tracing::log::{Level, Record};
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+	Kern,
+	User,
+	Mail,
+	Daemon,
+	Auth,
+	Syslog,
+	Lpr,
+	News,
+	Uucp,
+	Cron,
+	AuthPriv,
+	Ftp,
+	Local0,
+	Local1,
+	Local2,
+	Local3,
+	Local4,
+	Local5,
+	Local6,
+	Local7,
+}
+
+impl Facility {
+	fn code(self) -> u8 {
+		match self {
+			Facility::Kern => 0,
+			Facility::User => 1,
+			Facility::Mail => 2,
+			Facility::Daemon => 3,
+			Facility::Auth => 4,
+			Facility::Syslog => 5,
+			Facility::Lpr => 6,
+			Facility::News => 7,
+			Facility::Uucp => 8,
+			Facility::Cron => 9,
+			Facility::AuthPriv => 10,
+			Facility::Ftp => 11,
+			Facility::Local0 => 16,
+			Facility::Local1 => 17,
+			Facility::Local2 => 18,
+			Facility::Local3 => 19,
+			Facility::Local4 => 20,
+			Facility::Local5 => 21,
+			Facility::Local6 => 22,
+			Facility::Local7 => 23,
+		}
+	}
+}
+
+fn severity(level: Level) -> u8 {
+	match level {
+		Level::Error => 3,
+		Level::Warn => 4,
+		Level::Info => 6,
+		Level::Debug | Level::Trace => 7,
+	}
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogAppenderConfig {
+	facility: Facility,
+	/// Remote `host:port` to send to over udp
+	udp: Option<String>,
+	/// Remote `host:port` to send to over tcp, using octet counted framing
+	tcp: Option<String>,
+	/// The app name reported to syslog, defaults to `overbot`
+	ident: Option<String>,
+	encoder: Option<EncoderConfig>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct SyslogAppenderDeserializer;
+
+impl Deserialize for SyslogAppenderDeserializer {
+	type Trait = dyn Append;
+
+	type Config = SyslogAppenderConfig;
+
+	fn deserialize(
+		&self,
+		config: SyslogAppenderConfig,
+		deserializers: &Deserializers,
+	) -> anyhow::Result<Box<dyn Append>> {
+		// Only connected on the first record so an unreachable syslog can't stop the bot starting
+		let endpoint = match (config.udp, config.tcp) {
+			(Some(_), Some(_)) => {
+				anyhow::bail!("syslog appender can only have one of `udp` or `tcp`")
+			}
+			(Some(addr), None) => Endpoint::Udp(addr),
+			(None, Some(addr)) => Endpoint::Tcp(addr),
+			#[cfg(unix)]
+			(None, None) => Endpoint::Unix(LOCAL_SYSLOG_PATH),
+			#[cfg(not(unix))]
+			(None, None) => anyhow::bail!("syslog appender requires a `udp` or `tcp` address"),
+		};
+		let transport = Transport {
+			endpoint,
+			connection: Mutex::new(None),
+		};
+		let encoder: Box<dyn Encode> = if let Some(encoder) = config.encoder {
+			deserializers.deserialize(&encoder.kind, encoder.config)?
+		} else {
+			Box::new(log4rs::encode::pattern::PatternEncoder::new("{M}: {m}"))
+		};
+		Ok(Box::new(SyslogAppender {
+			facility: config.facility,
+			ident: config.ident.unwrap_or_else(|| "overbot".to_owned()),
+			pid: std::process::id(),
+			transport,
+			encoder,
+		}))
+	}
+}
+
+#[cfg(unix)]
+const LOCAL_SYSLOG_PATH: &str = "/dev/log";
+
+#[derive(Debug)]
+enum Endpoint {
+	#[cfg(unix)]
+	Unix(&'static str),
+	Udp(String),
+	/// Octet counted framing
+	Tcp(String),
+}
+
+#[derive(Debug)]
+enum Connection {
+	#[cfg(unix)]
+	Unix(std::os::unix::net::UnixDatagram),
+	Udp(UdpSocket),
+	Tcp(TcpStream),
+}
+
+impl Endpoint {
+	fn connect(&self) -> std::io::Result<Connection> {
+		match self {
+			#[cfg(unix)]
+			Endpoint::Unix(path) => {
+				let socket = std::os::unix::net::UnixDatagram::unbound()?;
+				socket.connect(path)?;
+				Ok(Connection::Unix(socket))
+			}
+			Endpoint::Udp(addr) => {
+				let socket = UdpSocket::bind("0.0.0.0:0")?;
+				socket.connect(addr.as_str())?;
+				Ok(Connection::Udp(socket))
+			}
+			Endpoint::Tcp(addr) => Ok(Connection::Tcp(TcpStream::connect(addr.as_str())?)),
+		}
+	}
+}
+
+/// Connects on first use and again on the next record after any send fails
+#[derive(Debug)]
+struct Transport {
+	endpoint: Endpoint,
+	connection: Mutex<Option<Connection>>,
+}
+
+impl Transport {
+	fn send(&self, message: &[u8]) -> std::io::Result<()> {
+		let mut connection = self.connection.lock().expect("poisoned lock");
+		if connection.is_none() {
+			*connection = Some(self.endpoint.connect()?);
+		}
+		let result = match connection.as_mut().expect("just connected") {
+			#[cfg(unix)]
+			Connection::Unix(socket) => socket.send(message).map(|_| ()),
+			Connection::Udp(socket) => socket.send(message).map(|_| ()),
+			Connection::Tcp(stream) => {
+				let mut framed = format!("{} ", message.len()).into_bytes();
+				framed.extend_from_slice(message);
+				stream.write_all(&framed)
+			}
+		};
+		if result.is_err() {
+			// Reconnect on the next record rather than blocking this one on it
+			*connection = None;
+		}
+		result
+	}
+}
+
+#[derive(Debug)]
+pub struct SyslogAppender {
+	facility: Facility,
+	ident: String,
+	pid: u32,
+	transport: Transport,
+	encoder: Box<dyn Encode>,
+}
+
+impl Append for SyslogAppender {
+	fn append(&self, record: &Record) -> anyhow::Result<()> {
+		// RFC 5424 header, the hostname is left as the nil value for the receiver to fill in
+		let priority = self.facility.code() * 8 + severity(record.level());
+		let timestamp = OffsetDateTime::now_utc().format(time::Format::Rfc3339);
+		let mut message = format!(
+			"<{}>1 {} - {} {} - - ",
+			priority, timestamp, self.ident, self.pid
+		);
+		self.encoder
+			.encode(&mut StringEncoder(&mut message), record)?;
+		self.transport.send(message.as_bytes())?;
+		Ok(())
+	}
+
+	fn flush(&self) {}
+}
+
+struct StringEncoder<'a>(&'a mut String);
+impl<'a> std::io::Write for StringEncoder<'a> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.push_str(&String::from_utf8_lossy(buf));
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+impl<'a> Write for StringEncoder<'a> {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unreachable_syslog_does_not_fail_deserializing() {
+		let config = SyslogAppenderConfig {
+			facility: Facility::Daemon,
+			udp: None,
+			// Nothing listens on the discard port in the test environment
+			tcp: Some("127.0.0.1:9".to_owned()),
+			ident: None,
+			encoder: None,
+		};
+		let appender = SyslogAppenderDeserializer
+			.deserialize(config, &Deserializers::new())
+			.unwrap();
+		assert!(appender
+			.append(
+				&Record::builder()
+					.level(Level::Info)
+					.args(format_args!("unsent"))
+					.build()
+			)
+			.is_err());
+	}
+
+	#[test]
+	fn udp_syslog_connects_on_the_first_record() {
+		let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let config = SyslogAppenderConfig {
+			facility: Facility::Local0,
+			udp: Some(receiver.local_addr().unwrap().to_string()),
+			tcp: None,
+			ident: Some("overbot-test".to_owned()),
+			encoder: None,
+		};
+		let appender = SyslogAppenderDeserializer
+			.deserialize(config, &Deserializers::new())
+			.unwrap();
+		appender
+			.append(
+				&Record::builder()
+					.level(Level::Warn)
+					.args(format_args!("hello syslog"))
+					.build(),
+			)
+			.unwrap();
+		let mut buf = [0u8; 1024];
+		let len = receiver.recv(&mut buf).unwrap();
+		let message = String::from_utf8_lossy(&buf[..len]);
+		// local0 * 8 + warning
+		assert!(message.starts_with("<132>1 "), "{}", message);
+		assert!(message.contains(" overbot-test "), "{}", message);
+		assert!(message.ends_with("hello syslog"), "{}", message);
+	}
+}
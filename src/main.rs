@@ -1,9 +1,11 @@
 use crate::system::System;
 
 pub mod accounts;
+pub mod build_info;
 pub mod dash_type_map;
 pub mod database;
 pub mod logger;
+pub mod metrics;
 pub mod system;
 pub mod system_tasks;
 pub mod web;
@@ -0,0 +1,185 @@
+//! Process wide metrics rendered in the Prometheus text exposition format
+
+use dashmap::DashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds in seconds of the request duration histogram buckets
+const DURATION_BUCKETS: [f64; 11] = [
+	0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+	/// Per bucket, not cumulative, the last is everything past the largest bound
+	buckets: [u64; DURATION_BUCKETS.len() + 1],
+	sum: f64,
+	count: u64,
+}
+
+impl Histogram {
+	fn observe(&mut self, value: f64) {
+		let bucket = DURATION_BUCKETS
+			.iter()
+			.position(|bound| value <= *bound)
+			.unwrap_or(DURATION_BUCKETS.len());
+		self.buckets[bucket] += 1;
+		self.sum += value;
+		self.count += 1;
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum LoginMetric {
+	Success,
+	TwoFactorRequired,
+	Failure,
+}
+
+impl LoginMetric {
+	fn as_str(self) -> &'static str {
+		match self {
+			LoginMetric::Success => "success",
+			LoginMetric::TwoFactorRequired => "two_factor_required",
+			LoginMetric::Failure => "failure",
+		}
+	}
+}
+
+/// A gauge sampled at render time rather than tracked
+pub struct Gauge {
+	pub name: &'static str,
+	pub help: &'static str,
+	pub value: f64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+	http_requests: DashMap<(String, u16), u64>,
+	http_durations: DashMap<String, Histogram>,
+	logins: DashMap<LoginMetric, u64>,
+}
+
+lazy_static::lazy_static! {
+	static ref METRICS: Metrics = Metrics::default();
+}
+
+impl Metrics {
+	pub fn global() -> &'static Metrics {
+		&METRICS
+	}
+
+	pub fn record_request(&self, method: &str, status: u16, duration: Duration) {
+		*self
+			.http_requests
+			.entry((method.to_owned(), status))
+			.or_default() += 1;
+		self.http_durations
+			.entry(method.to_owned())
+			.or_default()
+			.observe(duration.as_secs_f64());
+	}
+
+	pub fn record_login(&self, outcome: LoginMetric) {
+		*self.logins.entry(outcome).or_default() += 1;
+	}
+
+	pub fn render(&self, gauges: &[Gauge]) -> String {
+		let mut out = String::new();
+		// Writing to a `String` can't fail
+		let _ = self.render_into(&mut out, gauges);
+		out
+	}
+
+	fn render_into(&self, out: &mut String, gauges: &[Gauge]) -> std::fmt::Result {
+		writeln!(
+			out,
+			"# HELP overbot_http_requests_total HTTP requests served."
+		)?;
+		writeln!(out, "# TYPE overbot_http_requests_total counter")?;
+		let mut requests: Vec<_> = self
+			.http_requests
+			.iter()
+			.map(|entry| (entry.key().clone(), *entry.value()))
+			.collect();
+		requests.sort();
+		for ((method, status), count) in requests {
+			writeln!(
+				out,
+				"overbot_http_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+				method, status, count
+			)?;
+		}
+
+		writeln!(
+			out,
+			"# HELP overbot_http_request_duration_seconds HTTP request handling time."
+		)?;
+		writeln!(
+			out,
+			"# TYPE overbot_http_request_duration_seconds histogram"
+		)?;
+		let mut methods: Vec<String> = self
+			.http_durations
+			.iter()
+			.map(|entry| entry.key().clone())
+			.collect();
+		methods.sort();
+		for method in methods {
+			let histogram = match self.http_durations.get(&method) {
+				Some(histogram) => histogram,
+				None => continue,
+			};
+			let mut cumulative = 0;
+			for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+				cumulative += count;
+				writeln!(
+					out,
+					"overbot_http_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+					method, bound, cumulative
+				)?;
+			}
+			writeln!(
+				out,
+				"overbot_http_request_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+				method, histogram.count
+			)?;
+			writeln!(
+				out,
+				"overbot_http_request_duration_seconds_sum{{method=\"{}\"}} {}",
+				method, histogram.sum
+			)?;
+			writeln!(
+				out,
+				"overbot_http_request_duration_seconds_count{{method=\"{}\"}} {}",
+				method, histogram.count
+			)?;
+		}
+
+		writeln!(
+			out,
+			"# HELP overbot_logins_total Login attempts by outcome."
+		)?;
+		writeln!(out, "# TYPE overbot_logins_total counter")?;
+		for outcome in &[
+			LoginMetric::Success,
+			LoginMetric::TwoFactorRequired,
+			LoginMetric::Failure,
+		] {
+			let count = self.logins.get(outcome).map_or(0, |count| *count);
+			writeln!(
+				out,
+				"overbot_logins_total{{outcome=\"{}\"}} {}",
+				outcome.as_str(),
+				count
+			)?;
+		}
+
+		for gauge in gauges {
+			writeln!(out, "# HELP {} {}", gauge.name, gauge.help)?;
+			writeln!(out, "# TYPE {} gauge", gauge.name)?;
+			writeln!(out, "{} {}", gauge.name, gauge.value)?;
+		}
+		Ok(())
+	}
+}
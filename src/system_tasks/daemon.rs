@@ -1,5 +1,7 @@
+use crate::dash_type_map::DashTypeMap;
 use crate::system::{System, SystemPlugin};
 use anyhow::Context;
+use std::sync::Arc;
 use tokio::task::JoinHandle;
 use tracing::*;
 
@@ -14,13 +16,36 @@ impl Daemon {
 	}
 }
 
+/// Registered while a daemon task is handling signals so a second one doesn't register as well
+struct SignalHandlerRunning;
+
+/// Unregisters the signal handler when the task ends, even by panic, so it can be spawned again
+struct SignalHandlerGuard(Arc<DashTypeMap>);
+
+impl Drop for SignalHandlerGuard {
+	fn drop(&mut self) {
+		let _ = self.0.remove::<SignalHandlerRunning>();
+	}
+}
+
 #[typetag::serde]
 impl SystemPlugin for Daemon {
 	fn spawn(&self, system: &System) -> Option<JoinHandle<anyhow::Result<()>>> {
+		if system
+			.registered_data
+			.insert::<SignalHandlerRunning>(SignalHandlerRunning)
+			.is_err()
+		{
+			warn!("A daemon task is already handling signals, not spawning another");
+			return None;
+		}
+		let guard = SignalHandlerGuard(system.registered_data.clone());
 		let headless = self.headless;
 		let do_quit = system.quit.clone();
+		let do_reload = system.reload.clone();
 		let mut on_quit = system.quit.subscribe();
 		let handle = tokio::task::spawn(async move {
+			let _guard = guard;
 			info!("Daemon task has launched");
 			// Just wait until quit is requested, and then exit, or if ctrl+c is pressed, then exit safely.
 			loop {
@@ -40,7 +65,8 @@ impl SystemPlugin for Daemon {
 				let do_break = tokio::select! {
 					_ = hangup.recv() => {
 						if headless {
-							info!("Hangup requested, daemon mode ignores it");
+							info!("Hangup requested, reloading the configuration");
+							let _ = do_reload.send(());
 							false
 						} else {
 							info!("Hangup requested, cleanly exiting");
@@ -0,0 +1,170 @@
+use crate::dash_type_map::DashTypeMap;
+use crate::system_tasks::irc::connection::IrcConnectionHandle;
+use dashmap::DashMap;
+use std::sync::Arc;
+use tracing::*;
+
+/// Where a command came from and how to answer it
+pub struct CommandContext {
+	/// The name of the connection the command came in on
+	pub connection: String,
+	pub handle: IrcConnectionHandle,
+	/// The nick that sent the command
+	pub nick: String,
+	/// The channel the command was sent in, or the sender's nick for a private message
+	pub reply_to: String,
+	pub commands: Arc<CommandRegistry>,
+	pub data: Arc<DashTypeMap>,
+}
+
+impl CommandContext {
+	pub fn reply(&self, text: &str) -> anyhow::Result<()> {
+		self.handle.send_message(&self.reply_to, text)
+	}
+}
+
+/// A bot command invoked by messaging the command prefix followed by its name
+#[rocket::async_trait]
+pub trait Command: Send + Sync {
+	fn name(&self) -> &str;
+
+	fn help(&self) -> &str;
+
+	/// `args` is the rest of the message after the command name, trimmed
+	async fn handle(&self, ctx: &CommandContext, args: &str) -> anyhow::Result<()>;
+}
+
+/// The bot commands by name, registered in the `DashTypeMap` for plugins to add to
+#[derive(Default)]
+pub struct CommandRegistry {
+	commands: DashMap<String, Arc<dyn Command>>,
+}
+
+impl CommandRegistry {
+	/// A registry with the built in commands
+	pub fn with_builtins() -> Self {
+		let registry = Self::default();
+		registry.register(Arc::new(HelpCommand));
+		registry
+	}
+
+	/// Returns `false` without replacing it if a command with the same name already exists
+	pub fn register(&self, command: Arc<dyn Command>) -> bool {
+		match self.commands.entry(command.name().to_lowercase()) {
+			dashmap::mapref::entry::Entry::Occupied(_) => false,
+			dashmap::mapref::entry::Entry::Vacant(entry) => {
+				entry.insert(command);
+				true
+			}
+		}
+	}
+
+	pub fn unregister(&self, name: &str) -> Option<Arc<dyn Command>> {
+		self.commands
+			.remove(&name.to_lowercase())
+			.map(|(_name, command)| command)
+	}
+
+	pub fn get(&self, name: &str) -> Option<Arc<dyn Command>> {
+		self.commands
+			.get(&name.to_lowercase())
+			.map(|command| command.clone())
+	}
+
+	/// Every command sorted by name
+	pub fn list(&self) -> Vec<Arc<dyn Command>> {
+		let mut commands: Vec<Arc<dyn Command>> = self
+			.commands
+			.iter()
+			.map(|command| command.value().clone())
+			.collect();
+		commands.sort_by(|a, b| a.name().cmp(b.name()));
+		commands
+	}
+}
+
+/// Dispatches messages starting with the prefix to the matching command
+#[derive(Clone)]
+pub struct CommandDispatcher {
+	pub prefix: String,
+	pub commands: Arc<CommandRegistry>,
+	pub data: Arc<DashTypeMap>,
+}
+
+impl CommandDispatcher {
+	/// Runs the command in its own task so a slow one doesn't hold up the connection, does
+	/// nothing if the text isn't a command
+	pub fn dispatch(
+		&self,
+		connection: &str,
+		handle: &IrcConnectionHandle,
+		nick: &str,
+		reply_to: &str,
+		text: &str,
+	) {
+		let invocation = match text.strip_prefix(self.prefix.as_str()) {
+			Some(invocation) if !self.prefix.is_empty() => invocation,
+			_ => return,
+		};
+		let (name, args) = match invocation.split_once(char::is_whitespace) {
+			Some((name, args)) => (name, args.trim()),
+			None => (invocation, ""),
+		};
+		let command = match self.commands.get(name) {
+			Some(command) => command,
+			None => return,
+		};
+		let args = args.to_owned();
+		let ctx = CommandContext {
+			connection: connection.to_owned(),
+			handle: handle.clone(),
+			nick: nick.to_owned(),
+			reply_to: reply_to.to_owned(),
+			commands: self.commands.clone(),
+			data: self.data.clone(),
+		};
+		tokio::spawn(async move {
+			debug!(
+				"IRC `{}` command {} from {}",
+				ctx.connection,
+				command.name(),
+				ctx.nick
+			);
+			if let Err(e) = command.handle(&ctx, &args).await {
+				warn!("IRC command {} failed: {:?}", command.name(), e);
+				let _ = ctx.reply(&format!("{} failed: {}", command.name(), e));
+			}
+		});
+	}
+}
+
+/// Lists the commands, or the help of one command
+pub struct HelpCommand;
+
+#[rocket::async_trait]
+impl Command for HelpCommand {
+	fn name(&self) -> &str {
+		"help"
+	}
+
+	fn help(&self) -> &str {
+		"help [command] - Lists the commands, or describes one"
+	}
+
+	async fn handle(&self, ctx: &CommandContext, args: &str) -> anyhow::Result<()> {
+		if args.is_empty() {
+			let names: Vec<String> = ctx
+				.commands
+				.list()
+				.iter()
+				.map(|command| command.name().to_owned())
+				.collect();
+			ctx.reply(&format!("Commands: {}", names.join(", ")))
+		} else {
+			match ctx.commands.get(args) {
+				Some(command) => ctx.reply(command.help()),
+				None => ctx.reply(&format!("Unknown command: {}", args)),
+			}
+		}
+	}
+}
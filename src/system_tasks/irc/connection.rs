@@ -0,0 +1,489 @@
+use crate::system_tasks::irc::command::CommandDispatcher;
+use crate::system_tasks::irc::history::{HistorySender, StoredMessage};
+use crate::system_tasks::irc::message::Message;
+use anyhow::Context;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+use tracing::*;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Registration has to complete in this long or the connection is retried
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many outgoing lines can queue up, including while reconnecting
+const OUTGOING_CAPACITY: usize = 64;
+
+/// A password that is never printed by `Debug` or traced, it's still written out with the rest
+/// of the configuration so it survives reloads and environment overrides
+#[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+	pub fn expose(&self) -> &str {
+		&self.0
+	}
+}
+
+impl std::fmt::Debug for SecretString {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("[redacted]")
+	}
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct SaslConfig {
+	pub username: String,
+	pub password: SecretString,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct IrcServerConfig {
+	pub enabled: bool,
+	pub host: String,
+	pub port: u16,
+	pub tls: bool,
+	pub nick: String,
+	/// Defaults to the nick
+	pub username: Option<String>,
+	/// Defaults to the nick
+	pub realname: Option<String>,
+	/// The server password sent with `PASS`, not a NickServ or SASL password
+	pub password: Option<SecretString>,
+	/// Authenticates with SASL PLAIN while registering, channels aren't joined if it fails
+	pub sasl: Option<SaslConfig>,
+	/// Identifies to NickServ once registered, only used without `sasl`
+	pub nickserv_password: Option<SecretString>,
+	pub channels: Vec<String>,
+	/// Stores the channel messages of this connection in the database
+	pub persist_history: bool,
+}
+
+impl IrcServerConfig {
+	/// Checks for values that could never connect
+	pub fn validate(&self) -> anyhow::Result<()> {
+		anyhow::ensure!(!self.host.is_empty(), "IRC host is empty");
+		anyhow::ensure!(
+			!self.nick.is_empty() && !self.nick.contains(char::is_whitespace),
+			"IRC nick is empty or has whitespace: {:?}",
+			self.nick
+		);
+		if self.tls {
+			DNSNameRef::try_from_ascii_str(&self.host)
+				.map_err(|_e| anyhow::anyhow!("invalid TLS host name: {}", self.host))?;
+		}
+		Ok(())
+	}
+}
+
+impl Default for IrcServerConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			host: "irc.libera.chat".to_owned(),
+			port: 6697,
+			tls: true,
+			nick: "overbot".to_owned(),
+			username: None,
+			realname: None,
+			password: None,
+			sasl: None,
+			nickserv_password: None,
+			channels: vec![],
+			persist_history: false,
+		}
+	}
+}
+
+trait IrcStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IrcStream for T {}
+
+/// Where SASL authentication is at during registration
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum SaslState {
+	Unused,
+	Requested,
+	Authenticating,
+	Succeeded,
+	Failed,
+}
+
+enum SessionEnd {
+	Quit,
+	Disconnected { registered: bool },
+}
+
+/// The sending side of a connection, lines queue while it's reconnecting
+#[derive(Clone, Debug)]
+pub struct IrcConnectionHandle {
+	outgoing: mpsc::Sender<String>,
+	connected: Arc<AtomicBool>,
+}
+
+impl IrcConnectionHandle {
+	/// Whether the connection is currently registered with the server
+	pub fn is_connected(&self) -> bool {
+		self.connected.load(Ordering::Relaxed)
+	}
+
+	/// Queues a raw protocol line, it must not contain line breaks
+	pub fn send_raw(&self, line: impl Into<String>) -> anyhow::Result<()> {
+		let line = line.into();
+		anyhow::ensure!(
+			!line.contains(|c| c == '\r' || c == '\n'),
+			"IRC line contains a line break"
+		);
+		self.outgoing
+			.try_send(line)
+			.map_err(|e| anyhow::anyhow!("unable to queue IRC line: {}", e))
+	}
+
+	/// Sends a message to a channel or nick, one `PRIVMSG` per line of the text
+	pub fn send_message(&self, target: &str, text: &str) -> anyhow::Result<()> {
+		for line in text.lines().filter(|line| !line.is_empty()) {
+			self.send_raw(format!("PRIVMSG {} :{}", target, line))?;
+		}
+		Ok(())
+	}
+}
+
+/// The connection loop and the handle to send through it
+pub struct IrcConnection {
+	name: String,
+	config: IrcServerConfig,
+	outgoing: mpsc::Receiver<String>,
+	connected: Arc<AtomicBool>,
+	/// Kept to hand to the commands for replying
+	handle: IrcConnectionHandle,
+	dispatcher: Option<CommandDispatcher>,
+	history: Option<HistorySender>,
+}
+
+impl IrcConnection {
+	pub fn new(
+		name: String,
+		config: IrcServerConfig,
+		dispatcher: Option<CommandDispatcher>,
+		history: Option<HistorySender>,
+	) -> (Self, IrcConnectionHandle) {
+		let (sender, outgoing) = mpsc::channel(OUTGOING_CAPACITY);
+		let connected = Arc::new(AtomicBool::new(false));
+		let handle = IrcConnectionHandle {
+			outgoing: sender,
+			connected: connected.clone(),
+		};
+		let connection = Self {
+			name,
+			outgoing,
+			connected,
+			handle: handle.clone(),
+			dispatcher,
+			history: if config.persist_history {
+				history
+			} else {
+				None
+			},
+			config,
+		};
+		(connection, handle)
+	}
+
+	/// Keeps a connection to the server up, reconnecting with a backoff, until `quit`
+	pub async fn run(mut self, mut on_quit: broadcast::Receiver<()>) -> anyhow::Result<()> {
+		let config = self.config.clone();
+		let mut backoff = INITIAL_BACKOFF;
+		loop {
+			let end = self.session(&mut on_quit).await;
+			self.connected.store(false, Ordering::Relaxed);
+			match end {
+				Ok(SessionEnd::Quit) => break,
+				Ok(SessionEnd::Disconnected { registered }) => {
+					warn!(
+						"IRC `{}` disconnected from {}:{}",
+						self.name, config.host, config.port
+					);
+					if registered {
+						backoff = INITIAL_BACKOFF;
+					}
+				}
+				Err(e) => error!(
+					"IRC `{}` connection to {}:{} failed: {:?}",
+					self.name, config.host, config.port, e
+				),
+			}
+			info!("IRC `{}` reconnecting in {:?}", self.name, backoff);
+			tokio::select! {
+				_ = on_quit.recv() => break,
+				_ = tokio::time::sleep(backoff) => (),
+			}
+			backoff = (backoff * 2).min(MAX_BACKOFF);
+		}
+		Ok(())
+	}
+
+	async fn session(
+		&mut self,
+		on_quit: &mut broadcast::Receiver<()>,
+	) -> anyhow::Result<SessionEnd> {
+		let config = &self.config;
+		info!("IRC connecting to {}:{}", config.host, config.port);
+		let stream = connect(config).await?;
+		let (reader, mut writer) = tokio::io::split(stream);
+		let mut reader = BufReader::new(reader);
+		// Kept between reads as a read cut short by another branch leaves its partial line here
+		let mut buf = Vec::new();
+
+		let mut sasl_state = SaslState::Unused;
+		if config.sasl.is_some() {
+			// Holds registration open until `CAP END` so authentication happens before welcome
+			send_line(&mut writer, "CAP REQ :sasl").await?;
+			sasl_state = SaslState::Requested;
+		}
+		if let Some(password) = &config.password {
+			send_secret_line(&mut writer, &format!("PASS {}", password.expose())).await?;
+		}
+		let mut nick = config.nick.clone();
+		send_line(&mut writer, &format!("NICK {}", nick)).await?;
+		send_line(
+			&mut writer,
+			&format!(
+				"USER {} 0 * :{}",
+				config.username.as_deref().unwrap_or(&config.nick),
+				config.realname.as_deref().unwrap_or(&config.nick)
+			),
+		)
+		.await?;
+
+		let mut registered = false;
+		let registration_timeout = tokio::time::sleep(REGISTRATION_TIMEOUT);
+		tokio::pin!(registration_timeout);
+		loop {
+			let read = tokio::select! {
+				_ = on_quit.recv() => {
+					info!("IRC quitting {}", config.host);
+					let _ = send_line(&mut writer, "QUIT :Shutting down").await;
+					return Ok(SessionEnd::Quit);
+				}
+				_ = &mut registration_timeout, if !registered => {
+					anyhow::bail!("timed out registering with the server");
+				}
+				Some(line) = self.outgoing.recv(), if registered => {
+					send_line(&mut writer, &line).await?;
+					continue;
+				}
+				read = reader.read_until(b'\n', &mut buf) => read?,
+			};
+			if read == 0 {
+				return Ok(SessionEnd::Disconnected { registered });
+			}
+			// Plenty of networks still send Latin-1 and the like, so invalid UTF-8 is replaced
+			// rather than ending the session
+			let line = String::from_utf8_lossy(&buf)
+				.trim_end_matches(&['\r', '\n'][..])
+				.to_owned();
+			buf.clear();
+			trace!("IRC << {}", line);
+			let message = match Message::parse(&line) {
+				Some(message) => message,
+				None => continue,
+			};
+			match message.command {
+				"PING" => {
+					send_line(
+						&mut writer,
+						&format!("PONG :{}", message.param(0).unwrap_or_default()),
+					)
+					.await?
+				}
+				"CAP" if sasl_state == SaslState::Requested => {
+					let acked = message.param(1) == Some("ACK")
+						&& message
+							.params
+							.last()
+							.map_or(false, |caps| caps.split(' ').any(|cap| cap == "sasl"));
+					if acked {
+						send_line(&mut writer, "AUTHENTICATE PLAIN").await?;
+						sasl_state = SaslState::Authenticating;
+					} else {
+						error!(
+							"IRC `{}` server {} refused SASL, not joining channels",
+							self.name, config.host
+						);
+						sasl_state = SaslState::Failed;
+						send_line(&mut writer, "CAP END").await?;
+					}
+				}
+				"AUTHENTICATE"
+					if sasl_state == SaslState::Authenticating && message.param(0) == Some("+") =>
+				{
+					if let Some(sasl) = &config.sasl {
+						send_sasl_plain(&mut writer, sasl).await?;
+					}
+				}
+				// RPL_SASLSUCCESS
+				"903" if sasl_state == SaslState::Authenticating => {
+					info!("IRC `{}` authenticated with SASL", self.name);
+					sasl_state = SaslState::Succeeded;
+					send_line(&mut writer, "CAP END").await?;
+				}
+				// ERR_NICKLOCKED, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED
+				"902" | "904" | "905" | "906" if sasl_state == SaslState::Authenticating => {
+					error!(
+						"IRC `{}` SASL authentication failed on {}, not joining channels: {}",
+						self.name,
+						config.host,
+						message.params.last().copied().unwrap_or_default()
+					);
+					sasl_state = SaslState::Failed;
+					send_line(&mut writer, "CAP END").await?;
+				}
+				// RPL_WELCOME
+				"001" => {
+					registered = true;
+					self.connected.store(true, Ordering::Relaxed);
+					info!(
+						"IRC `{}` registered on {} as {}",
+						self.name, config.host, nick
+					);
+					if sasl_state == SaslState::Failed {
+						continue;
+					}
+					if let (None, Some(password)) = (&config.sasl, &config.nickserv_password) {
+						send_secret_line(
+							&mut writer,
+							&format!("PRIVMSG NickServ :IDENTIFY {} {}", nick, password.expose()),
+						)
+						.await?;
+					}
+					for channel in &config.channels {
+						send_line(&mut writer, &format!("JOIN {}", channel)).await?;
+					}
+				}
+				// ERR_NICKNAMEINUSE
+				"433" if !registered => {
+					nick.push('_');
+					warn!("IRC nick in use, trying {}", nick);
+					send_line(&mut writer, &format!("NICK {}", nick)).await?;
+				}
+				"JOIN" if message.nick() == Some(nick.as_str()) => {
+					info!(
+						"IRC joined {} on {}",
+						message.param(0).unwrap_or_default(),
+						config.host
+					);
+				}
+				"PRIVMSG" => {
+					info!(
+						"IRC `{}` {} <{}> {}",
+						self.name,
+						message.param(0).unwrap_or_default(),
+						message.nick().unwrap_or_default(),
+						message.param(1).unwrap_or_default()
+					);
+					if let (Some(history), Some(sender), Some(target), Some(text)) = (
+						&self.history,
+						message.nick(),
+						message.param(0),
+						message.param(1),
+					) {
+						// Only channel messages, private ones stay private
+						if target != nick {
+							history.store(StoredMessage {
+								connection: self.name.clone(),
+								channel: target.to_owned(),
+								nick: sender.to_owned(),
+								message: text.to_owned(),
+								sent_at: OffsetDateTime::now_utc(),
+							});
+						}
+					}
+					if let (Some(dispatcher), Some(sender), Some(target), Some(text)) = (
+						&self.dispatcher,
+						message.nick(),
+						message.param(0),
+						message.param(1),
+					) {
+						// Private messages are answered privately
+						let reply_to = if target == nick { sender } else { target };
+						dispatcher.dispatch(&self.name, &self.handle, sender, reply_to, text);
+					}
+				}
+				"ERROR" => {
+					warn!(
+						"IRC server {} sent an error: {}",
+						config.host,
+						message.param(0).unwrap_or_default()
+					);
+				}
+				_ => (),
+			}
+		}
+	}
+}
+
+async fn connect(config: &IrcServerConfig) -> anyhow::Result<Box<dyn IrcStream>> {
+	let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+	if !config.tls {
+		return Ok(Box::new(tcp));
+	}
+	let mut tls_config = ClientConfig::new();
+	tls_config
+		.root_store
+		.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+	let domain = DNSNameRef::try_from_ascii_str(&config.host)
+		.with_context(|| format!("invalid IRC server host name: {}", config.host))?;
+	let tls = TlsConnector::from(Arc::new(tls_config))
+		.connect(domain, tcp)
+		.await?;
+	Ok(Box::new(tls))
+}
+
+async fn send_line(writer: &mut (impl AsyncWrite + Unpin), line: &str) -> anyhow::Result<()> {
+	trace!("IRC >> {}", line);
+	writer.write_all(line.as_bytes()).await?;
+	writer.write_all(b"\r\n").await?;
+	writer.flush().await?;
+	Ok(())
+}
+
+/// Like `send_line` but without tracing the line itself
+async fn send_secret_line(
+	writer: &mut (impl AsyncWrite + Unpin),
+	line: &str,
+) -> anyhow::Result<()> {
+	trace!("IRC >> [line with a secret redacted]");
+	writer.write_all(line.as_bytes()).await?;
+	writer.write_all(b"\r\n").await?;
+	writer.flush().await?;
+	Ok(())
+}
+
+/// The SASL PLAIN response, split into 400 byte `AUTHENTICATE` chunks as the protocol requires
+async fn send_sasl_plain(
+	writer: &mut (impl AsyncWrite + Unpin),
+	sasl: &SaslConfig,
+) -> anyhow::Result<()> {
+	let encoded = base64::encode(format!(
+		"{}\0{}\0{}",
+		sasl.username,
+		sasl.username,
+		sasl.password.expose()
+	));
+	for chunk in encoded.as_bytes().chunks(400) {
+		let chunk = std::str::from_utf8(chunk).expect("base64 is ascii");
+		send_secret_line(writer, &format!("AUTHENTICATE {}", chunk)).await?;
+	}
+	if encoded.len() % 400 == 0 {
+		send_line(writer, "AUTHENTICATE +").await?;
+	}
+	Ok(())
+}
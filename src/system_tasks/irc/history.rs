@@ -0,0 +1,130 @@
+use crate::database::DbPool;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, mpsc};
+use tracing::*;
+
+/// How many received messages can wait to be written before new ones are dropped
+const HISTORY_BUFFER: usize = 4096;
+/// The most messages written in one insert
+const MAX_BATCH: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct StoredMessage {
+	pub connection: String,
+	pub channel: String,
+	pub nick: String,
+	pub message: String,
+	pub sent_at: OffsetDateTime,
+}
+
+/// Queues received messages for the history writer, never blocks the connection
+#[derive(Clone, Debug)]
+pub struct HistorySender(mpsc::Sender<StoredMessage>);
+
+impl HistorySender {
+	pub fn store(&self, message: StoredMessage) {
+		if self.0.try_send(message).is_err() {
+			warn!("IRC history buffer is full, dropping a message");
+		}
+	}
+}
+
+pub struct IrcHistory;
+
+impl IrcHistory {
+	/// Spawns the writer that batches stored messages into the database until `quit`
+	pub fn spawn_writer(db_pool: DbPool, quit: &broadcast::Sender<()>) -> HistorySender {
+		let (sender, mut messages) = mpsc::channel(HISTORY_BUFFER);
+		let mut on_quit = quit.subscribe();
+		tokio::spawn(async move {
+			loop {
+				let first = tokio::select! {
+					_ = on_quit.recv() => break,
+					message = messages.recv() => match message {
+						Some(message) => message,
+						None => break,
+					},
+				};
+				if let Err(e) = Self::write_batch(&db_pool, first, &mut messages).await {
+					error!("Failed storing IRC history: {:?}", e);
+				}
+			}
+			// Flush whatever was still buffered at shutdown
+			while let Ok(first) = messages.try_recv() {
+				if let Err(e) = Self::write_batch(&db_pool, first, &mut messages).await {
+					error!("Failed storing IRC history: {:?}", e);
+					break;
+				}
+			}
+		});
+		HistorySender(sender)
+	}
+
+	async fn write_batch(
+		db_pool: &PgPool,
+		first: StoredMessage,
+		messages: &mut mpsc::Receiver<StoredMessage>,
+	) -> anyhow::Result<()> {
+		let mut batch = vec![first];
+		while batch.len() < MAX_BATCH {
+			match messages.try_recv() {
+				Ok(message) => batch.push(message),
+				Err(_) => break,
+			}
+		}
+		let mut connections = Vec::with_capacity(batch.len());
+		let mut channels = Vec::with_capacity(batch.len());
+		let mut nicks = Vec::with_capacity(batch.len());
+		let mut texts = Vec::with_capacity(batch.len());
+		let mut sent_ats = Vec::with_capacity(batch.len());
+		for message in batch {
+			connections.push(message.connection);
+			channels.push(message.channel);
+			nicks.push(message.nick);
+			texts.push(message.message);
+			sent_ats.push(message.sent_at);
+		}
+		// One round trip for the whole batch
+		sqlx::query(
+			"INSERT INTO irc_messages (connection, channel, nick, message, sent_at) SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::timestamptz[])",
+		)
+		.bind(connections)
+		.bind(channels)
+		.bind(nicks)
+		.bind(texts)
+		.bind(sent_ats)
+		.execute(db_pool)
+		.await?;
+		Ok(())
+	}
+
+	/// The newest `limit` messages of a channel on a connection, oldest first
+	pub async fn last_messages(
+		db_pool: &PgPool,
+		connection: &str,
+		channel: &str,
+		limit: i64,
+	) -> Result<Vec<StoredMessage>, sqlx::Error> {
+		let mut messages: Vec<StoredMessage> =
+			sqlx::query_as::<_, (String, String, String, String, OffsetDateTime)>(
+				"SELECT connection, channel, nick, message, sent_at FROM irc_messages WHERE connection = $1 AND lower(channel) = lower($2) ORDER BY sent_at DESC, id DESC LIMIT $3",
+			)
+			.bind(connection)
+			.bind(channel)
+			.bind(limit)
+			.fetch_all(db_pool)
+			.await?
+			.into_iter()
+			.map(|(connection, channel, nick, message, sent_at)| StoredMessage {
+				connection,
+				channel,
+				nick,
+				message,
+				sent_at,
+			})
+			.collect();
+		messages.reverse();
+		Ok(messages)
+	}
+}
@@ -0,0 +1,130 @@
+//! Parsing of raw IRC protocol lines, any IRCv3 message tags are skipped
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Message<'a> {
+	pub prefix: Option<&'a str>,
+	pub command: &'a str,
+	pub params: Vec<&'a str>,
+}
+
+impl<'a> Message<'a> {
+	/// Parses a single line with the line ending already stripped, `None` if it has no command
+	pub fn parse(line: &'a str) -> Option<Self> {
+		let mut rest = line;
+		if rest.starts_with('@') {
+			rest = rest.split_once(' ')?.1.trim_start_matches(' ');
+		}
+		let prefix = if let Some(stripped) = rest.strip_prefix(':') {
+			let (prefix, remaining) = stripped.split_once(' ')?;
+			rest = remaining.trim_start_matches(' ');
+			Some(prefix)
+		} else {
+			None
+		};
+		let (command, mut rest) = match rest.split_once(' ') {
+			Some((command, remaining)) => (command, remaining),
+			None => (rest, ""),
+		};
+		if command.is_empty() {
+			return None;
+		}
+		let mut params = Vec::new();
+		loop {
+			rest = rest.trim_start_matches(' ');
+			if rest.is_empty() {
+				break;
+			}
+			if let Some(trailing) = rest.strip_prefix(':') {
+				params.push(trailing);
+				break;
+			}
+			match rest.split_once(' ') {
+				Some((param, remaining)) => {
+					params.push(param);
+					rest = remaining;
+				}
+				None => {
+					params.push(rest);
+					break;
+				}
+			}
+		}
+		Some(Self {
+			prefix,
+			command,
+			params,
+		})
+	}
+
+	/// The nick part of a `nick!user@host` prefix
+	pub fn nick(&self) -> Option<&'a str> {
+		self.prefix
+			.map(|prefix| prefix.split_once('!').map_or(prefix, |(nick, _)| nick))
+	}
+
+	pub fn param(&self, index: usize) -> Option<&'a str> {
+		self.params.get(index).copied()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_prefix_command_and_trailing_parameter() {
+		let message =
+			Message::parse(":nick!user@host PRIVMSG #channel :hello there  world").unwrap();
+		assert_eq!(message.prefix, Some("nick!user@host"));
+		assert_eq!(message.nick(), Some("nick"));
+		assert_eq!(message.command, "PRIVMSG");
+		assert_eq!(message.params, vec!["#channel", "hello there  world"]);
+	}
+
+	#[test]
+	fn skips_message_tags() {
+		let message = Message::parse(
+			"@time=2021-01-01T00:00:00.000Z;msgid=abc :irc.example.com 001 bot :Welcome",
+		)
+		.unwrap();
+		assert_eq!(message.prefix, Some("irc.example.com"));
+		assert_eq!(message.command, "001");
+		assert_eq!(message.params, vec!["bot", "Welcome"]);
+	}
+
+	#[test]
+	fn parses_without_a_prefix_or_parameters() {
+		assert_eq!(
+			Message::parse("PING :irc.example.com"),
+			Some(Message {
+				prefix: None,
+				command: "PING",
+				params: vec!["irc.example.com"],
+			})
+		);
+		let message = Message::parse("QUIT").unwrap();
+		assert_eq!(message.command, "QUIT");
+		assert!(message.params.is_empty());
+	}
+
+	#[test]
+	fn keeps_an_empty_trailing_parameter_and_colons_within_it() {
+		let message = Message::parse(":server CAP * ACK :").unwrap();
+		assert_eq!(message.params, vec!["*", "ACK", ""]);
+		let message = Message::parse("PRIVMSG #channel :see http://example.com").unwrap();
+		assert_eq!(message.param(1), Some("see http://example.com"));
+	}
+
+	#[test]
+	fn a_server_host_prefix_is_its_own_nick() {
+		let message = Message::parse(":irc.example.com NOTICE * :hi").unwrap();
+		assert_eq!(message.nick(), Some("irc.example.com"));
+	}
+
+	#[test]
+	fn rejects_lines_without_a_command() {
+		assert_eq!(Message::parse(""), None);
+		assert_eq!(Message::parse(":prefix-only"), None);
+		assert_eq!(Message::parse("@tags-only"), None);
+	}
+}
@@ -0,0 +1,192 @@
+pub mod command;
+pub mod connection;
+pub mod history;
+pub mod message;
+
+use crate::database::{Migration, Migrations};
+use crate::system::{QuitOnError, System, SystemPlugin};
+use crate::system_tasks::irc::command::{CommandDispatcher, CommandRegistry};
+use crate::system_tasks::irc::connection::{IrcConnection, IrcConnectionHandle, IrcServerConfig};
+use crate::system_tasks::irc::history::{HistorySender, IrcHistory};
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::*;
+
+/// How long to wait before restarting a connection task that failed outright
+const CONNECTION_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Default, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct IrcConnections {
+	/// Server connections by name, the name is how they're addressed for sending
+	connections: BTreeMap<String, IrcServerConfig>,
+}
+
+/// The running IRC connections by name, registered in the `DashTypeMap`
+#[derive(Default, Debug)]
+pub struct IrcRegistry {
+	connections: DashMap<String, IrcConnectionHandle>,
+}
+
+impl IrcRegistry {
+	pub fn names(&self) -> Vec<String> {
+		let mut names: Vec<String> = self
+			.connections
+			.iter()
+			.map(|entry| entry.key().clone())
+			.collect();
+		names.sort();
+		names
+	}
+
+	pub fn get(&self, name: &str) -> Option<IrcConnectionHandle> {
+		self.connections.get(name).map(|handle| handle.clone())
+	}
+
+	/// Sends a message to a channel or nick on the named connection
+	pub fn send_message(&self, connection: &str, target: &str, text: &str) -> anyhow::Result<()> {
+		self.get(connection)
+			.ok_or_else(|| anyhow::anyhow!("unknown IRC connection: {}", connection))?
+			.send_message(target, text)
+	}
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+pub struct IRC {
+	enabled: bool,
+	#[serde(default)]
+	connections: IrcConnections,
+	/// Messages starting with this run a bot command, empty disables commands
+	#[serde(default = "default_command_prefix")]
+	command_prefix: String,
+}
+
+fn default_command_prefix() -> String {
+	"!".to_owned()
+}
+
+impl IRC {
+	pub fn new(enabled: bool) -> Self {
+		Self {
+			enabled,
+			connections: Default::default(),
+			command_prefix: default_command_prefix(),
+		}
+	}
+
+	/// Runs the connection, restarting it with a fresh handle in the registry if its task fails
+	async fn supervise(
+		name: String,
+		config: IrcServerConfig,
+		registry: Arc<IrcRegistry>,
+		dispatcher: Option<CommandDispatcher>,
+		history: HistorySender,
+		quit: broadcast::Sender<()>,
+	) {
+		let mut on_quit = quit.subscribe();
+		loop {
+			let (connection, handle) = IrcConnection::new(
+				name.clone(),
+				config.clone(),
+				dispatcher.clone(),
+				Some(history.clone()),
+			);
+			registry.connections.insert(name.clone(), handle);
+			match tokio::spawn(connection.run(quit.subscribe())).await {
+				Ok(Ok(())) => break,
+				Ok(Err(e)) => error!("IRC `{}` connection task failed: {:?}", name, e),
+				Err(e) => error!("IRC `{}` connection task panicked: {}", name, e),
+			}
+			tokio::select! {
+				_ = on_quit.recv() => break,
+				_ = tokio::time::sleep(CONNECTION_RESTART_DELAY) => (),
+			}
+		}
+		registry.connections.remove(&name);
+	}
+}
+
+#[typetag::serde]
+impl SystemPlugin for IRC {
+	fn spawn(&self, system: &System) -> Option<JoinHandle<anyhow::Result<()>>> {
+		if !self.enabled {
+			return None;
+		}
+		let db_pool = system.db_pool.clone();
+		let registry = system
+			.registered_data
+			.get_or_insert_with(IrcRegistry::default);
+		let dispatcher = if self.command_prefix.is_empty() {
+			None
+		} else {
+			Some(CommandDispatcher {
+				prefix: self.command_prefix.clone(),
+				commands: system
+					.registered_data
+					.get_or_insert_with(CommandRegistry::with_builtins),
+				data: system.registered_data.clone(),
+			})
+		};
+		let connections = self.connections.connections.clone();
+		let do_quit = system.quit.clone();
+		let allow_checksum_override = system.allow_checksum_override();
+		let handle = tokio::task::spawn(async move {
+			info!("IRC Handler task has launched");
+			MIGRATIONS
+				.migrate_up(&db_pool, allow_checksum_override)
+				.await
+				.quit_on_err(&do_quit)?;
+			let history = IrcHistory::spawn_writer(db_pool, &do_quit);
+			let mut supervisors = Vec::new();
+			for (name, config) in connections {
+				if !config.enabled {
+					info!("IRC connection `{}` is disabled", name);
+					continue;
+				}
+				// A bad connection is only logged so the others still start
+				if let Err(e) = config.validate() {
+					error!("IRC connection `{}` not started: {}", name, e);
+					continue;
+				}
+				supervisors.push(Self::supervise(
+					name,
+					config,
+					registry.clone(),
+					dispatcher.clone(),
+					history.clone(),
+					do_quit.clone(),
+				));
+			}
+			futures::future::join_all(supervisors).await;
+			Ok(())
+		});
+		Some(handle)
+	}
+}
+
+const MIGRATIONS: Migrations = Migrations::new(
+	"IRC",
+	&[Migration::new("Create irc_messages table")
+		.up(r#"
+			CREATE TABLE irc_messages (
+				id bigserial NOT NULL,
+				connection text NOT NULL,
+				channel text NOT NULL,
+				nick text NOT NULL,
+				message text NOT NULL,
+				sent_at timestamp with time zone NOT NULL,
+				CONSTRAINT irc_messages_pkey PRIMARY KEY (id)
+			) WITH ( OIDS=FALSE );
+			CREATE INDEX irc_messages_channel_idx ON irc_messages (connection, lower(channel), sent_at);
+			"#)
+		.down(
+			r#"
+			DROP TABLE irc_messages;
+			"#,
+		)],
+);
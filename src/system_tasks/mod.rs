@@ -1,4 +1,4 @@
+pub mod backup;
 pub mod daemon;
 pub mod irc;
 pub mod tui;
-//pub mod web_ui;
@@ -0,0 +1,56 @@
+//! System clipboard access, compiled in with the `clipboard` feature
+
+/// Clipboard commands tried in order, each reading the text to copy from stdin
+#[cfg(all(feature = "clipboard", target_os = "macos"))]
+const COPY_COMMANDS: &[&[&str]] = &[&["pbcopy"]];
+#[cfg(all(feature = "clipboard", windows))]
+const COPY_COMMANDS: &[&[&str]] = &[&["clip"]];
+#[cfg(all(feature = "clipboard", not(any(target_os = "macos", windows))))]
+const COPY_COMMANDS: &[&[&str]] = &[
+	&["wl-copy"],
+	&["xclip", "-selection", "clipboard"],
+	&["xsel", "--clipboard", "--input"],
+];
+
+/// Copies `text` to the system clipboard, the error is a message for the user when no clipboard
+/// is available, such as when headless or over SSH
+#[cfg(feature = "clipboard")]
+pub fn copy(text: &str) -> Result<(), String> {
+	use std::io::Write;
+	use std::process::{Command, Stdio};
+	let mut failures = Vec::new();
+	for command in COPY_COMMANDS {
+		let child = Command::new(command[0])
+			.args(&command[1..])
+			.stdin(Stdio::piped())
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn();
+		let mut child = match child {
+			Ok(child) => child,
+			Err(e) => {
+				failures.push(format!("{}: {}", command[0], e));
+				continue;
+			}
+		};
+		let written = child
+			.stdin
+			.take()
+			.expect("stdin is piped")
+			.write_all(text.as_bytes());
+		match (written, child.wait()) {
+			(Ok(()), Ok(status)) if status.success() => return Ok(()),
+			(Err(e), _) | (_, Err(e)) => failures.push(format!("{}: {}", command[0], e)),
+			(Ok(()), Ok(status)) => failures.push(format!("{}: {}", command[0], status)),
+		}
+	}
+	Err(format!(
+		"No clipboard is available: {}",
+		failures.join(", ")
+	))
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn copy(_text: &str) -> Result<(), String> {
+	Err("Clipboard support is not compiled in, build with the `clipboard` feature".to_owned())
+}
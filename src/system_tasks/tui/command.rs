@@ -0,0 +1,214 @@
+//! The TUI command line, results are logged so they show up in the log view
+
+use crate::dash_type_map::DashTypeMap;
+use crate::database::DbPool;
+use crate::logger::conditional_map::ConditionalMap;
+use crate::logger::switchable_encoder::DEFAULT_SWITCH_ID;
+use crate::system::SystemStatus;
+use sqlx::prelude::*;
+use sqlx::{Column, TypeInfo, ValueRef};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast;
+use tracing::log::LevelFilter;
+use tracing::*;
+
+/// Every command name, for help and completion
+pub const COMMANDS: &[&str] = &["help", "json", "level", "quit", "sql", "tasks"];
+
+/// The most rows the `sql` command prints
+const SQL_MAX_ROWS: usize = 20;
+
+pub struct CommandContext {
+	pub data: Arc<DashTypeMap>,
+	pub quit: broadcast::Sender<()>,
+	/// The runtime the system runs on, for commands that have to await
+	pub runtime: Handle,
+	/// Whether the `sql` command may run arbitrary queries
+	pub allow_sql: bool,
+}
+
+impl CommandContext {
+	/// Runs a command line, failures are logged rather than returned
+	pub fn run(&self, line: &str) {
+		let line = line.trim();
+		if line.is_empty() {
+			return;
+		}
+		info!(target: "overbot::tui::command", "> {}", line);
+		let (command, args) = match line.find(char::is_whitespace) {
+			Some(idx) => (&line[..idx], line[idx..].trim()),
+			None => (line, ""),
+		};
+		match command {
+			"help" => info!(
+				target: "overbot::tui::command",
+				"Commands: json on|off [<id>], level [<target>] <level>, quit, sql <query>, tasks"
+			),
+			"json" => self.json(args),
+			"level" => self.level(args),
+			"quit" => {
+				info!(target: "overbot::tui::command", "Quit requested from the TUI");
+				let _ = self.quit.send(());
+			}
+			"sql" => self.sql(args),
+			"tasks" => self.tasks(),
+			_ => warn!(
+				target: "overbot::tui::command",
+				"Unknown command `{}`, try `help`", command
+			),
+		}
+	}
+
+	fn level(&self, args: &str) {
+		let words: Vec<&str> = args.split_whitespace().collect();
+		let (target, level) = match words.as_slice() {
+			[level] => ("", *level),
+			[target, level] => (*target, *level),
+			_ => {
+				warn!(target: "overbot::tui::command", "Usage: level [<target>] <level>");
+				return;
+			}
+		};
+		match LevelFilter::from_str(level) {
+			Ok(level) => {
+				ConditionalMap::set_level(target, level);
+				info!(
+					target: "overbot::tui::command",
+					"Log level of `{}` set to {}", target, level
+				);
+			}
+			Err(_) => warn!(target: "overbot::tui::command", "Unknown log level `{}`", level),
+		}
+	}
+
+	/// Switches a `switchable` log encoder between its pattern and json encoders
+	fn json(&self, args: &str) {
+		let words: Vec<&str> = args.split_whitespace().collect();
+		let (use_json, id) = match words.as_slice() {
+			[switch] => (*switch, DEFAULT_SWITCH_ID),
+			[switch, id] => (*switch, *id),
+			_ => ("", ""),
+		};
+		let use_json = match use_json {
+			"on" => true,
+			"off" => false,
+			_ => {
+				warn!(target: "overbot::tui::command", "Usage: json on|off [<id>]");
+				return;
+			}
+		};
+		match ConditionalMap::get_by_id(id) {
+			Some(switch) => {
+				switch.store(use_json, Ordering::Relaxed);
+				info!(
+					target: "overbot::tui::command",
+					"Encoder `{}` now logs {}", id, if use_json { "json" } else { "patterns" }
+				);
+			}
+			None => warn!(
+				target: "overbot::tui::command",
+				"No switchable log encoder with the id `{}`", id
+			),
+		}
+	}
+
+	fn tasks(&self) {
+		match self.data.with::<Arc<SystemStatus>, _, _>(Clone::clone) {
+			Ok(status) => {
+				for (name, state) in status.tasks() {
+					info!(target: "overbot::tui::command", "{}: {:?}", name, state);
+				}
+			}
+			Err(_) => warn!(target: "overbot::tui::command", "System status is not available"),
+		}
+	}
+
+	fn sql(&self, query: &str) {
+		if !self.allow_sql {
+			warn!(
+				target: "overbot::tui::command",
+				"The `sql` command is disabled, enable `tui_sql_command` in the configuration"
+			);
+			return;
+		}
+		let db_pool = match self.data.with::<DbPool, _, _>(Clone::clone) {
+			Ok(db_pool) => db_pool,
+			Err(_) => {
+				warn!(target: "overbot::tui::command", "The database is not available");
+				return;
+			}
+		};
+		let query = query.to_owned();
+		self.runtime.spawn(async move {
+			// A raw simple query so values come back as text, the same as the web table view
+			let rows = match db_pool.acquire().await {
+				Ok(mut conn) => conn.fetch_all(query.as_str()).await,
+				Err(e) => Err(e),
+			};
+			match rows {
+				Ok(rows) => {
+					for row in rows.iter().take(SQL_MAX_ROWS) {
+						let values: Vec<String> = row
+							.columns()
+							.iter()
+							.map(|column| {
+								let raw = match row.try_get_raw(column.ordinal()) {
+									Ok(raw) => raw,
+									Err(_) => return "{unreadable}".to_owned(),
+								};
+								if raw.is_null() {
+									"{null}".to_owned()
+								} else {
+									crate::web::decode_text_value(raw, column.type_info().name())
+										.unwrap_or_else(|| "{unsupported-type}".to_owned())
+								}
+							})
+							.collect();
+						info!(target: "overbot::tui::command", "{}", values.join("\t"));
+					}
+					info!(
+						target: "overbot::tui::command",
+						"{} rows{}",
+						rows.len(),
+						if rows.len() > SQL_MAX_ROWS {
+							format!(", showing the first {}", SQL_MAX_ROWS)
+						} else {
+							String::new()
+						}
+					);
+				}
+				Err(e) => error!(target: "overbot::tui::command", "Query failed: {}", e),
+			}
+		});
+	}
+}
+
+/// Completes the command name being typed, to the longest prefix shared by every candidate
+pub fn complete(line: &str) -> Option<String> {
+	if line.contains(char::is_whitespace) {
+		return None;
+	}
+	let candidates: Vec<&str> = COMMANDS
+		.iter()
+		.copied()
+		.filter(|command| command.starts_with(line))
+		.collect();
+	match candidates.as_slice() {
+		[] => None,
+		[command] => Some(format!("{} ", command)),
+		[first, rest @ ..] => {
+			let shared = rest.iter().fold(first.len(), |shared, command| {
+				first
+					.bytes()
+					.zip(command.bytes())
+					.take(shared)
+					.take_while(|(a, b)| a == b)
+					.count()
+			});
+			Some(first[..shared].to_owned()).filter(|prefix| prefix.len() > line.len())
+		}
+	}
+}
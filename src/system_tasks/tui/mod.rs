@@ -1,11 +1,16 @@
+mod clipboard;
+mod command;
 mod views;
 
 use crate::dash_type_map::DashTypeMap;
+use crate::logger::cache_appender::Cache;
 use crate::logger::conditional_map::ConditionalMap;
 use crate::system::{System, SystemPlugin};
 use anyhow::Context;
+use command::CommandContext;
+use crossbeam::channel::RecvTimeoutError;
 use cursive::align::HAlign;
-use cursive::event::Key;
+use cursive::event::{EventResult, Key};
 use cursive::menu::MenuTree;
 use cursive::view::*;
 use cursive::views::*;
@@ -13,21 +18,42 @@ use cursive::{Cursive, CursiveRunnable};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
 use tokio::task::{spawn_blocking, JoinHandle};
 use tracing::{log::Level, *};
 use views::*;
 
+pub use views::LogViewConfig;
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Default, serde::Deserialize, serde::Serialize)]
 pub struct TUI {
 	enabled: bool,
+	#[serde(default)]
+	log_view: LogViewConfig,
+	/// Allows the `sql` command to run arbitrary queries from the command line
+	#[serde(default)]
+	sql_command: bool,
 }
 
 impl TUI {
 	pub fn new(enabled: bool) -> Self {
-		Self { enabled }
+		Self {
+			enabled,
+			log_view: LogViewConfig::default(),
+			sql_command: false,
+		}
+	}
+
+	pub fn with_log_view(mut self, log_view: LogViewConfig) -> Self {
+		self.log_view = log_view;
+		self
+	}
+
+	pub fn with_sql_command(mut self, sql_command: bool) -> Self {
+		self.sql_command = sql_command;
+		self
 	}
 }
 
@@ -37,20 +63,34 @@ impl SystemPlugin for TUI {
 		let registered_data = system.registered_data.clone();
 		let quit = system.quit.clone();
 		let on_quit = system.quit.subscribe();
+		let log_view_config = self.log_view.clone();
+		let wakeup = spawn_wakeup(&system.quit);
+		let command_context = CommandContext {
+			data: system.registered_data.clone(),
+			quit: system.quit.clone(),
+			runtime: tokio::runtime::Handle::current(),
+			allow_sql: self.sql_command,
+		};
 		let handle = spawn_blocking(move || {
 			info!("TUI is starting up");
 			let mut siv = cursive::default();
 			{
 				siv.add_global_callback('l', |_siv| info!("Logging a loggy log by 'l'"));
 			}
-			setup_ui(&mut siv, registered_data, quit.clone());
+			setup_ui(
+				&mut siv,
+				registered_data,
+				quit.clone(),
+				&log_view_config,
+				command_context,
+			);
 			info!("TUI started, disabling the loggers conditional `console` output while it draws");
 			// Disable the logger while this runs
-			ConditionalMap::get_or_create_by_id("console".to_owned(), false)
+			ConditionalMap::get_or_create_by_id(CONSOLE_SWITCH_ID.to_owned(), false)
 				.store(false, Ordering::SeqCst);
-			tui_run_loop(&mut siv, quit, on_quit);
+			tui_run_loop(&mut siv, quit, on_quit, wakeup);
 			// And re-enable logger after
-			ConditionalMap::get_by_id("console").context("unable to lookup `console` in logger conditional map when it was created earlier")?
+			ConditionalMap::get_by_id(CONSOLE_SWITCH_ID).context("unable to lookup `console` in logger conditional map when it was created earlier")?
 				.store(true, Ordering::SeqCst);
 			Ok(())
 		});
@@ -58,7 +98,11 @@ impl SystemPlugin for TUI {
 	}
 }
 
+/// The conditional switch of the console appender, kept off while the TUI draws
+const CONSOLE_SWITCH_ID: &str = "console";
 const LOG_VIEW_HIDER: &str = "log_view_hider";
+const STATUS_VIEW_HIDER: &str = "status_view_hider";
+const COMMAND_INPUT: &str = "command_input";
 
 fn toggle_named_hideable<V: View>(siv: &mut Cursive, name: &str) {
 	if let Some(mut view) = siv.find_name::<HideableView<V>>(name) {
@@ -72,10 +116,113 @@ fn toggle_named_hideable<V: View>(siv: &mut Cursive, name: &str) {
 	}
 }
 
+fn with_log_view<R>(siv: &mut Cursive, fun: impl FnOnce(&mut LogView) -> R) -> Option<R> {
+	let mut view = siv.find_name::<HideableView<Panel<ResizedView<LogView>>>>(LOG_VIEW_HIDER)?;
+	Some(fun(view.get_inner_mut().get_inner_mut().get_inner_mut()))
+}
+
+/// Lists every conditional appender and encoder switch, selecting one toggles it
+fn open_log_switches(siv: &mut Cursive) {
+	let mut selector = SelectView::<String>::new().h_align(HAlign::Left);
+	for id in ConditionalMap::all_ids() {
+		if id == CONSOLE_SWITCH_ID {
+			continue;
+		}
+		let enabled =
+			ConditionalMap::get_by_id(&id).map_or(false, |switch| switch.load(Ordering::SeqCst));
+		selector.add_item(format!("[{}] {}", if enabled { "x" } else { " " }, id), id);
+	}
+	if selector.is_empty() {
+		siv.add_layer(Dialog::info("No conditional log switches are registered"));
+		return;
+	}
+	selector.set_on_submit(|siv, id: &str| {
+		if let Some(switch) = ConditionalMap::get_by_id(id) {
+			switch.fetch_xor(true, Ordering::SeqCst);
+		}
+		siv.pop_layer();
+		open_log_switches(siv);
+	});
+	siv.add_layer(
+		Dialog::around(selector)
+			.title("Log Switches")
+			.button("Enable All", |siv| set_all_log_switches(siv, true))
+			.button("Disable All", |siv| set_all_log_switches(siv, false))
+			.dismiss_button("Close"),
+	);
+}
+
+fn set_all_log_switches(siv: &mut Cursive, enabled: bool) {
+	ConditionalMap::set_all(enabled);
+	// The console appender would draw over the TUI
+	if let Some(console) = ConditionalMap::get_by_id(CONSOLE_SWITCH_ID) {
+		console.store(false, Ordering::SeqCst);
+	}
+	siv.pop_layer();
+	open_log_switches(siv);
+}
+
+const LOG_FILTER_EDIT: &str = "log_filter_edit";
+
+/// Prompts for a log view filter, `/regex/` for a regex, an empty query clears it
+fn open_log_filter(siv: &mut Cursive) {
+	let current = with_log_view(siv, |log_view| {
+		log_view
+			.filter()
+			.map(|filter| filter.query().to_owned())
+			.unwrap_or_default()
+	});
+	let current = match current {
+		Some(current) => current,
+		None => {
+			warn!(
+				"Attempted to filter log view `{}` but it was not found",
+				LOG_VIEW_HIDER
+			);
+			return;
+		}
+	};
+	siv.add_layer(
+		Dialog::around(
+			EditView::new()
+				.content(current)
+				.on_submit(apply_log_filter)
+				.with_name(LOG_FILTER_EDIT)
+				.min_width(30),
+		)
+		.title("Filter Log (/regex/ for a regex)")
+		.button("Filter", |siv| {
+			let query = siv
+				.call_on_name(LOG_FILTER_EDIT, |edit: &mut EditView| edit.get_content())
+				.unwrap_or_default();
+			apply_log_filter(siv, &query);
+		})
+		.dismiss_button("Cancel"),
+	);
+}
+
+fn apply_log_filter(siv: &mut Cursive, query: &str) {
+	let filter = if query.is_empty() {
+		None
+	} else {
+		match LogFilter::new(query) {
+			Ok(filter) => Some(filter),
+			Err(e) => {
+				siv.add_layer(Dialog::info(format!("Invalid filter: {}", e)));
+				return;
+			}
+		}
+	};
+	siv.pop_layer();
+	with_log_view(siv, |log_view| log_view.set_filter(filter));
+}
+
 fn setup_ui(
 	siv: &mut CursiveRunnable,
-	_registered_data: Arc<DashTypeMap>,
+	registered_data: Arc<DashTypeMap>,
 	quit: broadcast::Sender<()>,
+	log_view_config: &LogViewConfig,
+	command_context: CommandContext,
 ) {
 	// This is buggy as is doesn't appear "over" other things when focused... keep false
 	siv.set_autohide_menu(false);
@@ -98,9 +245,16 @@ fn setup_ui(
 			MenuTree::new()
 				.subtree(
 					"Toggle Visbility",
-					MenuTree::new().leaf("Toggle Log", |siv| {
-						toggle_named_hideable::<Panel<ResizedView<LogView>>>(siv, LOG_VIEW_HIDER)
-					}),
+					MenuTree::new()
+						.leaf("Toggle Status", |siv| {
+							toggle_named_hideable::<Panel<StatusView>>(siv, STATUS_VIEW_HIDER)
+						})
+						.leaf("Toggle Log", |siv| {
+							toggle_named_hideable::<Panel<ResizedView<LogView>>>(
+								siv,
+								LOG_VIEW_HIDER,
+							)
+						}),
 				)
 				.leaf("Set Max Log Level", |siv| {
 					if let Some(mut log_view) =
@@ -152,45 +306,127 @@ fn setup_ui(
 							LOG_VIEW_HIDER
 						);
 					}
-				}),
+				})
+				.leaf("Log Switches", open_log_switches),
 		)
 		.add_subtree(
 			"Help",
 			MenuTree::new().leaf("About", move |siv| {
-				siv.add_layer(Dialog::info(&format!(
-					"Cursive v{}\n{}",
-					env!("CARGO_PKG_VERSION"),
-					env!("CARGO_PKG_DESCRIPTION")
-				)))
+				siv.add_layer(Dialog::info(crate::build_info::build_info()))
 			}),
 		);
-	siv.add_global_callback(Key::Esc, |siv| siv.select_menubar());
+	siv.add_global_callback('/', open_log_filter);
+	// Esc clears an active log filter first before it goes to the menubar
+	siv.add_global_callback(Key::Esc, |siv| {
+		let cleared = with_log_view(siv, |log_view| {
+			log_view.filter().is_some() && {
+				log_view.set_filter(None);
+				true
+			}
+		});
+		if cleared != Some(true) {
+			siv.select_menubar()
+		}
+	});
 
 	siv.add_fullscreen_layer(
-		LinearLayout::vertical().child(
-			HideableView::new(
-				Panel::new(
-					LogView::default().resized(SizeConstraint::Full, SizeConstraint::Fixed(6)),
+		LinearLayout::vertical()
+			.child(
+				HideableView::new(Panel::new(StatusView::new(registered_data)).title("Status"))
+					.with_name(STATUS_VIEW_HIDER),
+			)
+			.child(
+				HideableView::new(
+					Panel::new(
+						LogView::new(log_view_config).resized(
+							SizeConstraint::Full,
+							log_view_config
+								.max_height
+								.map_or(SizeConstraint::Full, SizeConstraint::Fixed),
+						),
+					)
+					.title("System Log"),
 				)
-				.title("System Log"),
+				.with_name(LOG_VIEW_HIDER),
 			)
-			.with_name(LOG_VIEW_HIDER),
-		),
+			.child(
+				Panel::new(
+					OnEventView::new(
+						EditView::new()
+							.on_submit(move |siv, line| {
+								command_context.run(line);
+								siv.call_on_name(COMMAND_INPUT, |edit: &mut EditView| {
+									edit.set_content("")
+								});
+							})
+							.with_name(COMMAND_INPUT),
+					)
+					.on_pre_event_inner(Key::Tab, |edit, _event| {
+						let mut edit = edit.get_mut();
+						// Tab would move the focus otherwise, so it's consumed either way
+						let callback = command::complete(&edit.get_content())
+							.map(|completed| edit.set_content(completed));
+						Some(EventResult::Consumed(callback))
+					}),
+				)
+				.title("Command (Tab completes)"),
+			),
 	);
 }
 
+/// Cursive can only be polled for input, this is how long it waits on a wakeup between polls, the
+/// same as cursive's own run loop
+const INPUT_POLL: Duration = Duration::from_millis(30);
+/// Redraw at least this often, for things without a wakeup like the status uptime
+const FALLBACK_REFRESH: Duration = Duration::from_secs(1);
+
+/// Wakes the TUI thread on new log view records and on quit, ends on quit
+fn spawn_wakeup(quit: &broadcast::Sender<()>) -> crossbeam::channel::Receiver<()> {
+	// A single pending wakeup is enough, they coalesce into the next redraw
+	let (wake, wakeup) = crossbeam::channel::bounded(1);
+	let mut on_quit = quit.subscribe();
+	// Subscribing only works once the appender made the cache, otherwise only the fallback redraws
+	let mut records = Cache::subscribe(LOG_VIEW_CACHE);
+	tokio::spawn(async move {
+		loop {
+			let record = async {
+				match &mut records {
+					Some(records) => records.recv().await,
+					None => futures::future::pending().await,
+				}
+			};
+			tokio::select! {
+				_ = on_quit.recv() => {
+					let _ = wake.try_send(());
+					break;
+				}
+				record = record => match record {
+					Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {
+						let _ = wake.try_send(());
+					}
+					Err(broadcast::error::RecvError::Closed) => records = None,
+				},
+			}
+		}
+	});
+	wakeup
+}
+
 #[tracing::instrument(
 	name = "TUI RunLoop",
 	target = "overbot::system",
-	skip(siv, quit, on_quit)
+	skip(siv, quit, on_quit, wakeup)
 )]
 fn tui_run_loop(
 	siv: &mut CursiveRunnable,
 	quit: broadcast::Sender<()>,
 	mut on_quit: broadcast::Receiver<()>,
+	wakeup: crossbeam::channel::Receiver<()>,
 ) {
 	let mut runner = siv.runner();
 	runner.refresh();
+	let mut last_refresh = Instant::now();
+	let mut woken = false;
 
 	// TODO: Read the primary event processor here
 	while runner.is_running() {
@@ -210,11 +446,21 @@ fn tui_run_loop(
 				break;
 			}
 		}
-		runner.refresh();
-		// Unfortunately we have to poll changes in cursive, we can't just `await` it..  >.<
-		// Which is annoying because it is stdin, so absolutely could await it...
-		// But, we need to poll, so... don't busy wait, sleep a bit and try again...
-		sleep(Duration::from_millis(100));
+		if woken || processed > 0 || last_refresh.elapsed() >= FALLBACK_REFRESH {
+			runner.refresh();
+			last_refresh = Instant::now();
+		}
+		// Unfortunately we have to poll input in cursive, we can't just `await` it..  >.<
+		// So wait on a wakeup for new logs or quit, but only as long as until the next input poll
+		woken = match wakeup.recv_timeout(INPUT_POLL) {
+			Ok(()) => true,
+			Err(RecvTimeoutError::Timeout) => false,
+			Err(RecvTimeoutError::Disconnected) => {
+				// The waker is gone after quit, don't busy wait until the runner notices
+				sleep(INPUT_POLL);
+				false
+			}
+		};
 	}
 
 	// TUI closed, let's go ahead and post a quit regardless of if it was (should) already sent
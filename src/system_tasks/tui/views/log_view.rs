@@ -1,30 +1,240 @@
 use crate::logger::cache_appender::{Cache, CachedLogRecord};
+use crate::system_tasks::tui::clipboard;
+use cursive::direction::Direction;
+use cursive::event::{Event, EventResult, Key, MouseEvent};
+use cursive::views::Dialog;
 use cursive::{theme, Printer, Vec2, View};
+use regex::{Regex, RegexBuilder};
 use std::collections::VecDeque;
 use std::sync::{Arc, RwLock};
 use tracing::log::Level;
+use tracing::warn;
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct LogViewConfig {
+	/// Color of each level, a cursive color such as `"red"`, `"light black"`, or `"#rrggbb"`.
+	/// **(default: `"red"`)**
+	pub error_color: String,
+	/// **(default: `"yellow"`)**
+	pub warn_color: String,
+	/// **(default: `"light black"`)**
+	pub info_color: String,
+	/// **(default: `"green"`)**
+	pub debug_color: String,
+	/// **(default: `"blue"`)**
+	pub trace_color: String,
+	/// The widest the log view asks to be, `None` to use all the room it is given.
+	/// **(default: `Some(70)`)**
+	pub max_width: Option<usize>,
+	/// The height of the log view, `None` to use all the room it is given. **(default: `Some(6)`)**
+	pub max_height: Option<usize>,
+}
+
+impl Default for LogViewConfig {
+	fn default() -> Self {
+		Self {
+			error_color: "red".to_owned(),
+			warn_color: "yellow".to_owned(),
+			info_color: "light black".to_owned(),
+			debug_color: "green".to_owned(),
+			trace_color: "blue".to_owned(),
+			max_width: Some(70),
+			max_height: Some(6),
+		}
+	}
+}
+
+impl LogViewConfig {
+	/// The colors by level, unparseable ones fall back to the default with a warning
+	fn colors(&self) -> [theme::Color; 5] {
+		let defaults = Self::default();
+		let parse = |color: &str, default: &str| {
+			theme::Color::parse(color).unwrap_or_else(|| {
+				warn!(
+					"Unknown TUI log color `{}`, using `{}` instead",
+					color, default
+				);
+				theme::Color::parse(default).expect("default colors are valid")
+			})
+		};
+		[
+			parse(&self.error_color, &defaults.error_color),
+			parse(&self.warn_color, &defaults.warn_color),
+			parse(&self.info_color, &defaults.info_color),
+			parse(&self.debug_color, &defaults.debug_color),
+			parse(&self.trace_color, &defaults.trace_color),
+		]
+	}
+}
+
+/// The log cache appender the log view shows
+pub const LOG_VIEW_CACHE: &str = "tui_log_view";
+
+/// Lines scrolled per mouse wheel notch
+const WHEEL_SCROLL_LINES: usize = 3;
+
+/// A case-insensitive message or target filter, a query wrapped in `/`s is a regex, anything else
+/// is matched literally
+pub struct LogFilter {
+	query: String,
+	regex: Regex,
+}
+
+impl LogFilter {
+	pub fn new(query: &str) -> Result<Self, regex::Error> {
+		let pattern = match query.strip_prefix('/').and_then(|q| q.strip_suffix('/')) {
+			Some(pattern) => pattern.to_owned(),
+			None => regex::escape(query),
+		};
+		let regex = RegexBuilder::new(&pattern).case_insensitive(true).build()?;
+		Ok(Self {
+			query: query.to_owned(),
+			regex,
+		})
+	}
+
+	pub fn query(&self) -> &str {
+		&self.query
+	}
+}
 
 pub struct LogView {
 	logs: Arc<RwLock<VecDeque<CachedLogRecord>>>,
 	max_level: Level,
+	/// Indexed by `Level as usize - 1`
+	colors: [theme::Color; 5],
+	max_size: (Option<usize>, Option<usize>),
+	filter: Option<LogFilter>,
+	/// How many matching records back from the newest the bottom line shows, 0 follows the tail
+	scroll: usize,
+	/// The selected record as matching records back from the newest, kept on the same record as
+	/// new ones arrive
+	selected: Option<usize>,
+	/// Height from the last layout, the size of a page
+	height: usize,
+	/// Matching records as of the last layout, to hold a scrolled up view still as records arrive
+	seen: usize,
 }
 
 impl LogView {
+	pub fn new(config: &LogViewConfig) -> Self {
+		LogView {
+			colors: config.colors(),
+			max_size: (config.max_width, config.max_height),
+			..Self::default()
+		}
+	}
+
 	pub fn max_level(&self) -> Level {
 		self.max_level
 	}
 
 	pub fn set_max_level(&mut self, max_level: Level) {
 		self.max_level = max_level;
+		self.selected = None;
+		self.seen = self.matching_count();
+		self.scroll_to(self.scroll);
+	}
+
+	pub fn filter(&self) -> Option<&LogFilter> {
+		self.filter.as_ref()
+	}
+
+	pub fn set_filter(&mut self, filter: Option<LogFilter>) {
+		self.filter = filter;
+		self.scroll = 0;
+		self.selected = None;
+		self.seen = self.matching_count();
+	}
+
+	fn matches(&self, record: &CachedLogRecord) -> bool {
+		record.level() <= self.max_level
+			&& self.filter.as_ref().map_or(true, |filter| {
+				filter.regex.is_match(record.msg()) || filter.regex.is_match(record.target())
+			})
+	}
+
+	fn matching_count(&self) -> usize {
+		let logs = self.logs.read().expect("poisoned lock");
+		logs.iter().filter(|record| self.matches(record)).count()
+	}
+
+	/// Scrolls to `scroll` records back from the tail, clamped so the oldest record stays at the top
+	fn scroll_to(&mut self, scroll: usize) {
+		let max_scroll = self.matching_count().saturating_sub(self.height);
+		self.scroll = scroll.min(max_scroll);
+	}
+
+	fn select(&mut self, selected: usize) {
+		let selected = selected.min(self.matching_count().saturating_sub(1));
+		self.selected = Some(selected);
+		// Keep the selection on screen
+		if selected < self.scroll {
+			self.scroll = selected;
+		} else if selected >= self.scroll + self.height {
+			self.scroll_to(selected + 1 - self.height.max(1));
+		}
+	}
+
+	fn select_older(&mut self) {
+		match self.selected {
+			Some(selected) => self.select(selected.saturating_add(1)),
+			None => self.select(self.scroll),
+		}
+	}
+
+	fn select_newer(&mut self) {
+		match self.selected {
+			Some(selected) => self.select(selected.saturating_sub(1)),
+			None => self.select(self.scroll),
+		}
+	}
+
+	fn selected_msg(&self) -> Option<String> {
+		let selected = self.selected?;
+		let logs = self.logs.read().expect("poisoned lock");
+		logs.iter()
+			.rev()
+			.filter(|record| self.matches(record))
+			.nth(selected)
+			.map(|record| record.msg().to_owned())
+	}
+
+	fn copy_selected(&self) -> EventResult {
+		let status = match self.selected_msg() {
+			None => "No log line is selected".to_owned(),
+			Some(msg) => match clipboard::copy(&msg) {
+				Ok(()) => return EventResult::Consumed(None),
+				Err(status) => status,
+			},
+		};
+		EventResult::with_cb(move |siv| siv.add_layer(Dialog::info(status.clone())))
+	}
+
+	fn scroll_up(&mut self, lines: usize) {
+		self.scroll_to(self.scroll.saturating_add(lines));
+	}
+
+	fn scroll_down(&mut self, lines: usize) {
+		self.scroll_to(self.scroll.saturating_sub(lines));
 	}
 }
 
 impl Default for LogView {
 	fn default() -> Self {
-		let logs = Cache::get_or_create("tui_log_view".to_owned());
+		let logs = Cache::get_or_create(LOG_VIEW_CACHE.to_owned());
+		let config = LogViewConfig::default();
 		LogView {
 			logs,
 			max_level: Level::Info,
+			colors: config.colors(),
+			max_size: (config.max_width, config.max_height),
+			filter: None,
+			scroll: 0,
+			selected: None,
+			height: 0,
+			seen: 0,
 		}
 	}
 }
@@ -39,24 +249,105 @@ impl View for LogView {
 		for (offset, record) in logs
 			.iter()
 			.rev()
-			.filter(|record| record.level() <= self.max_level)
+			.filter(|record| self.matches(record))
+			.skip(self.scroll)
 			.take(printer.size.y)
 			.enumerate()
 		{
-			let color = match record.level() {
-				Level::Error => theme::BaseColor::Red.dark(),
-				Level::Warn => theme::BaseColor::Yellow.dark(),
-				Level::Info => theme::BaseColor::Black.light(),
-				Level::Debug => theme::BaseColor::Green.dark(),
-				Level::Trace => theme::BaseColor::Blue.dark(),
-			};
-			printer.with_color(color.into(), |printer| {
-				printer.print((0, printer.size.y - offset - 1), record.msg());
+			let color = self.colors[record.level() as usize - 1];
+			let y = printer.size.y - offset - 1;
+			if self.selected == Some(self.scroll + offset) {
+				printer.with_color(theme::ColorStyle::highlight_inactive(), |printer| {
+					printer.print_hline((0, y), printer.size.x, " ");
+					printer.print((0, y), record.msg());
+				});
+			} else {
+				printer.with_color(color.into(), |printer| {
+					printer.print((0, y), record.msg());
+				});
+			}
+			if let Some(filter) = &self.filter {
+				for found in filter.regex.find_iter(record.msg()) {
+					// Columns, not bytes, the same as `print` lays the message out
+					let x = record.msg()[..found.start()].chars().count();
+					printer.with_color(theme::ColorStyle::highlight(), |printer| {
+						printer.print((x, y), found.as_str());
+					});
+				}
+			}
+		}
+
+		if let Some(filter) = &self.filter {
+			let label = format!("[/{}]", filter.query());
+			printer.with_color(theme::ColorStyle::highlight_inactive(), |printer| {
+				printer.print((0, 0), &label);
+			});
+		}
+		if self.scroll > 0 {
+			let total = logs.iter().filter(|record| self.matches(record)).count();
+			let indicator = format!(
+				"[{}-{}/{}]",
+				total.saturating_sub(self.scroll + printer.size.y) + 1,
+				total - self.scroll.min(total),
+				total
+			);
+			let x = printer.size.x.saturating_sub(indicator.len());
+			printer.with_color(theme::ColorStyle::highlight(), |printer| {
+				printer.print((x, 0), &indicator);
 			});
 		}
 	}
 
+	fn layout(&mut self, size: Vec2) {
+		self.height = size.y;
+		let total = self.matching_count();
+		let arrived = total.saturating_sub(self.seen);
+		if self.scroll > 0 {
+			self.scroll += arrived;
+		}
+		self.selected = self
+			.selected
+			.map(|selected| selected + arrived)
+			.filter(|selected| *selected < total);
+		self.seen = total;
+		self.scroll_to(self.scroll);
+	}
+
 	fn required_size(&mut self, constraint: Vec2) -> Vec2 {
-		Vec2::new(constraint.x.min(70), constraint.y.min(4))
+		let (max_width, max_height) = self.max_size;
+		Vec2::new(
+			max_width.map_or(constraint.x, |max| constraint.x.min(max)),
+			max_height.map_or(constraint.y, |max| constraint.y.min(max)),
+		)
+	}
+
+	fn take_focus(&mut self, _source: Direction) -> bool {
+		true
+	}
+
+	fn on_event(&mut self, event: Event) -> EventResult {
+		let page = self.height.max(1);
+		match event {
+			Event::Key(Key::Up) => self.select_older(),
+			Event::Key(Key::Down) => self.select_newer(),
+			Event::Key(Key::PageUp) => self.scroll_up(page),
+			Event::Key(Key::PageDown) => self.scroll_down(page),
+			Event::Key(Key::Home) => self.scroll_up(usize::MAX),
+			Event::Key(Key::End) => {
+				self.scroll = 0;
+				self.selected = None;
+			}
+			Event::Char('y') => return self.copy_selected(),
+			Event::Mouse {
+				event: MouseEvent::WheelUp,
+				..
+			} => self.scroll_up(WHEEL_SCROLL_LINES),
+			Event::Mouse {
+				event: MouseEvent::WheelDown,
+				..
+			} => self.scroll_down(WHEEL_SCROLL_LINES),
+			_ => return EventResult::Ignored,
+		}
+		EventResult::Consumed(None)
 	}
 }
@@ -1,3 +1,5 @@
 pub mod log_view;
+pub mod status_view;
 
-pub use log_view::LogView;
+pub use log_view::{LogFilter, LogView, LogViewConfig, LOG_VIEW_CACHE};
+pub use status_view::StatusView;
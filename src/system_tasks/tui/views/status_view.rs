@@ -0,0 +1,83 @@
+use crate::dash_type_map::DashTypeMap;
+use crate::database::DbPool;
+use crate::system::{SystemStatus, TaskState};
+use cursive::{theme, Printer, Vec2, View};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Live system state, read from the `DashTypeMap` on every draw
+pub struct StatusView {
+	data: Arc<DashTypeMap>,
+}
+
+impl StatusView {
+	pub fn new(data: Arc<DashTypeMap>) -> Self {
+		Self { data }
+	}
+
+	fn lines(&self) -> Vec<(theme::Color, String)> {
+		let normal = theme::BaseColor::Black.light();
+		let mut lines = Vec::new();
+		match self.data.with::<Arc<SystemStatus>, _, _>(Clone::clone) {
+			Ok(status) => {
+				lines.push((
+					normal,
+					format!(
+						"Run mode: {:?}    Uptime: {}",
+						status.run_mode,
+						format_uptime(status.uptime())
+					),
+				));
+				match self.data.with::<DbPool, _, _>(Clone::clone) {
+					Ok(db_pool) => lines.push((
+						normal,
+						format!(
+							"Database pool: {} connections, {} idle",
+							db_pool.size(),
+							db_pool.num_idle()
+						),
+					)),
+					Err(_) => lines.push((normal, "Database pool: unavailable".to_owned())),
+				}
+				for (name, state) in status.tasks() {
+					let color = match state {
+						TaskState::Running => theme::BaseColor::Green.dark(),
+						TaskState::Restarting => theme::BaseColor::Yellow.dark(),
+						TaskState::Completed => normal,
+						TaskState::Failed => theme::BaseColor::Red.dark(),
+					};
+					lines.push((color, format!("  {}: {:?}", name, state)));
+				}
+			}
+			Err(_) => lines.push((normal, "System status is not available yet".to_owned())),
+		}
+		lines
+	}
+}
+
+fn format_uptime(uptime: Duration) -> String {
+	let secs = uptime.as_secs();
+	format!(
+		"{}d {:02}:{:02}:{:02}",
+		secs / 86400,
+		secs / 3600 % 24,
+		secs / 60 % 60,
+		secs % 60
+	)
+}
+
+impl View for StatusView {
+	fn draw(&self, printer: &Printer<'_, '_>) {
+		for (y, (color, line)) in self.lines().into_iter().take(printer.size.y).enumerate() {
+			printer.with_color(color.into(), |printer| {
+				printer.print((0, y), &line);
+			});
+		}
+	}
+
+	fn required_size(&mut self, constraint: Vec2) -> Vec2 {
+		let lines = self.lines();
+		let width = lines.iter().map(|(_, line)| line.len()).max().unwrap_or(0);
+		Vec2::new(constraint.x.min(width), constraint.y.min(lines.len()))
+	}
+}
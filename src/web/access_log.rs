@@ -0,0 +1,72 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use std::time::Instant;
+use tracing::log::Level;
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+	/// Level each request is logged at. **(default: `Info`)**
+	pub level: Level,
+	/// Include the client IP address in each line. **(default: `true`)**
+	pub client_ip: bool,
+}
+
+impl Default for AccessLogConfig {
+	fn default() -> Self {
+		Self {
+			level: Level::Info,
+			client_ip: true,
+		}
+	}
+}
+
+/// When the request arrived, cached on the request
+struct RequestStart(Instant);
+
+/// Logs every request through the log appenders once it has been responded to
+pub struct AccessLog(pub AccessLogConfig);
+
+#[rocket::async_trait]
+impl Fairing for AccessLog {
+	fn info(&self) -> Info {
+		Info {
+			name: "Access Log",
+			kind: Kind::Request | Kind::Response,
+		}
+	}
+
+	async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+		request.local_cache(|| RequestStart(Instant::now()));
+	}
+
+	async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+		let elapsed = request
+			.local_cache(|| RequestStart(Instant::now()))
+			.0
+			.elapsed();
+		if self.0.client_ip {
+			let client_ip = request
+				.client_ip()
+				.map_or_else(|| "-".to_owned(), |ip| ip.to_string());
+			tracing::log::log!(
+				self.0.level,
+				"{} {} {} {} {:.1}ms",
+				client_ip,
+				request.method(),
+				request.uri(),
+				response.status().code,
+				elapsed.as_secs_f64() * 1000.0
+			);
+		} else {
+			tracing::log::log!(
+				self.0.level,
+				"{} {} {} {:.1}ms",
+				request.method(),
+				request.uri(),
+				response.status().code,
+				elapsed.as_secs_f64() * 1000.0
+			);
+		}
+	}
+}
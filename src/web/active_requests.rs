@@ -0,0 +1,72 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ShutdownStatus {
+	/// Every in-flight request finished within the grace and mercy periods
+	Drained,
+	/// This many requests were still outstanding when the server stopped waiting on them
+	ForceTerminated(usize),
+}
+
+/// Count of requests currently being handled by the web server, registered in the
+/// `DashTypeMap` so it can be inspected from elsewhere.
+#[derive(Default)]
+pub struct ActiveRequests {
+	active: AtomicUsize,
+	last_shutdown: RwLock<Option<ShutdownStatus>>,
+}
+
+impl ActiveRequests {
+	pub fn active(&self) -> usize {
+		self.active.load(Ordering::Acquire)
+	}
+
+	/// How the most recent shutdown of the web server went, `None` if it hasn't shut down yet
+	pub fn last_shutdown(&self) -> Option<ShutdownStatus> {
+		*self.last_shutdown.read().expect("poisoned lock")
+	}
+
+	/// Records the outcome of a shutdown from the requests still active
+	pub fn finish_shutdown(&self) -> ShutdownStatus {
+		let status = match self.active() {
+			0 => ShutdownStatus::Drained,
+			outstanding => ShutdownStatus::ForceTerminated(outstanding),
+		};
+		*self.last_shutdown.write().expect("poisoned lock") = Some(status);
+		status
+	}
+
+	/// Requests of a terminated server never reach the response stage
+	pub fn reset(&self) {
+		self.active.store(0, Ordering::Release);
+	}
+}
+
+/// Marker cached on a request so only counted requests are released
+struct Counted;
+
+pub struct ActiveRequestsFairing(pub Arc<ActiveRequests>);
+
+#[rocket::async_trait]
+impl Fairing for ActiveRequestsFairing {
+	fn info(&self) -> Info {
+		Info {
+			name: "Active Requests",
+			kind: Kind::Request | Kind::Response,
+		}
+	}
+
+	async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+		self.0.active.fetch_add(1, Ordering::AcqRel);
+		request.local_cache(|| Some(Counted));
+	}
+
+	async fn on_response<'r>(&self, request: &'r Request<'_>, _response: &mut Response<'r>) {
+		if request.local_cache(|| None::<Counted>).is_some() {
+			self.0.active.fetch_sub(1, Ordering::AcqRel);
+		}
+	}
+}
@@ -1,6 +1,9 @@
-use crate::accounts::{AccountSession, Accounts};
+use crate::accounts::{
+	AccountError, AccountSession, Accounts, AccountsConfig, AccountsError, LoginOutcome, Role,
+};
 use crate::database::{DbPool, DbTransaction};
 use anyhow::Context;
+use dashmap::DashMap;
 use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::outcome::try_outcome;
 use rocket::outcome::IntoOutcome;
@@ -10,14 +13,110 @@ use std::convert::TryInto;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 use time::Duration;
 use tracing::*;
+use uuid::Uuid;
 
-const COOKIE_USER_SESSION: &str = "user_session";
+/// The longest a validated session is trusted without asking the database again, so a revoked
+/// session stops working soon even when it was revoked somewhere that can't invalidate the cache
+pub const SESSION_CACHE_MAX_TTL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Sessions recently validated against the database, shared through the `DashTypeMap` so a
+/// restarted web task keeps it, keyed by session token
+#[derive(Debug)]
+pub struct SessionValidationCache {
+	ttl: std::time::Duration,
+	validated: DashMap<Uuid, (Uuid, Instant)>,
+}
+
+impl SessionValidationCache {
+	/// A `ttl` of zero disables the cache, it is capped at [`SESSION_CACHE_MAX_TTL`]
+	pub fn new(ttl: std::time::Duration) -> Self {
+		Self {
+			ttl: ttl.min(SESSION_CACHE_MAX_TTL),
+			validated: DashMap::new(),
+		}
+	}
+
+	fn is_validated(&self, session: &AccountSession) -> bool {
+		match self.validated.get(&session.token()) {
+			Some(entry) => {
+				let (id, validated_at) = *entry;
+				id == session.id() && validated_at.elapsed() < self.ttl
+			}
+			None => false,
+		}
+	}
+
+	fn insert(&self, session: &AccountSession) {
+		if !self.ttl.is_zero() {
+			self.validated
+				.insert(session.token(), (session.id(), Instant::now()));
+		}
+	}
+
+	pub fn invalidate(&self, token: Uuid) {
+		self.validated.remove(&token);
+	}
+
+	pub fn invalidate_account(&self, id: Uuid) {
+		self.validated
+			.retain(|_token, (account_id, _validated_at)| *account_id != id);
+	}
+
+	pub fn prune(&self) {
+		let ttl = self.ttl;
+		self.validated
+			.retain(|_token, (_account_id, validated_at)| validated_at.elapsed() < ttl);
+	}
+}
+
+/// How the session cookie is named and sent, managed by rocket so every path that sets, reads,
+/// or removes the cookie agrees on it
+#[derive(Clone, Debug)]
+pub struct SessionCookieConfig {
+	pub name: String,
+	/// Only send the cookie over HTTPS
+	pub secure: bool,
+}
+
+impl SessionCookieConfig {
+	/// Removes the session and refresh cookies without touching either in the database
+	pub fn remove(&self, cookies: &CookieJar<'_>) {
+		cookies.remove_private(Cookie::named(self.name.clone()));
+		cookies.remove_private(Cookie::named(self.refresh_name()));
+	}
+
+	/// The remember me refresh token cookie is named after the session cookie
+	pub fn refresh_name(&self) -> String {
+		format!("{}_refresh", self.name)
+	}
+
+	fn add(&self, cookies: &CookieJar<'_>, name: String, value: String, max_age: Duration) {
+		let mut cookie = Cookie::named(name);
+		cookie.set_http_only(true);
+		cookie.set_max_age(Some(max_age));
+		cookie.set_value(value);
+		cookie.set_secure(self.secure);
+		cookie.set_same_site(SameSite::Strict);
+		cookies.add_private(cookie);
+	}
+
+	fn from_state<'r>(request: &'r Request<'_>) -> Outcome<&'r Self, ()> {
+		request
+			.rocket()
+			.state::<SessionCookieConfig>()
+			.into_outcome((Status::InternalServerError, ()))
+	}
+}
 
 #[derive(Debug)]
 pub struct AuthControl<'r> {
 	_phantom: PhantomData<&'r ()>,
+	cookie_config: &'r SessionCookieConfig,
+	session_cache: Option<&'r Arc<SessionValidationCache>>,
 	pub auth_session: Option<AuthSession<'r>>,
 }
 
@@ -26,6 +125,7 @@ impl<'r> FromRequest<'r> for AuthControl<'r> {
 	type Error = ();
 
 	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let cookie_config = try_outcome!(SessionCookieConfig::from_state(request));
 		let auth_session = if let Outcome::Success(auth_session) = request.guard().await {
 			Some(auth_session)
 		} else {
@@ -33,6 +133,8 @@ impl<'r> FromRequest<'r> for AuthControl<'r> {
 		};
 		let control = Self {
 			_phantom: Default::default(),
+			cookie_config,
+			session_cache: request.rocket().state::<Arc<SessionValidationCache>>(),
 			auth_session,
 		};
 		Outcome::Success(control)
@@ -44,40 +146,150 @@ impl<'r> AuthControl<'r> {
 		self.auth_session.is_some()
 	}
 
+	/// Returns `false` without logging in when a two factor code is required but wasn't given,
+	/// `remember_me` also issues a refresh token cookie for `refresh` to renew the session with
+	#[allow(clippy::too_many_arguments)]
 	pub async fn login(
 		&self,
 		db_pool: &DbPool,
+		accounts_config: &AccountsConfig,
 		cookies: &CookieJar<'_>,
 		username: &str,
 		password: &str,
+		totp_code: Option<&str>,
 		age_secs: u64,
-	) -> anyhow::Result<()> {
+		remember_me: bool,
+	) -> anyhow::Result<bool> {
 		info!("Login being attempted: {} - {}", username, age_secs);
 		let age_secs: i64 = age_secs.try_into().context("invalid possible age")?;
 		let mut conn = db_pool.begin().await?;
-		let user_session =
-			Accounts::login_session(&mut conn, username, password, Duration::seconds(age_secs))
-				.await?;
-		drop(conn);
-		let mut cookie = Cookie::named(COOKIE_USER_SESSION);
-		cookie.set_http_only(true);
-		cookie.set_max_age(Some(Duration::seconds(age_secs).try_into().unwrap()));
-		cookie.set_value(user_session.to_string());
-		// cookie.set_secure(true);
-		cookie.set_same_site(SameSite::Strict);
-		cookies.add_private(cookie);
+		let outcome = Accounts::login_session(
+			&mut conn,
+			accounts_config,
+			username,
+			password,
+			totp_code,
+			Duration::seconds(age_secs),
+		)
+		.await;
+		let refresh_token = match &outcome {
+			Ok(LoginOutcome::Session(user_session)) if remember_me => Some(
+				Accounts::create_refresh_token(&mut conn, accounts_config, user_session.id())
+					.await?,
+			),
+			_ => None,
+		};
+		// Committed even when the login failed so the failed login counts toward the lockout
+		conn.commit().await?;
+		let user_session = match outcome? {
+			LoginOutcome::Session(user_session) => user_session,
+			LoginOutcome::TwoFactorRequired => return Ok(false),
+		};
+		self.cookie_config.add(
+			cookies,
+			self.cookie_config.name.clone(),
+			user_session.to_string(),
+			Duration::seconds(age_secs),
+		);
+		if let Some(refresh_token) = refresh_token {
+			self.cookie_config.add(
+				cookies,
+				self.cookie_config.refresh_name(),
+				refresh_token.to_hyphenated().to_string(),
+				accounts_config
+					.refresh_token_valid_duration()
+					.try_into()
+					.context("invalid refresh token duration")?,
+			);
+		}
+		Ok(true)
+	}
+
+	/// Trades the refresh token cookie for a new session and refresh token, returns `false` when
+	/// there is no refresh token cookie, the refresh cookie is removed if it was refused
+	pub async fn refresh(
+		&self,
+		db_pool: &DbPool,
+		accounts_config: &AccountsConfig,
+		cookies: &CookieJar<'_>,
+		age_secs: u64,
+	) -> anyhow::Result<bool> {
+		let refresh_token = match cookies.get_private(&self.cookie_config.refresh_name()) {
+			Some(cookie) => Uuid::from_str(cookie.value()).ok(),
+			None => return Ok(false),
+		};
+		let age_secs: i64 = age_secs.try_into().context("invalid possible age")?;
+		let mut conn = db_pool.begin().await?;
+		let refreshed = match refresh_token {
+			Some(refresh_token) => {
+				Accounts::refresh_session(&mut conn, refresh_token, Duration::seconds(age_secs))
+					.await
+			}
+			None => Err(AccountsError::InvalidRefreshToken),
+		};
+		let (user_session, refresh_token) = match refreshed {
+			Ok(refreshed) => refreshed,
+			Err(e) => {
+				cookies.remove_private(Cookie::named(self.cookie_config.refresh_name()));
+				return Err(e.into());
+			}
+		};
+		conn.commit().await?;
+		self.cookie_config.add(
+			cookies,
+			self.cookie_config.name.clone(),
+			user_session.to_string(),
+			Duration::seconds(age_secs),
+		);
+		self.cookie_config.add(
+			cookies,
+			self.cookie_config.refresh_name(),
+			refresh_token.to_hyphenated().to_string(),
+			accounts_config
+				.refresh_token_valid_duration()
+				.try_into()
+				.context("invalid refresh token duration")?,
+		);
+		Ok(true)
+	}
+
+	/// Removes the session and refresh cookies and revokes whichever of them were present in the
+	/// database
+	pub async fn logout(&self, db_pool: &DbPool, cookies: &CookieJar<'_>) -> anyhow::Result<()> {
+		if let Some(cookie) = cookies.get_private(&self.cookie_config.name) {
+			if let Ok(user_session) = AccountSession::from_str(cookie.value()) {
+				info!("Logout of session: {}", user_session);
+				let mut conn = db_pool.begin().await?;
+				user_session.revoke(&mut conn).await?;
+				conn.commit().await?;
+				if let Some(cache) = self.session_cache {
+					cache.invalidate(user_session.token());
+				}
+			}
+		}
+		if let Some(cookie) = cookies.get_private(&self.cookie_config.refresh_name()) {
+			if let Ok(refresh_token) = Uuid::from_str(cookie.value()) {
+				let mut conn = db_pool.begin().await?;
+				Accounts::revoke_refresh_token(&mut conn, refresh_token).await?;
+				conn.commit().await?;
+			}
+		}
+		self.cookie_config.remove(cookies);
 		Ok(())
 	}
 
 	pub async fn register(
 		&self,
 		conn: &mut DbTransaction<'_>,
+		accounts_config: &AccountsConfig,
 		username: &str,
 		password: &str,
 	) -> anyhow::Result<()> {
 		info!("Registration being attempted: {}", username);
-		let account = Accounts::create_account(conn, username).await?;
-		account.set_password(conn, None, Some(password)).await?;
+		let account = Accounts::create_account(conn, accounts_config, username).await?;
+		account
+			.set_password(conn, accounts_config, None, Some(password))
+			.await?;
 		Ok(())
 	}
 }
@@ -88,6 +300,17 @@ pub struct AuthSession<'r> {
 	pub user_session: AccountSession,
 }
 
+impl<'r> AuthSession<'r> {
+	/// Runtime equivalent of the `RequireRole` guard for when the role isn't known statically
+	pub async fn has_role(
+		&self,
+		conn: &mut DbTransaction<'_>,
+		role: &str,
+	) -> Result<bool, AccountError> {
+		Accounts::has_role(conn, self.user_session.id(), role).await
+	}
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for AuthSession<'r> {
 	type Error = ();
@@ -97,14 +320,26 @@ impl<'r> FromRequest<'r> for AuthSession<'r> {
 			.rocket()
 			.state::<DbPool>()
 			.into_outcome((Status::InternalServerError, ())));
+		let cookie_config = try_outcome!(SessionCookieConfig::from_state(request));
 		let user_session_cookie = try_outcome!(request
 			.cookies()
-			.get_private(COOKIE_USER_SESSION)
+			.get_private(&cookie_config.name)
 			.into_outcome((Status::Unauthorized, ())));
 		let user_session_string: &str = user_session_cookie.value();
 		let user_session = try_outcome!(AccountSession::from_str(user_session_string)
 			.map_err(|_| ())
 			.into_outcome(Status::Unauthorized));
+		let session_cache = request.rocket().state::<Arc<SessionValidationCache>>();
+		if session_cache.map_or(false, |cache| cache.is_validated(&user_session)) {
+			debug!(
+				"AuthSession found in cookie, recently validated: {}",
+				user_session
+			);
+			return Outcome::Success(Self {
+				_phantom: Default::default(),
+				user_session,
+			});
+		}
 		{
 			let mut conn = try_outcome!(db_pool
 				.begin()
@@ -117,6 +352,9 @@ impl<'r> FromRequest<'r> for AuthSession<'r> {
 				.map_err(|_| ())
 				.into_outcome(Status::Unauthorized));
 		}
+		if let Some(cache) = session_cache {
+			cache.insert(&user_session);
+		}
 		debug!("AuthSession found in cookie: {}", user_session);
 		Outcome::Success(Self {
 			_phantom: Default::default(),
@@ -124,3 +362,146 @@ impl<'r> FromRequest<'r> for AuthSession<'r> {
 		})
 	}
 }
+
+/// An `AuthSession` whose account has the role `R`, otherwise a 403
+#[derive(Debug)]
+pub struct RequireRole<'r, R: Role> {
+	_role: PhantomData<R>,
+	pub auth_session: AuthSession<'r>,
+}
+
+#[rocket::async_trait]
+impl<'r, R: Role> FromRequest<'r> for RequireRole<'r, R> {
+	type Error = ();
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let auth_session: AuthSession<'r> = try_outcome!(request.guard().await);
+		let db_pool = try_outcome!(request
+			.rocket()
+			.state::<DbPool>()
+			.into_outcome((Status::InternalServerError, ())));
+		let mut conn = try_outcome!(db_pool
+			.begin()
+			.await
+			.map_err(|e| error!("{}", e))
+			.into_outcome(Status::InternalServerError));
+		let has_role = try_outcome!(auth_session
+			.has_role(&mut conn, R::NAME)
+			.await
+			.map_err(|e| error!("{}", e))
+			.into_outcome(Status::InternalServerError));
+		if !has_role {
+			debug!(
+				"AuthSession {} lacks role: {}",
+				auth_session.user_session,
+				R::NAME
+			);
+			return Outcome::Failure((Status::Forbidden, ()));
+		}
+		Outcome::Success(Self {
+			_role: PhantomData,
+			auth_session,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database::test_support::{test_database_url, test_pool};
+	use rocket::local::asynchronous::Client;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	const REQUESTS: usize = 20;
+
+	#[rocket::get("/whoami")]
+	fn whoami(auth_session: AuthSession<'_>) -> String {
+		auth_session.user_session.id().to_string()
+	}
+
+	/// Makes `REQUESTS` authenticated requests, returning how many database connections they used
+	async fn connections_used(ttl: std::time::Duration, user_session: &AccountSession) -> usize {
+		let released = Arc::new(AtomicUsize::new(0));
+		let counter = released.clone();
+		let db_pool: DbPool = Arc::new(
+			sqlx::postgres::PgPoolOptions::new()
+				.max_connections(2)
+				.after_release(move |_conn| {
+					counter.fetch_add(1, Ordering::SeqCst);
+					true
+				})
+				.connect(&test_database_url().unwrap())
+				.await
+				.unwrap(),
+		);
+		let rocket = rocket::build()
+			.manage(db_pool)
+			.manage(SessionCookieConfig {
+				name: "user_session".to_owned(),
+				secure: false,
+			})
+			.manage(Arc::new(SessionValidationCache::new(ttl)))
+			.mount("/", rocket::routes![whoami]);
+		let client = Client::tracked(rocket).await.unwrap();
+		for _ in 0..REQUESTS {
+			let response = client
+				.get("/whoami")
+				.private_cookie(Cookie::new("user_session", user_session.to_string()))
+				.dispatch()
+				.await;
+			assert_eq!(response.status(), Status::Ok);
+		}
+		released.load(Ordering::SeqCst)
+	}
+
+	#[tokio::test]
+	async fn session_cache_skips_the_database_for_recently_validated_sessions() {
+		let pool = match test_pool(&[&crate::accounts::MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let config = AccountsConfig::default();
+		let password = "correct horse battery staple";
+		// Committed as the requests validate on their own connections
+		let mut conn = pool.begin().await.unwrap();
+		let login = format!("test_{}", Uuid::new_v4().to_simple());
+		let account = Accounts::create_account(&mut conn, &config, &login)
+			.await
+			.unwrap();
+		account
+			.set_password(&mut conn, &config, None, Some(password))
+			.await
+			.unwrap();
+		let user_session = match Accounts::login_session(
+			&mut conn,
+			&config,
+			&login,
+			password,
+			None,
+			Duration::hours(1),
+		)
+		.await
+		.unwrap()
+		{
+			LoginOutcome::Session(user_session) => user_session,
+			LoginOutcome::TwoFactorRequired => unreachable!(),
+		};
+		conn.commit().await.unwrap();
+
+		let uncached = connections_used(std::time::Duration::ZERO, &user_session).await;
+		assert_eq!(
+			uncached, REQUESTS,
+			"every uncached request should validate against the database"
+		);
+		let cached = connections_used(std::time::Duration::from_secs(60), &user_session).await;
+		assert_eq!(
+			cached, 1,
+			"only the first of {} cached requests should validate against the database",
+			REQUESTS
+		);
+
+		let mut conn = pool.begin().await.unwrap();
+		account.remove(&mut conn).await.unwrap();
+		conn.commit().await.unwrap();
+	}
+}
@@ -0,0 +1,173 @@
+use flate2::write::GzEncoder;
+use flate2::Compression as GzLevel;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header, Status};
+use rocket::{Request, Response};
+use std::io::{Cursor, Write};
+use tracing::*;
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+	/// Responses smaller than this many bytes are sent as is. **(default: `1024`)**
+	pub min_size: usize,
+	/// Gzip level from `0` to `9`. **(default: `6`)**
+	pub level: u32,
+}
+
+impl Default for CompressionConfig {
+	fn default() -> Self {
+		Self {
+			min_size: 1024,
+			level: 6,
+		}
+	}
+}
+
+/// Gzips text like responses of a known size when the client accepts it, streamed responses such
+/// as the server sent events are left alone, as are anything but full `200` responses since a
+/// partial or error body is not what the client asked to be encoded
+pub struct Compression(pub CompressionConfig);
+
+fn accepts_gzip(request: &Request<'_>) -> bool {
+	request
+		.headers()
+		.get("Accept-Encoding")
+		.flat_map(|value| value.split(','))
+		.any(|coding| {
+			let mut parts = coding.split(';').map(str::trim);
+			let name = parts.next().unwrap_or_default();
+			let refused = parts.any(|param| {
+				param
+					.strip_prefix("q=")
+					.and_then(|q| q.parse::<f32>().ok())
+					.map_or(false, |q| q <= 0.0)
+			});
+			(name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+		})
+}
+
+fn is_compressible(content_type: &ContentType) -> bool {
+	let (top, sub) = (content_type.top().as_str(), content_type.sub().as_str());
+	match top {
+		"text" => sub != "event-stream",
+		"application" => matches!(
+			sub,
+			"json" | "javascript" | "xml" | "wasm" | "manifest+json"
+		),
+		"image" => sub == "svg+xml",
+		_ => false,
+	}
+}
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+	fn info(&self) -> Info {
+		Info {
+			name: "Compression",
+			kind: Kind::Response,
+		}
+	}
+
+	async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+		if response.status() != Status::Ok
+			|| response.headers().contains("Content-Range")
+			|| response.headers().contains("Content-Encoding")
+			|| !response
+				.content_type()
+				.map_or(false, |ct| is_compressible(&ct))
+			|| !accepts_gzip(request)
+		{
+			return;
+		}
+		match response.body().preset_size() {
+			Some(size) if size >= self.0.min_size => (),
+			_ => return,
+		}
+		let body = match response.body_mut().to_bytes().await {
+			Ok(body) => body,
+			Err(e) => {
+				error!("Failed reading a response body to compress: {}", e);
+				return;
+			}
+		};
+		let mut encoder = GzEncoder::new(
+			Vec::with_capacity(body.len() / 2),
+			GzLevel::new(self.0.level.min(9)),
+		);
+		let compressed = encoder.write_all(&body).and_then(|()| encoder.finish());
+		match compressed {
+			Ok(compressed) if compressed.len() < body.len() => {
+				response.set_header(Header::new("Content-Encoding", "gzip"));
+				response.set_sized_body(compressed.len(), Cursor::new(compressed));
+			}
+			// Not worth it, or failed, either way the original body still has to be sent
+			_ => response.set_sized_body(body.len(), Cursor::new(body)),
+		}
+		response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rocket::local::blocking::Client;
+	use rocket::response::content::Html;
+	use rocket::response::status::NotFound;
+
+	fn page() -> Html<String> {
+		Html("<p>overbot</p>".repeat(200))
+	}
+
+	#[rocket::get("/page")]
+	fn ok_page() -> Html<String> {
+		page()
+	}
+
+	#[rocket::get("/missing")]
+	fn missing_page() -> NotFound<Html<String>> {
+		NotFound(page())
+	}
+
+	#[derive(rocket::Responder)]
+	#[response(status = 206)]
+	struct PartialPage {
+		body: Html<String>,
+		range: Header<'static>,
+	}
+
+	#[rocket::get("/range")]
+	fn range_page() -> PartialPage {
+		PartialPage {
+			body: page(),
+			range: Header::new("Content-Range", "bytes 0-2799/5600"),
+		}
+	}
+
+	fn client() -> Client {
+		Client::tracked(
+			rocket::build()
+				.attach(Compression(CompressionConfig::default()))
+				.mount("/", rocket::routes![ok_page, missing_page, range_page]),
+		)
+		.unwrap()
+	}
+
+	fn encoding(client: &Client, uri: &'static str) -> Option<String> {
+		client
+			.get(uri)
+			.header(Header::new("Accept-Encoding", "gzip"))
+			.dispatch()
+			.headers()
+			.get_one("Content-Encoding")
+			.map(str::to_owned)
+	}
+
+	#[test]
+	fn only_full_ok_responses_are_compressed() {
+		let client = client();
+		assert_eq!(encoding(&client, "/page").as_deref(), Some("gzip"));
+		assert_eq!(encoding(&client, "/missing"), None);
+		assert_eq!(encoding(&client, "/range"), None);
+	}
+}
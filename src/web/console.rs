@@ -0,0 +1,171 @@
+//! Live admin console for the browser UI, the web counterpart of the TUI.
+//!
+//! Rocket 0.5.0-rc.1 has no connection upgrade support to build a websocket on, so the console
+//! is a server sent event stream of system events with commands posted back separately.
+
+use super::{json_error, log_record_json, JsonError, LOGS_DEFAULT_CACHE};
+use crate::accounts::AdminRole;
+use crate::dash_type_map::{ChangeKind, DashTypeMap};
+use crate::logger::cache_appender::{Cache, CachedLogRecord};
+use crate::logger::conditional_map::ConditionalMap;
+use crate::system::{SystemStatus, TaskState};
+use crate::web::auth::{AuthSession, RequireRole};
+use crate::web::csrf::CsrfToken;
+use rocket::http::Status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::{json, Json, Value};
+use rocket::{Shutdown, State};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::log::LevelFilter;
+
+/// Caps the number of consoles connected at once
+pub struct ConsoleConnections {
+	active: AtomicUsize,
+	max: usize,
+}
+
+impl ConsoleConnections {
+	pub fn new(max: usize) -> Arc<Self> {
+		Arc::new(Self {
+			active: AtomicUsize::new(0),
+			max,
+		})
+	}
+
+	fn acquire(self: &Arc<Self>) -> Option<ConsoleSlot> {
+		let max = self.max;
+		self.active
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+				Some(active + 1).filter(|active| *active <= max)
+			})
+			.ok()
+			.map(|_| ConsoleSlot(self.clone()))
+	}
+}
+
+/// A connected console, releases its slot when the stream ends or the client disconnects
+struct ConsoleSlot(Arc<ConsoleConnections>);
+
+impl Drop for ConsoleSlot {
+	fn drop(&mut self) {
+		self.0.active.fetch_sub(1, Ordering::AcqRel);
+	}
+}
+
+/// Streams new log records as `log` events, `DashTypeMap` changes as `change` events and system
+/// task state changes, like a task stopping or being restarted, as `task` events until the client
+/// disconnects or the server shuts down.
+#[rocket::get("/console/events")]
+pub fn console_events(
+	_auth: AuthSession<'_>,
+	connections: &State<Arc<ConsoleConnections>>,
+	data: &State<Arc<DashTypeMap>>,
+	mut shutdown: Shutdown,
+) -> Result<EventStream![], JsonError> {
+	let slot = connections
+		.acquire()
+		.ok_or_else(|| json_error(Status::ServiceUnavailable, "too many console connections"))?;
+	let mut records = Cache::subscribe(LOGS_DEFAULT_CACHE);
+	let mut changes = data.subscribe();
+	let mut tasks = data
+		.with::<Arc<SystemStatus>, _, _>(|status| status.subscribe_tasks())
+		.ok();
+	let data = data.inner().clone();
+	Ok(EventStream! {
+		let _slot = slot;
+		loop {
+			tokio::select! {
+				record = recv_record(&mut records) => match record {
+					Ok(record) => yield Event::json(&log_record_json(&record)).event("log"),
+					Err(broadcast::error::RecvError::Lagged(_)) => continue,
+					// The log cache went away, keep streaming the changes
+					Err(broadcast::error::RecvError::Closed) => records = None,
+				},
+				change = changes.recv() => match change {
+					Ok(((type_id, name), kind)) => {
+						let kind = match kind {
+							ChangeKind::Inserted => "inserted",
+							ChangeKind::Removed => "removed",
+						};
+						yield Event::json(&json!({
+							"kind": kind,
+							"type": data.type_name(&type_id).unwrap_or("unknown"),
+							"name": name,
+						})).event("change");
+					}
+					Err(broadcast::error::RecvError::Lagged(_)) => continue,
+					Err(broadcast::error::RecvError::Closed) => break,
+				},
+				task = recv_task(&mut tasks) => match task {
+					Ok((name, state)) => yield Event::json(&json!({
+						"name": name,
+						"state": state.as_str(),
+					})).event("task"),
+					Err(broadcast::error::RecvError::Lagged(_)) => continue,
+					Err(broadcast::error::RecvError::Closed) => tasks = None,
+				},
+				_ = &mut shutdown => break,
+			}
+		}
+	})
+}
+
+async fn recv_record(
+	records: &mut Option<broadcast::Receiver<CachedLogRecord>>,
+) -> Result<CachedLogRecord, broadcast::error::RecvError> {
+	match records {
+		Some(records) => records.recv().await,
+		None => std::future::pending().await,
+	}
+}
+
+async fn recv_task(
+	tasks: &mut Option<broadcast::Receiver<(String, TaskState)>>,
+) -> Result<(String, TaskState), broadcast::error::RecvError> {
+	match tasks {
+		Some(tasks) => tasks.recv().await,
+		None => std::future::pending().await,
+	}
+}
+
+#[derive(serde::Deserialize)]
+pub struct ConsoleCommand {
+	command: String,
+}
+
+/// Runs a console command, currently:
+///
+/// - `log levels`
+/// - `set log level [<target>] <level>`
+/// - `clear log level [<target>]`
+///
+/// An omitted target is every target.
+#[rocket::post("/console/command", data = "<command>")]
+pub fn console_command(
+	command: Json<ConsoleCommand>,
+	_csrf: CsrfToken,
+	_admin: RequireRole<'_, AdminRole>,
+) -> Result<Json<Value>, JsonError> {
+	let words: Vec<&str> = command.command.split_whitespace().collect();
+	match words.as_slice() {
+		["log", "levels"] => {}
+		["set", "log", "level", target @ .., level] if target.len() <= 1 => {
+			let level = LevelFilter::from_str(level)
+				.map_err(|_e| json_error(Status::BadRequest, "unknown log level"))?;
+			ConditionalMap::set_level(target.first().copied().unwrap_or(""), level);
+		}
+		["clear", "log", "level", target @ ..] if target.len() <= 1 => {
+			ConditionalMap::clear_level(target.first().copied().unwrap_or(""));
+		}
+		_ => return Err(json_error(Status::BadRequest, "unknown command")),
+	}
+	let levels: BTreeMap<String, &str> = ConditionalMap::levels()
+		.into_iter()
+		.map(|(target, level)| (target, level.as_str()))
+		.collect();
+	Ok(Json(json!({ "levels": levels })))
+}
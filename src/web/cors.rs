@@ -0,0 +1,186 @@
+use crate::system::ConfigError;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::{Request, Response};
+use std::io::Cursor;
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct CorsConfig {
+	/// Origins like `"https://app.example.com"` allowed to make cross origin requests, `"*"` allows
+	/// any origin. **(default: `[]`)**
+	pub allowed_origins: Vec<String>,
+	/// **(default: `["GET", "POST"]`)**
+	pub allowed_methods: Vec<String>,
+	/// Request headers allowed in cross origin requests.
+	/// **(default: `["Content-Type", "X-CSRF-Token"]`)**
+	pub allowed_headers: Vec<String>,
+	/// Whether cookies are sent with cross origin requests, only for the listed origins as any
+	/// site could read authenticated responses through a `"*"`. **(default: `false`)**
+	pub allow_credentials: bool,
+	/// How long in seconds browsers may cache a preflight response. **(default: `3600`)**
+	pub max_age: u64,
+}
+
+impl CorsConfig {
+	/// Adds every problem with the configuration to `errors`
+	pub fn validate(&self, errors: &mut Vec<ConfigError>) {
+		if self.allow_credentials && self.allowed_origins.iter().any(|allowed| allowed == "*") {
+			errors.push(ConfigError::new(
+				"web.cors.allowed_origins",
+				"must list the origins explicitly instead of `\"*\"` when `allow_credentials` is set",
+			));
+		}
+	}
+}
+
+impl Default for CorsConfig {
+	fn default() -> Self {
+		Self {
+			allowed_origins: vec![],
+			allowed_methods: vec!["GET".to_owned(), "POST".to_owned()],
+			allowed_headers: vec!["Content-Type".to_owned(), "X-CSRF-Token".to_owned()],
+			allow_credentials: false,
+			max_age: 60 * 60,
+		}
+	}
+}
+
+/// Adds the `Access-Control-*` headers for allowed origins and answers their preflight requests,
+/// requests from other origins get no CORS headers at all so browsers keep them same origin only
+pub struct Cors(pub CorsConfig);
+
+impl Cors {
+	/// Only explicitly listed origins are ever echoed back
+	fn lists_origin(&self, origin: &str) -> bool {
+		self.0
+			.allowed_origins
+			.iter()
+			.any(|allowed| allowed != "*" && allowed.eq_ignore_ascii_case(origin))
+	}
+
+	fn allows_any_origin(&self) -> bool {
+		self.0.allowed_origins.iter().any(|allowed| allowed == "*")
+	}
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+	fn info(&self) -> Info {
+		Info {
+			name: "CORS",
+			kind: Kind::Response,
+		}
+	}
+
+	async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+		let origin = match request.headers().get_one("Origin") {
+			Some(origin) => origin,
+			None => return,
+		};
+		if self.lists_origin(origin) {
+			response.set_header(Header::new(
+				"Access-Control-Allow-Origin",
+				origin.to_owned(),
+			));
+			response.adjoin_header(Header::new("Vary", "Origin"));
+			if self.0.allow_credentials {
+				response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+			}
+		} else if self.allows_any_origin() {
+			// Never with credentials, browsers then refuse to share authenticated responses
+			response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+		} else {
+			return;
+		}
+		let is_preflight = request.method() == Method::Options
+			&& request.headers().contains("Access-Control-Request-Method");
+		if is_preflight {
+			response.set_header(Header::new(
+				"Access-Control-Allow-Methods",
+				self.0.allowed_methods.join(", "),
+			));
+			response.set_header(Header::new(
+				"Access-Control-Allow-Headers",
+				self.0.allowed_headers.join(", "),
+			));
+			response.set_header(Header::new(
+				"Access-Control-Max-Age",
+				self.0.max_age.to_string(),
+			));
+			// There are no `OPTIONS` routes, so this replaces the 404
+			response.set_status(Status::NoContent);
+			response.set_sized_body(0, Cursor::new(""));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rocket::local::blocking::Client;
+
+	#[rocket::get("/data")]
+	fn data() -> &'static str {
+		"data"
+	}
+
+	fn client(config: CorsConfig) -> Client {
+		Client::tracked(
+			rocket::build()
+				.attach(Cors(config))
+				.mount("/", rocket::routes![data]),
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn wildcard_origin_with_credentials_is_rejected() {
+		let config = CorsConfig {
+			allowed_origins: vec!["*".to_owned()],
+			allow_credentials: true,
+			..CorsConfig::default()
+		};
+		let mut errors = Vec::new();
+		config.validate(&mut errors);
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].field, "web.cors.allowed_origins");
+	}
+
+	#[test]
+	fn wildcard_origin_is_never_reflected_or_credentialed() {
+		let client = client(CorsConfig {
+			allowed_origins: vec!["https://app.example.com".to_owned(), "*".to_owned()],
+			allow_credentials: true,
+			..CorsConfig::default()
+		});
+		let response = client
+			.get("/data")
+			.header(Header::new("Origin", "https://evil.example.com"))
+			.dispatch();
+		assert_eq!(
+			response.headers().get_one("Access-Control-Allow-Origin"),
+			Some("*")
+		);
+		assert_eq!(
+			response
+				.headers()
+				.get_one("Access-Control-Allow-Credentials"),
+			None
+		);
+		let response = client
+			.get("/data")
+			.header(Header::new("Origin", "https://app.example.com"))
+			.dispatch();
+		assert_eq!(
+			response.headers().get_one("Access-Control-Allow-Origin"),
+			Some("https://app.example.com")
+		);
+		assert_eq!(
+			response
+				.headers()
+				.get_one("Access-Control-Allow-Credentials"),
+			Some("true")
+		);
+	}
+}
@@ -0,0 +1,81 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket::http::{Cookie, CookieJar, Method, SameSite, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use tracing::*;
+
+const COOKIE_CSRF_TOKEN: &str = "csrf_token";
+/// The header state changing requests have to echo the token back in
+pub const HEADER_CSRF_TOKEN: &str = "X-CSRF-Token";
+const TOKEN_LENGTH: usize = 32;
+
+/// The request's CSRF token, on anything but a safe method the request has to carry the same token
+/// in the `X-CSRF-Token` header or it's rejected with a 403.  The token lives in a private cookie,
+/// so it's encrypted and authenticated with the rocket `SecretKey`, and clients can only learn it
+/// from the token route.
+#[derive(Debug)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+	/// The current token, issuing a new one if the client doesn't have one yet
+	pub fn get_or_issue(cookies: &CookieJar<'_>) -> Self {
+		if let Some(cookie) = cookies.get_private(COOKIE_CSRF_TOKEN) {
+			return Self(cookie.value().to_owned());
+		}
+		let token: String = rand::thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(TOKEN_LENGTH)
+			.map(char::from)
+			.collect();
+		let mut cookie = Cookie::new(COOKIE_CSRF_TOKEN, token.clone());
+		cookie.set_http_only(true);
+		cookie.set_same_site(SameSite::Strict);
+		cookies.add_private(cookie);
+		Self(token)
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	/// Compares without exiting early so the timing doesn't leak how much of a guess matched
+	fn matches(&self, given: &str) -> bool {
+		let expected = self.0.as_bytes();
+		let given = given.as_bytes();
+		expected.len() == given.len()
+			&& expected
+				.iter()
+				.zip(given)
+				.fold(0u8, |diff, (a, b)| diff | (a ^ b))
+				== 0
+	}
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+	type Error = ();
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		if matches!(
+			request.method(),
+			Method::Get | Method::Head | Method::Options
+		) {
+			return Outcome::Success(Self::get_or_issue(request.cookies()));
+		}
+		let token = match request.cookies().get_private(COOKIE_CSRF_TOKEN) {
+			Some(cookie) => Self(cookie.value().to_owned()),
+			None => {
+				debug!("CSRF token cookie missing on {}", request.uri());
+				return Outcome::Failure((Status::Forbidden, ()));
+			}
+		};
+		match request.headers().get_one(HEADER_CSRF_TOKEN) {
+			Some(given) if token.matches(given) => Outcome::Success(token),
+			_ => {
+				debug!("CSRF token mismatch on {}", request.uri());
+				Outcome::Failure((Status::Forbidden, ()))
+			}
+		}
+	}
+}
@@ -0,0 +1,72 @@
+use rocket::http::uri::Origin;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Redirect;
+use rocket::{Request, State};
+use tokio::sync::broadcast;
+use tracing::*;
+
+/// The port the HTTPS server listens on, to build the redirect target with
+struct HttpsPort(u16);
+
+/// The HTTPS URL equivalent to the plain HTTP request
+struct HttpsTarget(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HttpsTarget {
+	type Error = ();
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let https_port = match request.guard::<&State<HttpsPort>>().await {
+			Outcome::Success(https_port) => https_port.0,
+			_ => return Outcome::Failure((Status::InternalServerError, ())),
+		};
+		// Without a host there's nothing sensible to redirect to
+		let host = match request.headers().get_one("Host") {
+			Some(host) => host,
+			None => return Outcome::Failure((Status::BadRequest, ())),
+		};
+		// Strip the plain HTTP port, bracketed IPv6 hosts contain colons of their own
+		let host = match host.rfind(':') {
+			Some(colon) if !host[colon..].contains(']') => &host[..colon],
+			_ => host,
+		};
+		let origin: &Origin<'_> = request.uri();
+		let target = if https_port == 443 {
+			format!("https://{}{}", host, origin)
+		} else {
+			format!("https://{}:{}{}", host, https_port, origin)
+		};
+		Outcome::Success(Self(target))
+	}
+}
+
+#[rocket::get("/<_path..>")]
+fn redirect(_path: std::path::PathBuf, target: HttpsTarget) -> Redirect {
+	Redirect::moved(target.0)
+}
+
+/// Serves plain HTTP that only permanently redirects everything under `url_root` to the same URL
+/// on HTTPS, until `quit`
+pub async fn run_redirect_server(
+	rocket_config: rocket::Config,
+	url_root: String,
+	https_port: u16,
+	quit: broadcast::Sender<()>,
+) -> anyhow::Result<()> {
+	let rocket = rocket::custom(rocket_config)
+		.manage(HttpsPort(https_port))
+		.mount(&url_root, rocket::routes![redirect])
+		.ignite()
+		.await?;
+	let shutdown = rocket.shutdown();
+	let mut on_quit = quit.subscribe();
+	tokio::spawn(async move {
+		let _ = on_quit.recv().await;
+		shutdown.notify();
+	});
+	info!("Launching the HTTP to HTTPS redirect server");
+	rocket.launch().await?;
+	info!("HTTP to HTTPS redirect server had a successful shutdown");
+	Ok(())
+}
@@ -0,0 +1,82 @@
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum LimitKey {
+	Ip(IpAddr),
+	Login(String),
+}
+
+/// Sliding window limiter of failed logins, keyed separately by both the client IP and the
+/// attempted login name so neither rotating names nor rotating addresses gets around it.
+#[derive(Debug)]
+pub struct LoginRateLimiter {
+	failures: DashMap<LimitKey, VecDeque<Instant>>,
+	max_failures: usize,
+	window: Duration,
+}
+
+impl LoginRateLimiter {
+	pub fn new(max_failures: usize, window: Duration) -> Self {
+		Self {
+			failures: Default::default(),
+			max_failures,
+			window,
+		}
+	}
+
+	fn keys(ip: Option<IpAddr>, login: &str) -> impl Iterator<Item = LimitKey> {
+		ip.map(LimitKey::Ip)
+			.into_iter()
+			.chain(std::iter::once(LimitKey::Login(login.to_lowercase())))
+	}
+
+	fn prune_entry(&self, attempts: &mut VecDeque<Instant>, now: Instant) {
+		while let Some(oldest) = attempts.front() {
+			if now.duration_since(*oldest) > self.window {
+				attempts.pop_front();
+			} else {
+				break;
+			}
+		}
+	}
+
+	/// Whether either the IP or the login has too many recent failures
+	pub fn is_limited(&self, ip: Option<IpAddr>, login: &str) -> bool {
+		let now = Instant::now();
+		Self::keys(ip, login).any(|key| {
+			if let Some(mut attempts) = self.failures.get_mut(&key) {
+				self.prune_entry(&mut attempts, now);
+				attempts.len() >= self.max_failures
+			} else {
+				false
+			}
+		})
+	}
+
+	pub fn record_failure(&self, ip: Option<IpAddr>, login: &str) {
+		let now = Instant::now();
+		for key in Self::keys(ip, login) {
+			let mut attempts = self.failures.entry(key).or_default();
+			self.prune_entry(&mut attempts, now);
+			attempts.push_back(now);
+		}
+	}
+
+	pub fn reset(&self, ip: Option<IpAddr>, login: &str) {
+		for key in Self::keys(ip, login) {
+			self.failures.remove(&key);
+		}
+	}
+
+	/// Drops every expired failure so idle keys don't accumulate forever
+	pub fn prune(&self) {
+		let now = Instant::now();
+		self.failures.retain(|_key, attempts| {
+			self.prune_entry(attempts, now);
+			!attempts.is_empty()
+		});
+	}
+}
@@ -0,0 +1,108 @@
+use crate::dash_type_map::DashTypeMap;
+use crate::database::DbPool;
+use crate::metrics::{Gauge, Metrics};
+use crate::system::SystemStatus;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{Data, Request, Response, State};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::broadcast;
+use tracing::*;
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+	/// Serves `/metrics` only on this separate port, for keeping it internal, instead of on the
+	/// main server. **(default: `None`)**
+	pub port: Option<u16>,
+}
+
+impl Default for MetricsConfig {
+	fn default() -> Self {
+		Self { port: None }
+	}
+}
+
+/// When the request arrived, cached on the request
+struct RequestStart(Instant);
+
+/// Records the count and duration of every request
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+	fn info(&self) -> Info {
+		Info {
+			name: "Request Metrics",
+			kind: Kind::Request | Kind::Response,
+		}
+	}
+
+	async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+		request.local_cache(|| RequestStart(Instant::now()));
+	}
+
+	async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+		let start = request.local_cache(|| RequestStart(Instant::now()));
+		Metrics::global().record_request(
+			request.method().as_str(),
+			response.status().code,
+			start.0.elapsed(),
+		);
+	}
+}
+
+#[rocket::get("/metrics")]
+pub fn metrics(db_pool: &State<DbPool>, data: &State<Arc<DashTypeMap>>) -> (ContentType, String) {
+	let uptime = data
+		.with::<Arc<SystemStatus>, _, _>(|status| status.uptime().as_secs_f64())
+		.unwrap_or_default();
+	let gauges = [
+		Gauge {
+			name: "overbot_uptime_seconds",
+			help: "Seconds since the system started.",
+			value: uptime,
+		},
+		Gauge {
+			name: "overbot_db_pool_connections",
+			help: "Open database pool connections.",
+			value: db_pool.size() as f64,
+		},
+		Gauge {
+			name: "overbot_db_pool_idle_connections",
+			help: "Idle database pool connections.",
+			value: db_pool.num_idle() as f64,
+		},
+	];
+	(
+		ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+		Metrics::global().render(&gauges),
+	)
+}
+
+/// Serves only `/metrics` under `url_root` until `quit`
+pub async fn run_metrics_server(
+	rocket_config: rocket::Config,
+	url_root: String,
+	db_pool: DbPool,
+	data: Arc<DashTypeMap>,
+	quit: broadcast::Sender<()>,
+) -> anyhow::Result<()> {
+	let rocket = rocket::custom(rocket_config)
+		.manage(db_pool)
+		.manage(data)
+		.mount(&url_root, rocket::routes![metrics])
+		.ignite()
+		.await?;
+	let shutdown = rocket.shutdown();
+	let mut on_quit = quit.subscribe();
+	tokio::spawn(async move {
+		let _ = on_quit.recv().await;
+		shutdown.notify();
+	});
+	info!("Launching the metrics server");
+	rocket.launch().await?;
+	info!("Metrics server had a successful shutdown");
+	Ok(())
+}
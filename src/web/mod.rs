@@ -1,28 +1,60 @@
+pub mod access_log;
+pub mod active_requests;
 pub mod auth;
+pub mod compression;
+pub mod console;
+pub mod cors;
+pub mod csrf;
+pub mod https_redirect;
+pub mod login_limiter;
 pub mod macros;
+pub mod metrics;
+pub mod security_headers;
 pub mod static_files;
+pub mod temp_files;
+pub mod uploads;
 
-use crate::accounts::Accounts;
+use crate::accounts::{Accounts, AccountsConfig, AccountsError, AdminRole, ResetTokenSender};
 use crate::dash_type_map::DashTypeMap;
 use crate::database::DbPool;
-use crate::database::Migrations;
-use crate::system::{QuitOnError, System};
-use crate::web::auth::{AuthControl, AuthSession};
+use crate::database::{Migration, Migrations};
+use crate::logger::cache_appender::{Cache, CachedLogRecord};
+use crate::system::{QuitOnError, System, SystemPlugin, SystemStatus};
+use crate::web::access_log::{AccessLog, AccessLogConfig};
+use crate::web::active_requests::{ActiveRequests, ActiveRequestsFairing, ShutdownStatus};
+use crate::web::auth::{
+	AuthControl, AuthSession, RequireRole, SessionCookieConfig, SessionValidationCache,
+};
+use crate::web::compression::{Compression, CompressionConfig};
+use crate::web::console::ConsoleConnections;
+use crate::web::cors::{Cors, CorsConfig};
+use crate::web::csrf::{CsrfToken, HEADER_CSRF_TOKEN};
+use crate::web::login_limiter::LoginRateLimiter;
+use crate::web::metrics::{MetricsConfig, RequestMetrics};
+use crate::web::security_headers::SecurityHeaders;
 use crate::web::static_files::{Assets, StaticFile};
+use anyhow::Context;
 use rocket::config::{Ident, SecretKey, TlsConfig};
 use rocket::data::Limits;
-use rocket::http::{CookieJar, Status};
-use rocket::State;
-use serde::Serializer;
+use rocket::http::{Accept, CookieJar, Status};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::{json, Json, Value};
+use rocket::{Shutdown, State};
+use serde::{Deserialize, Serializer};
+use sqlx::postgres::{PgRow, PgValueRef};
 use sqlx::prelude::*;
-use sqlx::{Column, TypeInfo, ValueRef};
+use sqlx::{Column, Postgres, TypeInfo, ValueRef};
 use std::fmt::Write;
 use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+use time::{OffsetDateTime, PrimitiveDateTime};
 use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
 use tracing::*;
+use uuid::Uuid;
 
 fn secret_key_serialize_zero<S>(_secret_key: &SecretKey, ser: S) -> Result<S::Ok, S::Error>
 where
@@ -42,7 +74,53 @@ where
 	}
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+fn ident_from_string_or_false<'de, D>(de: D) -> Result<Ident, D::Error>
+where
+	D: serde::Deserializer<'de>,
+{
+	#[derive(serde::Deserialize)]
+	#[serde(untagged)]
+	enum IdentValue {
+		Enabled(bool),
+		Ident(String),
+	}
+	match IdentValue::deserialize(de)? {
+		IdentValue::Enabled(false) => Ok(Ident::none()),
+		IdentValue::Enabled(true) => Err(serde::de::Error::custom(
+			"`ident` must be a string or `false`",
+		)),
+		IdentValue::Ident(ident) => new_ident(&ident).map_err(serde::de::Error::custom),
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdentError {
+	#[error("server ident cannot be empty")]
+	Empty,
+	#[error("server ident cannot start with whitespace")]
+	LeadingWhitespace,
+	#[error("server ident can only contain visible ASCII, spaces, and tabs, found {0:?}")]
+	InvalidCharacter(char),
+}
+
+/// Validates the `Server` header value with the same rules as [`Ident::try_new`], but reporting
+/// which rule was broken
+pub fn new_ident(ident: &str) -> Result<Ident, IdentError> {
+	let first = ident.chars().next().ok_or(IdentError::Empty)?;
+	if first.is_whitespace() {
+		return Err(IdentError::LeadingWhitespace);
+	}
+	if let Some(c) = ident
+		.chars()
+		.find(|c| !(c.is_ascii_graphic() || *c == ' ' || *c == '\t'))
+	{
+		return Err(IdentError::InvalidCharacter(c));
+	}
+	Ident::try_new(ident).map_err(|_| IdentError::Empty)
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
 pub struct WebConfig {
 	/// Root path, useful to change if hosted at a non root URL, **(default: "/")**
 	pub url_root: String,
@@ -67,20 +145,38 @@ pub struct WebConfig {
 	/// The string `ident` must be non-empty and may only contain visible ASCII
 	/// characters. The first character cannot be whitespace. The only
 	/// whitespace characters allowed are ` ` (space) and `\t` (horizontal tab).
-	#[serde(serialize_with = "ident_as_string_serializer")]
+	#[serde(
+		serialize_with = "ident_as_string_serializer",
+		deserialize_with = "ident_from_string_or_false"
+	)]
 	pub ident: Ident,
 	/// The 256-bit key for signing and encrypting. **(default: `0`)**
 	///
 	/// If it's zero then it is randomly generated every load, good for testing,
-	/// bad for actual production, so generate something random and set it.
+	/// bad for actual production as every restart logs everyone out, so generate
+	/// one with the `generate-secret-key` command and set it.
 	///
 	/// This will never actually write out a non-zero key for security reasons,
 	/// it's up to the user to set it in the config file
 	#[serde(serialize_with = "secret_key_serialize_zero")]
 	pub secret_key: SecretKey,
-	/// Directory to store temporary files in. **(default:
-	/// [`std::env::temp_dir()`])**
+	/// Refuse to start when `secret_key` is zero instead of just warning. **(default: `false`)**
+	pub require_secret_key: bool,
+	/// Directory to store temporary files in, they are kept within an `overbot`
+	/// subdirectory of it. **(default: [`std::env::temp_dir()`])**
 	pub temp_dir: PathBuf,
+	/// Seconds a temporary file can be left behind before it is considered
+	/// orphaned and removed. **(default: `86400`)**
+	pub temp_file_max_age: u64,
+	/// Seconds between sweeps for orphaned temporary files, one also runs at
+	/// startup. **(default: `3600`)**
+	pub temp_cleanup_interval: u64,
+	/// Directory uploaded files are kept in, relative to the root directory.
+	/// **(default: `"uploads"`)**
+	pub upload_dir: PathBuf,
+	/// Content types that can be uploaded, anything else is rejected.
+	/// **(default: `["image/png", "image/jpeg", "image/gif", "image/webp", "text/plain", "application/pdf"]`)**
+	pub upload_content_types: Vec<String>,
 	/// Max level to log, `normal` or `critical`. **(default: `critical`)**
 	pub log_level: rocket::config::LogLevel,
 	/// The grace period: number of seconds to continue to try to finish
@@ -95,6 +191,52 @@ pub struct WebConfig {
 	pub mercy: u32,
 	/// Whether to use colors and emoji when logging. **(default: `true`)**
 	pub cli_colors: bool,
+	/// Whether debug routes such as the table viewer are mounted at all, never
+	/// enable it in production. **(default: `false`)**
+	pub enable_debug_routes: bool,
+	/// Tables the debug table viewer may display, anything else is a 404.
+	/// **(default: `["_migrations", "accounts"]`)**
+	pub show_table_allowlist: Vec<String>,
+	/// Failed logins allowed per client IP and per login name within the failure window before
+	/// further attempts are rejected. **(default: `5`)**
+	pub login_max_failures: usize,
+	/// The sliding window in seconds that failed logins are counted over. **(default: `300`)**
+	pub login_failure_window: u32,
+	/// The `Content-Security-Policy` header sent with every response, empty to not send one.
+	/// **(default: `"default-src 'self'; object-src 'none'; frame-ancestors 'none'; base-uri 'self'"`)**
+	pub content_security_policy: String,
+	/// The `max-age` in seconds of the `Strict-Transport-Security` header, only sent when `tls` is
+	/// configured, `0` to not send it. **(default: `31536000`)**
+	pub hsts_max_age: u64,
+	/// Cross origin access for a separately hosted frontend, same origin only when `None`.
+	/// **(default: `None`)**
+	pub cors: Option<CorsConfig>,
+	/// Seconds a login session lasts before it has to be refreshed or logged in
+	/// to again, at most `SESSION_MAX_AGE_LIMIT`. **(default: `3600`)**
+	pub session_max_age: u64,
+	/// Name of the login session cookie. **(default: `"user_session"`)**
+	pub cookie_name: String,
+	/// Only send the session cookie over HTTPS, can be disabled for local development without
+	/// `tls`. **(default: `true`)**
+	pub cookie_secure: bool,
+	/// Plain HTTP port to listen on that only redirects to HTTPS, ignored unless `tls` is
+	/// configured. **(default: `None`)**
+	pub https_redirect_port: Option<u16>,
+	/// Gzip compression of text, JSON, JS, and CSS responses, disabled when `None`.
+	/// **(default: `Some(CompressionConfig::default())`)**
+	pub compression: Option<CompressionConfig>,
+	/// `Cache-Control` max-age in seconds of the static files. **(default: `3600`)**
+	pub static_max_age: u32,
+	/// Prometheus metrics at `/metrics`, disabled when `None`. **(default: `None`)**
+	pub metrics: Option<MetricsConfig>,
+	/// Seconds a validated login session is trusted without checking the
+	/// database again, at most `30`, `0` checks on every request. **(default: `10`)**
+	pub session_cache_ttl: u64,
+	/// The most live admin consoles connected at once. **(default: `4`)**
+	pub console_max_connections: usize,
+	/// Logs every request through the log appenders, disabled when `None`.
+	/// **(default: `Some(AccessLogConfig::default())`)**
+	pub access_log: Option<AccessLogConfig>,
 }
 
 impl Default for WebConfig {
@@ -107,29 +249,148 @@ impl Default for WebConfig {
 			keep_alive: 5,
 			limits: Limits::default(),
 			tls: None,
-			ident: Ident::try_new("Overbot").unwrap(),
+			ident: new_ident("Overbot").expect("default ident is valid"),
 			secret_key: rocket::Config::default().secret_key,
+			require_secret_key: false,
 			temp_dir: std::env::temp_dir(),
+			temp_file_max_age: 24 * 60 * 60,
+			temp_cleanup_interval: 60 * 60,
+			upload_dir: PathBuf::from("uploads"),
+			upload_content_types: [
+				"image/png",
+				"image/jpeg",
+				"image/gif",
+				"image/webp",
+				"text/plain",
+				"application/pdf",
+			]
+			.iter()
+			.map(|content_type| (*content_type).to_owned())
+			.collect(),
 			log_level: rocket::config::LogLevel::Critical,
 			grace: 2,
 			mercy: 3,
 			cli_colors: true,
+			enable_debug_routes: false,
+			show_table_allowlist: vec!["_migrations".to_owned(), "accounts".to_owned()],
+			login_max_failures: 5,
+			login_failure_window: 300,
+			content_security_policy:
+				"default-src 'self'; object-src 'none'; frame-ancestors 'none'; base-uri 'self'"
+					.to_owned(),
+			hsts_max_age: 60 * 60 * 24 * 365,
+			cors: None,
+			session_max_age: 60 * 60,
+			cookie_name: "user_session".to_owned(),
+			cookie_secure: true,
+			https_redirect_port: None,
+			compression: Some(CompressionConfig::default()),
+			static_max_age: 60 * 60,
+			metrics: None,
+			session_cache_ttl: 10,
+			console_max_connections: 4,
+			access_log: Some(AccessLogConfig::default()),
 		}
 	}
 }
 
 #[rocket::get("/<path..>", rank = 100)]
-fn static_file(path: PathBuf) -> Option<StaticFile> {
+fn static_file(path: PathBuf, web_config: &State<Arc<WebConfig>>) -> Option<StaticFile> {
 	// Static files are urf-8 only:
 	let path = path.to_str()?;
-	Assets::get(path)
+	Assets::get(path).map(|file| file.with_max_age(web_config.static_max_age))
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct LoginData {
+	login: String,
+	password: String,
+	/// Only required when the account has two factor authentication enabled
+	#[serde(default)]
+	totp_code: Option<String>,
+	/// Also issue a refresh token so `/auth/refresh` can renew the session once it expires
+	#[serde(default)]
+	remember_me: bool,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct ResetTokenRequestData {
+	login: String,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct ResetPasswordData {
+	token: String,
+	password: String,
+	password_check: String,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct TotpCodeData {
+	code: String,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct RegisterData {
+	login: String,
+	password: String,
+	password_check: String,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct BulkAccountData {
+	login: String,
+	password: String,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct RevokeSessionData {
+	/// The masked token as returned from the session listing
+	#[serde(default)]
+	token: Option<String>,
+	#[serde(default)]
+	all: bool,
 }
 
-#[derive(Debug, PartialEq, rocket::FromForm)]
-struct RegisterData<'r> {
-	login: &'r str,
-	password: &'r str,
-	password_check: &'r str,
+type JsonError = (Status, Json<Value>);
+
+fn json_error(status: Status, error: impl std::fmt::Display) -> JsonError {
+	(status, Json(json!({ "error": error.to_string() })))
+}
+
+/// Longest `session_max_age` allowed, browsers cap cookie lifetimes at 400 days anyway
+pub const SESSION_MAX_AGE_LIMIT: u64 = 400 * 24 * 60 * 60;
+
+/// How long the database gets to answer the readiness check
+const READY_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness, answers as long as the web server does
+#[rocket::get("/health")]
+fn health(data: &State<Arc<DashTypeMap>>) -> Json<Value> {
+	let uptime = data
+		.with::<Arc<SystemStatus>, _, _>(|status| status.uptime().as_secs())
+		.ok();
+	Json(json!({ "status": "ok", "uptime_seconds": uptime }))
+}
+
+/// Readiness, only ok while the database answers
+#[rocket::get("/ready")]
+async fn ready(db_pool: &State<DbPool>) -> Result<Json<Value>, JsonError> {
+	let check = sqlx::query_scalar::<_, i32>("SELECT 1").fetch_one(db_pool.inner().as_ref());
+	match tokio::time::timeout(READY_DB_TIMEOUT, check).await {
+		Ok(Ok(_)) => Ok(Json(json!({ "status": "ready" }))),
+		Ok(Err(e)) => {
+			warn!("Readiness check failed: {}", e);
+			Err(json_error(
+				Status::ServiceUnavailable,
+				"database unavailable",
+			))
+		}
+		Err(_elapsed) => {
+			warn!("Readiness check timed out");
+			Err(json_error(Status::ServiceUnavailable, "database timed out"))
+		}
+	}
 }
 
 #[rocket::get("/account")]
@@ -137,88 +398,620 @@ fn account(auth: AuthSession<'_>) -> String {
 	format!("Things: {}", auth.user_session)
 }
 
-#[rocket::get("/auth/login")]
+#[rocket::get("/account/sessions")]
+async fn account_sessions(
+	auth: AuthSession<'_>,
+	db_pool: &State<DbPool>,
+) -> Result<Json<Value>, JsonError> {
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
+	let sessions = Accounts::list_sessions(&mut conn, auth.user_session.id())
+		.await
+		.map_err(|e| json_error(Status::InternalServerError, e))?;
+	let sessions: Vec<Value> = sessions
+		.iter()
+		.map(|session| {
+			json!({
+				"token": session.masked_token(),
+				"inserted_at": session.inserted_at.format("%F %T"),
+				"valid_until": session.valid_until.format("%F %T"),
+				"current": session.token() == auth.user_session.token(),
+			})
+		})
+		.collect();
+	Ok(Json(json!({ "sessions": sessions })))
+}
+
+#[rocket::post("/account/sessions/revoke", data = "<revoke>")]
+async fn account_sessions_revoke(
+	revoke: Json<RevokeSessionData>,
+	_csrf: CsrfToken,
+	auth: AuthSession<'_>,
+	db_pool: &State<DbPool>,
+	cookie_config: &State<SessionCookieConfig>,
+	session_cache: &State<Arc<SessionValidationCache>>,
+	cookies: &CookieJar<'_>,
+) -> Result<Json<Value>, JsonError> {
+	let account_id = auth.user_session.id();
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
+	let (revoked, revoked_current) = if revoke.all {
+		let revoked = Accounts::revoke_all_sessions(&mut conn, account_id)
+			.await
+			.map_err(|e| json_error(Status::InternalServerError, e))?;
+		session_cache.invalidate_account(account_id);
+		(revoked, true)
+	} else if let Some(masked) = &revoke.token {
+		// The client only ever sees masked tokens, so resolve it against this account's sessions
+		let sessions = Accounts::list_sessions(&mut conn, account_id)
+			.await
+			.map_err(|e| json_error(Status::InternalServerError, e))?;
+		let mut matching = sessions.iter().filter(|s| s.matches_masked(masked));
+		let token = match (matching.next(), matching.next()) {
+			(Some(session), None) => session.token(),
+			(Some(_), Some(_)) => {
+				return Err(json_error(Status::Conflict, "ambiguous session token"))
+			}
+			(None, _) => return Err(json_error(Status::NotFound, "unknown session")),
+		};
+		let revoked = Accounts::revoke_session(&mut conn, account_id, token)
+			.await
+			.map_err(|e| json_error(Status::InternalServerError, e))?;
+		session_cache.invalidate(token);
+		(revoked as u64, token == auth.user_session.token())
+	} else {
+		return Err(json_error(
+			Status::BadRequest,
+			"either a session token or `all` is required",
+		));
+	};
+	conn.commit()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "database transaction failed"))?;
+	if revoked_current {
+		cookie_config.remove(cookies);
+	}
+	Ok(Json(
+		json!({ "status": "revoked", "revoked": revoked, "logged_out": revoked_current }),
+	))
+}
+
+#[rocket::post("/account/totp/enroll")]
+async fn account_totp_enroll(
+	_csrf: CsrfToken,
+	auth: AuthSession<'_>,
+	db_pool: &State<DbPool>,
+	accounts_config: &State<Arc<AccountsConfig>>,
+) -> Result<Json<Value>, JsonError> {
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
+	let account = Accounts::account_by_id(&mut conn, auth.user_session.id())
+		.await
+		.map_err(|e| json_error(Status::NotFound, e))?;
+	let enrollment = account
+		.enroll_totp(&mut conn, accounts_config)
+		.await
+		.map_err(|e| json_error(Status::BadRequest, e))?;
+	conn.commit()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "database transaction failed"))?;
+	Ok(Json(json!(enrollment)))
+}
+
+#[rocket::post("/account/totp/confirm", data = "<totp>")]
+async fn account_totp_confirm(
+	totp: Json<TotpCodeData>,
+	_csrf: CsrfToken,
+	auth: AuthSession<'_>,
+	db_pool: &State<DbPool>,
+	accounts_config: &State<Arc<AccountsConfig>>,
+) -> Result<Json<Value>, JsonError> {
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
+	let account = Accounts::account_by_id(&mut conn, auth.user_session.id())
+		.await
+		.map_err(|e| json_error(Status::NotFound, e))?;
+	account
+		.confirm_totp(&mut conn, accounts_config, &totp.code)
+		.await
+		.map_err(|e| json_error(Status::BadRequest, e))?;
+	conn.commit()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "database transaction failed"))?;
+	Ok(Json(json!({ "status": "two factor enabled" })))
+}
+
+#[rocket::post("/admin/accounts/reset_token", data = "<request>")]
+async fn admin_reset_token(
+	request: Json<ResetTokenRequestData>,
+	_csrf: CsrfToken,
+	_admin: RequireRole<'_, AdminRole>,
+	db_pool: &State<DbPool>,
+	accounts_config: &State<Arc<AccountsConfig>>,
+	reset_token_sender: &State<Arc<dyn ResetTokenSender>>,
+) -> Result<Json<Value>, JsonError> {
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
+	let token = Accounts::request_password_reset(
+		&mut conn,
+		accounts_config,
+		reset_token_sender.inner().as_ref(),
+		&request.login,
+	)
+	.await
+	.map_err(|e| match e {
+		AccountsError::AccountNotFound => json_error(Status::NotFound, e),
+		e => json_error(Status::InternalServerError, e),
+	})?;
+	conn.commit()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "database transaction failed"))?;
+	match token {
+		Some(token) => Ok(Json(
+			json!({ "status": "created", "token": token.to_hyphenated().to_string() }),
+		)),
+		None => Ok(Json(json!({ "status": "sent" }))),
+	}
+}
+
+#[rocket::post("/admin/accounts/bulk", data = "<accounts>")]
+async fn admin_create_accounts(
+	accounts: Json<Vec<BulkAccountData>>,
+	_csrf: CsrfToken,
+	_admin: RequireRole<'_, AdminRole>,
+	db_pool: &State<DbPool>,
+	accounts_config: &State<Arc<AccountsConfig>>,
+) -> Result<Json<Value>, JsonError> {
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
+	let requested: Vec<(&str, &str)> = accounts
+		.iter()
+		.map(|account| (account.login.as_str(), account.password.as_str()))
+		.collect();
+	let results = Accounts::create_accounts_bulk(&mut conn, accounts_config, &requested).await;
+	conn.commit()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "database transaction failed"))?;
+	let results: Vec<Value> = accounts
+		.iter()
+		.zip(results)
+		.map(|(requested, result)| match result {
+			Ok(account) => json!({
+				"login": &requested.login,
+				"status": "created",
+				"id": account.id().to_hyphenated().to_string(),
+			}),
+			Err(e) => json!({
+				"login": &requested.login,
+				"status": "failed",
+				"error": e.to_string(),
+			}),
+		})
+		.collect();
+	Ok(Json(json!({ "results": results })))
+}
+
+#[rocket::post("/auth/reset_password", data = "<reset>")]
+async fn reset_password(
+	reset: Json<ResetPasswordData>,
+	_csrf: CsrfToken,
+	db_pool: &State<DbPool>,
+	accounts_config: &State<Arc<AccountsConfig>>,
+) -> Result<Json<Value>, JsonError> {
+	if reset.password != reset.password_check {
+		return Err(json_error(Status::BadRequest, "passwords don't match"));
+	}
+	let token = Uuid::parse_str(&reset.token)
+		.map_err(|_e| json_error(Status::BadRequest, AccountsError::InvalidResetToken))?;
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
+	Accounts::consume_reset_token(&mut conn, accounts_config, token, &reset.password)
+		.await
+		.map_err(|e| json_error(Status::BadRequest, e))?;
+	conn.commit()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "database transaction failed"))?;
+	Ok(Json(json!({ "status": "password reset" })))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/auth/login", data = "<creds>")]
 async fn login(
+	creds: Json<LoginData>,
+	_csrf: CsrfToken,
+	client_ip: Option<IpAddr>,
 	db_pool: &State<DbPool>,
+	accounts_config: &State<Arc<AccountsConfig>>,
+	web_config: &State<Arc<WebConfig>>,
+	limiter: &State<Arc<LoginRateLimiter>>,
 	auth_control: AuthControl<'_>,
 	cookies: &CookieJar<'_>,
-) -> Result<String, (Status, &'static str)> {
+) -> Result<Json<Value>, JsonError> {
 	if auth_control.is_logged_in() {
-		Ok(format!("Already logged in: {:#?}", auth_control))
+		Ok(Json(json!({ "status": "already logged in" })))
+	} else if limiter.is_limited(client_ip, &creds.login) {
+		warn!(
+			"Login rate limited for {} from {:?}",
+			&creds.login, client_ip
+		);
+		Err(json_error(
+			Status::TooManyRequests,
+			"too many failed login attempts, try again later",
+		))
 	} else {
-		auth_control
+		let logged_in = auth_control
 			.login(
 				db_pool,
+				accounts_config,
 				cookies,
-				"username",
-				"super-secret-password",
-				60 * 60,
+				&creds.login,
+				&creds.password,
+				creds.totp_code.as_deref(),
+				web_config.session_max_age,
+				creds.remember_me,
 			)
 			.await
-			.map_err(|_| (Status::Unauthorized, "invalid username or password"))?;
-		Ok(format!("Now logged in: {:#?}", auth_control))
+			.map_err(|e| {
+				limiter.record_failure(client_ip, &creds.login);
+				match e.downcast_ref::<AccountsError>() {
+					Some(AccountsError::InvalidTwoFactorCode) => {
+						json_error(Status::Unauthorized, "invalid two factor code")
+					}
+					Some(AccountsError::AccountLocked) => json_error(
+						Status::Forbidden,
+						"account is locked after too many failed logins, try again later",
+					),
+					_ => json_error(Status::Unauthorized, "invalid username or password"),
+				}
+			})?;
+		if logged_in {
+			limiter.reset(client_ip, &creds.login);
+			Ok(Json(json!({ "status": "logged in" })))
+		} else {
+			Ok(Json(json!({ "status": "two factor required" })))
+		}
 	}
 }
 
-#[rocket::get("/auth/register?<register>")]
-async fn register(
-	register: RegisterData<'_>,
+/// Trades the remember me refresh token cookie for a new session, the refresh cookie is rotated
+/// along with it
+#[rocket::post("/auth/refresh")]
+async fn refresh(
+	_csrf: CsrfToken,
 	db_pool: &State<DbPool>,
-) -> Result<String, (Status, String)> {
-	let mut conn = db_pool.begin().await.map_err(|_e| {
-		(
-			Status::InternalServerError,
-			"unable to access database".to_owned(),
+	accounts_config: &State<Arc<AccountsConfig>>,
+	web_config: &State<Arc<WebConfig>>,
+	auth_control: AuthControl<'_>,
+	cookies: &CookieJar<'_>,
+) -> Result<Json<Value>, JsonError> {
+	let refreshed = auth_control
+		.refresh(
+			db_pool,
+			accounts_config,
+			cookies,
+			web_config.session_max_age,
 		)
-	})?;
+		.await
+		.map_err(|_e| json_error(Status::Unauthorized, "invalid or expired refresh token"))?;
+	if refreshed {
+		Ok(Json(json!({ "status": "refreshed" })))
+	} else {
+		Err(json_error(Status::Unauthorized, "no refresh token"))
+	}
+}
+
+/// The CSRF token to send back in the `X-CSRF-Token` header of every state changing request
+#[rocket::get("/auth/csrf")]
+fn csrf_token(csrf: CsrfToken) -> Json<Value> {
+	Json(json!({ "csrf_token": csrf.as_str(), "header": HEADER_CSRF_TOKEN }))
+}
+
+#[rocket::post("/auth/logout")]
+async fn logout(
+	_csrf: CsrfToken,
+	db_pool: &State<DbPool>,
+	auth_control: AuthControl<'_>,
+	cookies: &CookieJar<'_>,
+) -> Result<String, (Status, &'static str)> {
+	auth_control
+		.logout(db_pool, cookies)
+		.await
+		.map_err(|_| (Status::InternalServerError, "unable to log out"))?;
+	Ok("Logged out".to_owned())
+}
+
+#[rocket::post("/auth/register", data = "<register>")]
+async fn register(
+	register: Json<RegisterData>,
+	_csrf: CsrfToken,
+	db_pool: &State<DbPool>,
+	accounts_config: &State<Arc<AccountsConfig>>,
+) -> Result<(Status, Json<Value>), JsonError> {
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
 	if register.password != register.password_check {
-		return Err((Status::BadRequest, "passwords don't match".to_owned()));
+		return Err(json_error(Status::BadRequest, "passwords don't match"));
 	}
-	let account = Accounts::create_account(&mut conn, register.login)
+	let account = Accounts::create_account(&mut conn, accounts_config, &register.login)
 		.await
-		.map_err(|e| (Status::BadRequest, e.to_string()))?;
+		.map_err(|e| json_error(Status::BadRequest, e))?;
 	account
-		.set_password(&mut conn, None, Some(register.password))
+		.set_password(&mut conn, accounts_config, None, Some(&register.password))
 		.await
-		.map_err(|e| (Status::BadRequest, e.to_string()))?;
-	conn.commit().await.map_err(|_e| {
-		(
-			Status::InternalServerError,
-			"database transaction failed".to_owned(),
-		)
-	})?;
-	Ok("test".to_owned())
+		.map_err(|e| json_error(Status::BadRequest, e))?;
+	conn.commit()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "database transaction failed"))?;
+	Ok((
+		Status::Created,
+		Json(json!({ "status": "registered", "login": &register.login })),
+	))
+}
+
+/// Decodes a text-format value (as returned by a raw simple query) into a readable string based
+/// on the name of its postgres type.
+pub(crate) fn decode_text_value(raw: PgValueRef<'_>, type_name: &str) -> Option<String> {
+	match type_name {
+		"INT2" => <i16 as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.to_string()),
+		"INT4" => <i32 as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.to_string()),
+		"INT8" => <i64 as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.to_string()),
+		"FLOAT4" => <f32 as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.to_string()),
+		"FLOAT8" => <f64 as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.to_string()),
+		"BOOL" => <bool as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.to_string()),
+		"UUID" => <Uuid as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.to_string()),
+		"TIMESTAMP" => <PrimitiveDateTime as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.format("%F %T")),
+		"TIMESTAMPTZ" => <OffsetDateTime as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(|v| v.format("%F %T %z")),
+		// Text, json, jsonb, and most everything else is already readable in text format
+		_ => <&str as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map(str::to_owned),
+	}
+}
+
+const LOGS_DEFAULT_CACHE: &str = "tui_log_view";
+const LOGS_DEFAULT_LIMIT: usize = 100;
+const LOGS_MAX_LIMIT: usize = 1024;
+
+/// Recent log entries from a log cache appender, filtered to `level` and more severe
+#[rocket::get("/logs?<cache>&<level>&<limit>")]
+fn logs(
+	cache: Option<&str>,
+	level: Option<&str>,
+	limit: Option<usize>,
+	_auth: AuthSession<'_>,
+) -> Result<Json<Value>, JsonError> {
+	let level = match level {
+		Some(level) => tracing::log::Level::from_str(level)
+			.map_err(|_e| json_error(Status::BadRequest, "unknown log level"))?,
+		None => tracing::log::Level::Trace,
+	};
+	let limit = limit.unwrap_or(LOGS_DEFAULT_LIMIT).min(LOGS_MAX_LIMIT);
+	let entries: Vec<Value> = Cache::snapshot(cache.unwrap_or(LOGS_DEFAULT_CACHE), level, limit)
+		.iter()
+		.map(log_record_json)
+		.collect();
+	Ok(Json(json!({ "logs": entries })))
+}
+
+pub(crate) fn log_record_json(record: &CachedLogRecord) -> Value {
+	json!({
+		"level": record.level().as_str(),
+		"time": record.time().format(time::Format::Rfc3339),
+		"target": record.target(),
+		"module": record.module(),
+		"msg": record.msg(),
+	})
 }
 
-#[rocket::get("/db/tables/<table>")]
+/// Live log entries from a log cache appender as server sent json events named by their level,
+/// filtered to `level` and more severe, ends on shutdown.  Entries are skipped when the client can't keep up.
+#[rocket::get("/logs/stream?<cache>&<level>")]
+fn logs_stream(
+	cache: Option<&str>,
+	level: Option<&str>,
+	_auth: AuthSession<'_>,
+	mut shutdown: Shutdown,
+) -> Result<EventStream![], JsonError> {
+	let level = match level {
+		Some(level) => tracing::log::Level::from_str(level)
+			.map_err(|_e| json_error(Status::BadRequest, "unknown log level"))?,
+		None => tracing::log::Level::Trace,
+	};
+	let mut records = Cache::subscribe(cache.unwrap_or(LOGS_DEFAULT_CACHE))
+		.ok_or_else(|| json_error(Status::NotFound, "unknown log cache"))?;
+	Ok(EventStream! {
+		loop {
+			let record = tokio::select! {
+				record = records.recv() => match record {
+					Ok(record) => record,
+					Err(broadcast::error::RecvError::Closed) => break,
+					Err(broadcast::error::RecvError::Lagged(_)) => continue,
+				},
+				_ = &mut shutdown => break,
+			};
+			if record.level() <= level {
+				yield Event::json(&log_record_json(&record)).event(record.level().as_str());
+			}
+		}
+	})
+}
+
+#[derive(rocket::Responder)]
+enum TableResponse {
+	Text(String),
+	Json(Json<Value>),
+}
+
+/// Converts a row fetched with the raw simple query protocol, so every value is in text format,
+/// into a json object by column name, typed by each column's postgres type.
+pub(crate) fn row_to_json(row: &PgRow) -> Value {
+	let mut object = serde_json::Map::new();
+	for column in row.columns() {
+		let value = match row.try_get_raw(column.ordinal()) {
+			Ok(raw) if raw.is_null() => Value::Null,
+			Ok(raw) => text_value_to_json(raw, column.type_info().name()),
+			Err(_) => Value::Null,
+		};
+		object.insert(column.name().to_owned(), value);
+	}
+	Value::Object(object)
+}
+
+fn text_value_to_json(raw: PgValueRef<'_>, type_name: &str) -> Value {
+	match type_name {
+		"INT2" | "INT4" | "INT8" => <&str as Decode<Postgres>>::decode(raw)
+			.ok()
+			.and_then(|v| v.parse::<i64>().ok())
+			.map_or(Value::Null, Value::from),
+		// Non-finite floats have no json number, so they stay strings like "NaN"
+		"FLOAT4" | "FLOAT8" | "NUMERIC" => {
+			<&str as Decode<Postgres>>::decode(raw)
+				.ok()
+				.map_or(Value::Null, |v| {
+					v.parse::<f64>()
+						.ok()
+						.and_then(serde_json::Number::from_f64)
+						.map_or_else(|| Value::from(v), Value::Number)
+				})
+		}
+		"BOOL" => <&str as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map_or(Value::Null, |v| Value::Bool(v == "t" || v == "true")),
+		"JSON" | "JSONB" => <&str as Decode<Postgres>>::decode(raw)
+			.ok()
+			.map_or(Value::Null, |v| {
+				serde_json::from_str(v).unwrap_or_else(|_| Value::from(v))
+			}),
+		_ => decode_text_value(raw, type_name).map_or(Value::Null, Value::from),
+	}
+}
+
+/// Renders a row fetched with the raw simple query protocol as a tab separated line
+fn row_to_text(row: &PgRow) -> String {
+	let mut line = String::new();
+	for column in row.columns() {
+		let raw = row.try_get_raw(column.ordinal()).unwrap();
+		if raw.is_null() {
+			line.push_str("{null}");
+		} else if let Some(data) = decode_text_value(raw, column.type_info().name()) {
+			line.push_str(&data);
+		} else {
+			line.push_str("{unsupported-type}");
+		}
+		line.push('\t');
+	}
+	line.push('\n');
+	line
+}
+
+const SHOW_TABLE_DEFAULT_LIMIT: u32 = 100;
+const SHOW_TABLE_MAX_LIMIT: u32 = 1000;
+
+#[rocket::get("/db/tables/<table>?<limit>&<offset>")]
 async fn show_table(
 	table: &str,
+	limit: Option<u32>,
+	offset: Option<u32>,
 	_auth: AuthSession<'_>,
 	db_pool: &State<DbPool>,
-) -> Result<String, String> {
+	web_config: &State<Arc<WebConfig>>,
+	accept: Option<&Accept>,
+) -> Result<TableResponse, (Status, String)> {
+	if !web_config.show_table_allowlist.iter().any(|t| t == table) {
+		return Err((Status::NotFound, "unknown table".to_owned()));
+	}
+	let exists = sqlx::query_scalar::<_, String>(
+		"SELECT table_name::text FROM information_schema.tables WHERE table_schema = current_schema() AND table_name = $1",
+	)
+	.bind(table)
+	.fetch_optional(db_pool.inner().as_ref())
+	.await
+	.map_err(|e| (Status::InternalServerError, e.to_string()))?;
+	if exists.is_none() {
+		return Err((Status::NotFound, "unknown table".to_owned()));
+	}
+	let limit = limit
+		.unwrap_or(SHOW_TABLE_DEFAULT_LIMIT)
+		.min(SHOW_TABLE_MAX_LIMIT);
+	let offset = offset.unwrap_or(0);
+	// The raw simple query protocol can't bind parameters, but these are both plain integers, and
+	// one extra row is fetched to know if there are more
 	let query = format!(
-		"SELECT * FROM {}",
-		table
-			.chars()
-			.filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.')
-			.collect::<String>()
+		"SELECT * FROM \"{}\" LIMIT {} OFFSET {}",
+		table.replace('"', "\"\""),
+		limit as u64 + 1,
+		offset
 	);
 	// Have to use raw sql via a connection directly so binary encoding isn't used, which breaks on
 	// some PGSQL types and sqlx isn't accounting for that...
-	let rows = db_pool
+	let mut rows = db_pool
 		.inner()
 		.acquire()
 		.await
-		.map_err(|e| e.to_string())?
+		.map_err(|e| (Status::InternalServerError, e.to_string()))?
 		.fetch_all(query.as_str())
 		.await
-		.map_err(|e| e.to_string())?;
+		.map_err(|e| (Status::InternalServerError, e.to_string()))?;
 	// // Using a string query because sqlx isn't capable of interpolating a table name into position
 	// let rows = sqlx::query(query.as_str())
 	// 	.bind(table)
 	// 	.fetch_all(db_pool.inner().as_ref())
 	// 	.await
 	// 	.map_err(|e| e.to_string())?;
+	let has_more = rows.len() > limit as usize;
+	rows.truncate(limit as usize);
+	if accept.map_or(false, |accept| accept.preferred().media_type().is_json()) {
+		let columns: Vec<Value> = rows
+			.first()
+			.map(|row| {
+				row.columns()
+					.iter()
+					.map(
+						|column| json!({ "name": column.name(), "type": column.type_info().name() }),
+					)
+					.collect()
+			})
+			.unwrap_or_default();
+		return Ok(TableResponse::Json(Json(json!({
+			"columns": columns,
+			"rows": rows.iter().map(row_to_json).collect::<Vec<_>>(),
+			"next_offset": if has_more { Some(offset as u64 + limit as u64) } else { None },
+		}))));
+	}
 	let mut ret = String::new();
 	if let Some(row) = rows.first() {
 		for column in row.columns() {
@@ -227,29 +1020,23 @@ async fn show_table(
 				column.name(),
 				column.type_info().name()
 			))
-			.map_err(|e| e.to_string())?;
+			.map_err(|e| (Status::InternalServerError, e.to_string()))?;
 		}
 	}
 	ret.push('\n');
-	rows.into_iter().for_each(|row| {
-		for column in row.columns() {
-			let raw = row.try_get_raw(column.ordinal()).unwrap();
-			if raw.is_null() {
-				ret.push_str("{null}");
-			} else if let Ok(data) = <&str as Decode<_>>::decode(raw.clone()) {
-				ret.push_str(data);
-			// } else if let Ok(data) = <i32 as Decode<_>>::decode(raw.clone()) {
-			// 	let _ = ret.write_fmt(format_args!("{}", data));
-			// } else if let Ok(data) = <bool as Decode<_>>::decode(raw.clone()) {
-			// 	let _ = ret.write_fmt(format_args!("{}", data));
-			} else {
-				ret.push_str("{unsupported-type}");
-			}
-			ret.push('\t');
-		}
-		ret.push('\n');
-	});
-	Ok(ret)
+	for row in &rows {
+		ret.push_str(&row_to_text(row));
+	}
+	if has_more {
+		ret.write_fmt(format_args!(
+			"{{more rows available at offset {}}}\n",
+			offset as u64 + limit as u64
+		))
+		.map_err(|e| (Status::InternalServerError, e.to_string()))?;
+	} else {
+		ret.push_str("{no more rows}\n");
+	}
+	Ok(TableResponse::Text(ret))
 }
 
 impl WebConfig {
@@ -261,22 +1048,179 @@ impl WebConfig {
 	}
 
 	pub async fn runner(
-		url_root: String,
+		config: Arc<WebConfig>,
 		rocket_config: rocket::Config,
 		db_pool: DbPool,
+		allow_checksum_override: bool,
 		data: Arc<DashTypeMap>,
 		quit: broadcast::Sender<()>,
 	) -> anyhow::Result<()> {
-		MIGRATIONS.migrate_up(&db_pool).await.quit_on_err(&quit)?;
+		let accounts_config = data
+			.wait_clone_if_arc::<AccountsConfig>(Duration::from_secs(60))
+			.await
+			.quit_on_err(&quit)?;
+		// After the accounts tables are ready as uploads reference them
+		MIGRATIONS
+			.migrate_up(&db_pool, allow_checksum_override)
+			.await
+			.quit_on_err(&quit)?;
+		// Registered by the accounts system before its config
+		let reset_token_sender = data
+			.with::<Arc<dyn ResetTokenSender>, _, _>(Clone::clone)
+			.quit_on_err(&quit)?;
+		// Shared with a restarted web task so failures aren't forgotten by crashing it
+		let limiter = data.get_or_insert_with(|| {
+			LoginRateLimiter::new(
+				config.login_max_failures,
+				Duration::from_secs(config.login_failure_window as u64),
+			)
+		});
+		{
+			let limiter = limiter.clone();
+			let mut on_quit = quit.subscribe();
+			let mut prune = tokio::time::interval(Duration::from_secs(
+				config.login_failure_window.max(1) as u64,
+			));
+			tokio::spawn(async move {
+				loop {
+					tokio::select! {
+						_ = on_quit.recv() => break,
+						_ = prune.tick() => limiter.prune(),
+					}
+				}
+			});
+		}
+		// Shared with a restarted web task so sessions revoked just before it aren't forgotten
+		let session_cache = data.get_or_insert_with(|| {
+			SessionValidationCache::new(Duration::from_secs(config.session_cache_ttl))
+		});
+		{
+			let session_cache = session_cache.clone();
+			let mut on_quit = quit.subscribe();
+			let mut prune = tokio::time::interval(auth::SESSION_CACHE_MAX_TTL);
+			tokio::spawn(async move {
+				loop {
+					tokio::select! {
+						_ = on_quit.recv() => break,
+						_ = prune.tick() => session_cache.prune(),
+					}
+				}
+			});
+		}
+
+		temp_files::spawn_cleanup(
+			rocket_config.temp_dir.clone(),
+			Duration::from_secs(config.temp_file_max_age),
+			Duration::from_secs(config.temp_cleanup_interval.max(1)),
+			&quit,
+		)
+		.context("failed creating the web temp directory")
+		.quit_on_err(&quit)?;
+		std::fs::create_dir_all(&config.upload_dir)
+			.with_context(|| {
+				format!(
+					"failed creating the upload directory {:?}",
+					config.upload_dir
+				)
+			})
+			.quit_on_err(&quit)?;
+
+		if config.cookie_secure && config.tls.is_none() {
+			warn!("Session cookies are secure only but TLS isn't configured, logins will only work behind an HTTPS proxy or on localhost, set `cookie_secure` to false for plain HTTP development");
+		}
+
+		// Shared with a restarted web task so the last shutdown status stays inspectable
+		let active_requests = data.get_or_insert_with(ActiveRequests::default);
+		active_requests.reset();
 
 		info!("Building the web UI");
-		let rocket = rocket::custom(rocket_config)
-			.manage(db_pool)
-			.manage(data)
+		let mut rocket = rocket::custom(rocket_config.clone())
+			.manage(db_pool.clone())
+			.manage(accounts_config)
+			.manage(limiter)
+			.manage(session_cache)
+			.manage(reset_token_sender)
+			.manage(data.clone())
+			.manage(SessionCookieConfig {
+				name: config.cookie_name.clone(),
+				secure: config.cookie_secure,
+			})
+			.manage(ConsoleConnections::new(config.console_max_connections))
+			.manage(config.clone())
+			.attach(SecurityHeaders {
+				content_security_policy: config.content_security_policy.clone(),
+				hsts_max_age: Some(config.hsts_max_age)
+					.filter(|max_age| *max_age > 0 && config.tls.is_some()),
+			})
+			.attach(ActiveRequestsFairing(active_requests.clone()))
 			.mount(
-				&url_root,
-				rocket::routes![static_file, account, login, register, show_table],
+				&config.url_root,
+				rocket::routes![
+					static_file,
+					health,
+					ready,
+					account,
+					account_sessions,
+					account_sessions_revoke,
+					account_totp_enroll,
+					account_totp_confirm,
+					admin_reset_token,
+					admin_create_accounts,
+					reset_password,
+					csrf_token,
+					login,
+					refresh,
+					logout,
+					register,
+					logs,
+					logs_stream,
+					console::console_events,
+					console::console_command,
+					uploads::upload,
+					uploads::download
+				],
 			);
+		if config.enable_debug_routes {
+			warn!("Web debug routes are enabled, they expose database contents and must not be enabled in production");
+			rocket = rocket.mount(&config.url_root, rocket::routes![show_table]);
+		}
+		if let Some(cors) = &config.cors {
+			rocket = rocket.attach(Cors(cors.clone()));
+		}
+		if let Some(access_log) = &config.access_log {
+			rocket = rocket.attach(AccessLog(access_log.clone()));
+		}
+		if let Some(compression) = &config.compression {
+			rocket = rocket.attach(Compression(compression.clone()));
+		}
+		if let Some(MetricsConfig { port }) = &config.metrics {
+			rocket = rocket.attach(RequestMetrics);
+			match *port {
+				None => rocket = rocket.mount(&config.url_root, rocket::routes![metrics::metrics]),
+				Some(port) => {
+					let metrics_config = rocket::Config {
+						port,
+						..rocket_config.clone()
+					};
+					let url_root = config.url_root.clone();
+					let db_pool = db_pool.clone();
+					let quit = quit.clone();
+					tokio::spawn(async move {
+						if let Err(e) = metrics::run_metrics_server(
+							metrics_config,
+							url_root,
+							db_pool,
+							data,
+							quit,
+						)
+						.await
+						{
+							error!("Metrics server failed: {:?}", e);
+						}
+					});
+				}
+			}
+		}
 
 		info!("Igniting the rocket web UI");
 		let rocket = rocket.ignite().await.quit_on_err(&quit)?;
@@ -288,15 +1232,60 @@ impl WebConfig {
 			shutdown.notify();
 		});
 
+		if let (Some(redirect_port), Some(_tls)) = (config.https_redirect_port, &config.tls) {
+			let redirect_config = rocket::Config {
+				port: redirect_port,
+				tls: None,
+				..rocket_config.clone()
+			};
+			let url_root = config.url_root.clone();
+			let https_port = config.port;
+			let quit = quit.clone();
+			tokio::spawn(async move {
+				if let Err(e) =
+					https_redirect::run_redirect_server(redirect_config, url_root, https_port, quit)
+						.await
+				{
+					error!("HTTP to HTTPS redirect server failed: {:?}", e);
+				}
+			});
+		}
+
 		info!("Launching the rocket web ui");
 		rocket.launch().await.quit_on_err(&quit)?;
 
+		match active_requests.finish_shutdown() {
+			ShutdownStatus::Drained => {
+				info!("Rocket Web UI drained cleanly, no requests were outstanding")
+			}
+			ShutdownStatus::ForceTerminated(outstanding) => warn!(
+				"Rocket Web UI force-terminated {} requests still outstanding after the {}s grace and {}s mercy periods",
+				outstanding, config.grace, config.mercy
+			),
+		}
 		info!("Rocket Web UI had a successful shutdown");
 		let _ = quit.send(());
 		Ok(())
 	}
+}
+
+impl WebConfig {
+	/// Sets the `Server` header value, `None` for no header at all
+	pub fn set_ident(&mut self, ident: Option<&str>) -> Result<(), IdentError> {
+		self.ident = match ident {
+			Some(ident) => new_ident(ident)?,
+			None => Ident::none(),
+		};
+		Ok(())
+	}
+}
 
-	pub fn spawn(&self, system: &System) -> JoinHandle<anyhow::Result<()>> {
+#[typetag::serde]
+impl SystemPlugin for WebConfig {
+	fn spawn(&self, system: &System) -> Option<JoinHandle<anyhow::Result<()>>> {
+		if self.secret_key.is_zero() {
+			warn!("Web secret_key is not set so a random one is used, every session is invalidated on restart, generate one with the `generate-secret-key` command");
+		}
 		let rocket_config = rocket::Config {
 			address: self.address,
 			port: self.port,
@@ -306,7 +1295,7 @@ impl WebConfig {
 			tls: self.tls.clone(),
 			ident: self.ident.clone(),
 			secret_key: self.secret_key.clone(),
-			temp_dir: self.temp_dir.clone(),
+			temp_dir: temp_files::temp_dir(&self.temp_dir),
 			log_level: self.log_level,
 			shutdown: rocket::config::Shutdown {
 				// `ctrlc` and signals are already handled by the bot system
@@ -321,14 +1310,98 @@ impl WebConfig {
 			..Default::default()
 		};
 
-		tokio::spawn(Self::runner(
-			self.url_root.clone(),
+		let mut config = self.clone();
+		config.upload_dir = system.root_path.join(&self.upload_dir);
+		Some(tokio::spawn(Self::runner(
+			Arc::new(config),
 			rocket_config,
 			system.db_pool.clone(),
+			system.allow_checksum_override(),
 			system.registered_data.clone(),
 			system.quit.clone(),
-		))
+		)))
 	}
 }
 
-const MIGRATIONS: Migrations = Migrations::new("Web", &[]);
+const MIGRATIONS: Migrations = Migrations::new(
+	"Web",
+	&[Migration::new("Create web_files table").up(r#"
+				CREATE TABLE web_files (
+					id uuid NOT NULL DEFAULT gen_random_uuid(),
+					owner uuid NOT NULL,
+					content_type text NOT NULL,
+					size bigint NOT NULL,
+					inserted_at timestamp without time zone NOT NULL DEFAULT now(),
+					CONSTRAINT web_files_pkey PRIMARY KEY (id),
+					CONSTRAINT web_files_owner_fkey FOREIGN KEY (owner) REFERENCES accounts (id) MATCH SIMPLE ON UPDATE CASCADE ON DELETE CASCADE
+				) WITH ( OIDS=FALSE );
+				"#).down(r#"
+				DROP TABLE web_files;
+				"#)],
+);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database::test_support::test_pool;
+
+	/// Fetches with the raw simple query protocol like `show_table` does, so every value is text
+	async fn fetch_raw(conn: &mut crate::database::DbTransaction<'_>, query: &str) -> Vec<PgRow> {
+		conn.fetch_all(query).await.unwrap()
+	}
+
+	#[tokio::test]
+	async fn show_table_renders_uuid_and_timestamp_columns_of_accounts() {
+		let pool = match test_pool(&[&crate::accounts::MIGRATIONS]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let mut conn = pool.begin().await.unwrap();
+		let id = sqlx::query_scalar::<_, Uuid>("INSERT INTO accounts DEFAULT VALUES RETURNING id")
+			.fetch_one(&mut conn)
+			.await
+			.unwrap();
+		let rows = fetch_raw(
+			&mut conn,
+			&format!("SELECT id, inserted_at FROM accounts WHERE id = '{}'", id),
+		)
+		.await;
+		assert_eq!(rows.len(), 1);
+		let line = row_to_text(&rows[0]);
+		let mut values = line.trim_end_matches('\n').split('\t');
+		assert_eq!(values.next(), Some(id.to_string().as_str()));
+		let inserted_at = values.next().unwrap();
+		assert!(
+			PrimitiveDateTime::parse(inserted_at, "%F %T").is_ok(),
+			"unreadable timestamp: {}",
+			inserted_at
+		);
+		assert!(!line.contains("{unsupported-type}"), "{}", line);
+	}
+
+	#[tokio::test]
+	async fn row_to_json_keeps_nulls_and_parses_json_columns() {
+		let pool = match test_pool(&[]).await {
+			Some(pool) => pool,
+			None => return,
+		};
+		let mut conn = pool.begin().await.unwrap();
+		let rows = fetch_raw(
+			&mut conn,
+			r#"SELECT NULL::text AS nothing, NULL::jsonb AS no_json, '{"a": [1, 2]}'::json AS doc, '{"b": true}'::jsonb AS binary_doc, 42::int4 AS int, 'NaN'::float8 AS nan"#,
+		)
+		.await;
+		assert_eq!(rows.len(), 1);
+		assert_eq!(
+			row_to_json(&rows[0]),
+			json!({
+				"nothing": null,
+				"no_json": null,
+				"doc": { "a": [1, 2] },
+				"binary_doc": { "b": true },
+				"int": 42,
+				"nan": "NaN",
+			})
+		);
+	}
+}
@@ -0,0 +1,42 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+
+/// Adds the security headers to every response, not replacing any a route set itself
+pub struct SecurityHeaders {
+	pub content_security_policy: String,
+	/// Only sent when serving over TLS, `None` to never send it
+	pub hsts_max_age: Option<u64>,
+}
+
+#[rocket::async_trait]
+impl Fairing for SecurityHeaders {
+	fn info(&self) -> Info {
+		Info {
+			name: "Security Headers",
+			kind: Kind::Response,
+		}
+	}
+
+	async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
+		let mut set_default = |name: &'static str, value: String| {
+			if !response.headers().contains(name) {
+				response.set_header(Header::new(name, value));
+			}
+		};
+		if !self.content_security_policy.is_empty() {
+			set_default(
+				"Content-Security-Policy",
+				self.content_security_policy.clone(),
+			);
+		}
+		set_default("X-Content-Type-Options", "nosniff".to_owned());
+		set_default("X-Frame-Options", "DENY".to_owned());
+		if let Some(max_age) = self.hsts_max_age {
+			set_default(
+				"Strict-Transport-Security",
+				format!("max-age={}; includeSubDomains", max_age),
+			);
+		}
+	}
+}
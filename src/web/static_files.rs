@@ -1,17 +1,209 @@
-use rocket::http::ContentType;
+use dashmap::DashMap;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use sha2::Digest;
 use std::borrow::Cow;
+use std::io::Cursor;
+use std::ops::Range;
 use std::path::Path;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
 
-#[derive(rocket::Responder)]
 pub struct StaticFile {
 	pub data: Cow<'static, [u8]>,
 	pub content_type: ContentType,
+	/// Weak ETag of the content
+	pub etag: String,
+	/// `Cache-Control` max-age in seconds
+	pub max_age: u32,
+}
+
+impl StaticFile {
+	pub fn with_max_age(self, max_age: u32) -> Self {
+		Self { max_age, ..self }
+	}
+
+	fn is_not_modified(&self, request: &Request<'_>) -> bool {
+		// `If-None-Match` takes precedence, `If-Modified-Since` is only the fallback
+		if let Some(if_none_match) = request.headers().get_one("If-None-Match") {
+			let etag = self.etag.trim_start_matches("W/");
+			return if_none_match
+				.split(',')
+				.map(str::trim)
+				.any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag);
+		}
+		match (
+			request.headers().get_one("If-Modified-Since"),
+			assets_last_modified(),
+		) {
+			(Some(if_modified_since), Some(last_modified)) => {
+				PrimitiveDateTime::parse(if_modified_since, HTTP_DATE_FORMAT)
+					.map_or(false, |since| since.assume_utc() >= last_modified)
+			}
+			_ => false,
+		}
+	}
+}
+
+/// What a `Range` header asks of a body
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+	/// No usable range, multiple ranges included as they're allowed to be answered in full
+	Full,
+	Partial(Range<usize>),
+	Unsatisfiable,
+}
+
+impl RangeRequest {
+	fn parse(range: &str, len: usize) -> Self {
+		let spec = match range.trim().strip_prefix("bytes=") {
+			Some(spec) if !spec.contains(',') => spec.trim(),
+			_ => return Self::Full,
+		};
+		let (start, end) = match spec.split_once('-') {
+			Some(bounds) => bounds,
+			None => return Self::Full,
+		};
+		let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+			// `bytes=-N` is the last N bytes
+			(Err(_), Ok(suffix)) if start.is_empty() => {
+				if suffix == 0 {
+					return Self::Unsatisfiable;
+				}
+				len.saturating_sub(suffix)..len
+			}
+			(Ok(start), Err(_)) if end.is_empty() => start..len,
+			(Ok(start), Ok(end)) if start <= end => start..(end + 1).min(len),
+			_ => return Self::Full,
+		};
+		if range.start >= len {
+			Self::Unsatisfiable
+		} else {
+			Self::Partial(range)
+		}
+	}
+}
+
+impl StaticFile {
+	/// A range is only honored if `If-Range` is missing or still matches this content
+	fn range_request(&self, request: &Request<'_>) -> RangeRequest {
+		let range = match request.headers().get_one("Range") {
+			Some(range) => range,
+			None => return RangeRequest::Full,
+		};
+		if let Some(if_range) = request.headers().get_one("If-Range") {
+			let if_range = if_range.trim();
+			let matches = if if_range.starts_with('"') || if_range.starts_with("W/") {
+				// Ranges are meant to need a strong tag, but this weak one is a hash of the full
+				// content so it's just as exact
+				if_range.trim_start_matches("W/") == self.etag.trim_start_matches("W/")
+			} else {
+				match (
+					PrimitiveDateTime::parse(if_range, HTTP_DATE_FORMAT),
+					assets_last_modified(),
+				) {
+					(Ok(date), Some(last_modified)) => date.assume_utc() == last_modified,
+					_ => false,
+				}
+			};
+			if !matches {
+				return RangeRequest::Full;
+			}
+		}
+		RangeRequest::parse(range, self.data.len())
+	}
+}
+
+impl<'r> Responder<'r, 'static> for StaticFile {
+	fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+		let mut response = Response::build();
+		response
+			.raw_header("ETag", self.etag.clone())
+			.raw_header("Cache-Control", format!("public, max-age={}", self.max_age))
+			.raw_header("Accept-Ranges", "bytes");
+		if let Some(last_modified) = assets_last_modified() {
+			response.raw_header("Last-Modified", last_modified.format(HTTP_DATE_FORMAT));
+		}
+		if self.is_not_modified(request) {
+			response.status(Status::NotModified);
+			return response.ok();
+		}
+		let len = self.data.len();
+		match self.range_request(request) {
+			RangeRequest::Full => {
+				response
+					.header(self.content_type)
+					.sized_body(len, Cursor::new(self.data));
+			}
+			RangeRequest::Partial(range) => {
+				let data = match self.data {
+					Cow::Borrowed(data) => Cow::Borrowed(&data[range.clone()]),
+					Cow::Owned(data) => Cow::Owned(data[range.clone()].to_vec()),
+				};
+				response
+					.status(Status::PartialContent)
+					.raw_header(
+						"Content-Range",
+						format!("bytes {}-{}/{}", range.start, range.end - 1, len),
+					)
+					.header(self.content_type)
+					.sized_body(data.len(), Cursor::new(data));
+			}
+			RangeRequest::Unsatisfiable => {
+				response
+					.status(Status::RangeNotSatisfiable)
+					.raw_header("Content-Range", format!("bytes */{}", len));
+			}
+		}
+		response.ok()
+	}
 }
 
 #[derive(rust_embed::RustEmbed)]
 #[folder = "assets/web/dist/"]
 pub struct StaticAssets;
 
+lazy_static::lazy_static! {
+	/// Only filled in release mode where the embedded bytes can never change
+	static ref ETAGS: DashMap<String, String> = DashMap::new();
+	/// The assets are embedded so they're as new as this process, whole seconds as HTTP dates are
+	static ref STARTED_AT: OffsetDateTime = {
+		let now = OffsetDateTime::now_utc();
+		now - time::Duration::nanoseconds(now.nanosecond() as i64)
+	};
+}
+
+/// `None` in debug mode as the files are read from the file system and can change at any time
+fn assets_last_modified() -> Option<OffsetDateTime> {
+	if cfg!(debug_assertions) {
+		None
+	} else {
+		Some(*STARTED_AT)
+	}
+}
+
+fn etag_of(file_path: &str, data: &[u8]) -> String {
+	if !cfg!(debug_assertions) {
+		if let Some(etag) = ETAGS.get(file_path) {
+			return etag.clone();
+		}
+	}
+	let hash = sha2::Sha256::digest(data);
+	let etag = format!(
+		"W/\"{}\"",
+		hash[..16]
+			.iter()
+			.map(|b| format!("{:02x}", b))
+			.collect::<String>()
+	);
+	if !cfg!(debug_assertions) {
+		ETAGS.insert(file_path.to_owned(), etag.clone());
+	}
+	etag
+}
+
 pub struct Assets;
 
 impl Assets {
@@ -24,6 +216,119 @@ impl Assets {
 			} else {
 				ContentType::Binary
 			};
-		Some(StaticFile { data, content_type })
+		let etag = etag_of(file_path, &data);
+		Some(StaticFile {
+			data,
+			content_type,
+			etag,
+			max_age: 0,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rocket::http::Header;
+	use rocket::local::blocking::Client;
+
+	const BODY: &[u8] = b"0123456789";
+	const ETAG: &str = "W/\"0123456789abcdef\"";
+
+	#[rocket::get("/file")]
+	fn file() -> StaticFile {
+		StaticFile {
+			data: Cow::Borrowed(BODY),
+			content_type: ContentType::Plain,
+			etag: ETAG.to_owned(),
+			max_age: 0,
+		}
+	}
+
+	fn client() -> Client {
+		Client::tracked(rocket::build().mount("/", rocket::routes![file])).unwrap()
+	}
+
+	#[test]
+	fn parses_full_partial_and_invalid_ranges() {
+		assert_eq!(
+			RangeRequest::parse("bytes=2-4", 10),
+			RangeRequest::Partial(2..5)
+		);
+		assert_eq!(
+			RangeRequest::parse("bytes=7-", 10),
+			RangeRequest::Partial(7..10)
+		);
+		assert_eq!(
+			RangeRequest::parse("bytes=-3", 10),
+			RangeRequest::Partial(7..10)
+		);
+		assert_eq!(
+			RangeRequest::parse("bytes=5-100", 10),
+			RangeRequest::Partial(5..10)
+		);
+		assert_eq!(RangeRequest::parse("bytes=0-1,4-5", 10), RangeRequest::Full);
+		assert_eq!(RangeRequest::parse("items=0-1", 10), RangeRequest::Full);
+		assert_eq!(RangeRequest::parse("bytes=4-2", 10), RangeRequest::Full);
+		assert_eq!(RangeRequest::parse("bytes=a-b", 10), RangeRequest::Full);
+		assert_eq!(
+			RangeRequest::parse("bytes=10-", 10),
+			RangeRequest::Unsatisfiable
+		);
+		assert_eq!(
+			RangeRequest::parse("bytes=-0", 10),
+			RangeRequest::Unsatisfiable
+		);
+	}
+
+	#[test]
+	fn responds_in_full_without_a_range() {
+		let client = client();
+		let response = client.get("/file").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.headers().get_one("Accept-Ranges"), Some("bytes"));
+		assert_eq!(response.headers().get_one("Content-Range"), None);
+		assert_eq!(response.into_bytes().unwrap(), BODY);
+	}
+
+	#[test]
+	fn responds_with_only_the_requested_range() {
+		let client = client();
+		let response = client
+			.get("/file")
+			.header(Header::new("Range", "bytes=2-4"))
+			.dispatch();
+		assert_eq!(response.status(), Status::PartialContent);
+		assert_eq!(
+			response.headers().get_one("Content-Range"),
+			Some("bytes 2-4/10")
+		);
+		assert_eq!(response.into_bytes().unwrap(), b"234");
+	}
+
+	#[test]
+	fn rejects_a_range_past_the_end() {
+		let client = client();
+		let response = client
+			.get("/file")
+			.header(Header::new("Range", "bytes=20-30"))
+			.dispatch();
+		assert_eq!(response.status(), Status::RangeNotSatisfiable);
+		assert_eq!(
+			response.headers().get_one("Content-Range"),
+			Some("bytes */10")
+		);
+	}
+
+	#[test]
+	fn ignores_the_range_when_if_range_no_longer_matches() {
+		let client = client();
+		let response = client
+			.get("/file")
+			.header(Header::new("Range", "bytes=2-4"))
+			.header(Header::new("If-Range", "W/\"stale\""))
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.into_bytes().unwrap(), BODY);
 	}
 }
@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+use tracing::*;
+
+/// Subdirectory of the configured `temp_dir` that uploads are written to, only it is ever swept so
+/// unrelated temporary files are never touched
+pub const TEMP_SUBDIR: &str = "overbot";
+
+pub fn temp_dir(configured: &Path) -> PathBuf {
+	configured.join(TEMP_SUBDIR)
+}
+
+/// Removes the files directly within `dir` last modified longer than `max_age` ago, returns how
+/// many were removed
+pub fn sweep(dir: &Path, max_age: Duration) -> std::io::Result<usize> {
+	let now = SystemTime::now();
+	let mut removed = 0;
+	for entry in std::fs::read_dir(dir)? {
+		let entry = entry?;
+		let metadata = entry.metadata()?;
+		if !metadata.is_file() {
+			continue;
+		}
+		let age = metadata
+			.modified()
+			.ok()
+			.and_then(|modified| now.duration_since(modified).ok());
+		if age.map_or(false, |age| age > max_age) {
+			match std::fs::remove_file(entry.path()) {
+				Ok(()) => removed += 1,
+				// Already cleaned up by the request that made it
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+				Err(e) => warn!(
+					"Failed removing orphaned temp file {:?}: {}",
+					entry.path(),
+					e
+				),
+			}
+		}
+	}
+	Ok(removed)
+}
+
+/// Sweeps `dir` now and then every `interval` until quit
+pub fn spawn_cleanup(
+	dir: PathBuf,
+	max_age: Duration,
+	interval: Duration,
+	quit: &broadcast::Sender<()>,
+) -> std::io::Result<()> {
+	std::fs::create_dir_all(&dir)?;
+	let mut on_quit = quit.subscribe();
+	let mut interval = tokio::time::interval(interval);
+	tokio::spawn(async move {
+		loop {
+			tokio::select! {
+				_ = on_quit.recv() => break,
+				_ = interval.tick() => {
+					let dir = dir.clone();
+					match tokio::task::spawn_blocking(move || sweep(&dir, max_age)).await {
+						Ok(Ok(0)) => (),
+						Ok(Ok(removed)) => info!("Removed {} orphaned temp files", removed),
+						Ok(Err(e)) => warn!("Failed sweeping the temp directory: {}", e),
+						Err(e) => error!("Temp directory sweep panicked: {}", e),
+					}
+				}
+			}
+		}
+	});
+	Ok(())
+}
@@ -0,0 +1,112 @@
+//! Authenticated file uploads, the bytes are kept in `WebConfig.upload_dir` named by their id with
+//! the uploader and content type recorded in the `web_files` table.
+
+use super::{json_error, JsonError, WebConfig};
+use crate::database::DbPool;
+use crate::web::auth::AuthSession;
+use crate::web::csrf::CsrfToken;
+use rocket::form::Form;
+use rocket::fs::{NamedFile, TempFile};
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::{json, Json, Value};
+use rocket::State;
+use std::sync::Arc;
+use tracing::*;
+use uuid::Uuid;
+
+#[derive(rocket::FromForm)]
+pub struct Upload<'r> {
+	/// Limited in size by the `file` limit of `WebConfig.limits`
+	file: TempFile<'r>,
+}
+
+/// Takes a multipart form with a `file` field, rocket streams it to the temp directory as it
+/// arrives so the upload is never held in memory, then it is copied into the upload directory.
+#[rocket::post("/upload", data = "<upload>")]
+pub async fn upload(
+	mut upload: Form<Upload<'_>>,
+	_csrf: CsrfToken,
+	auth: AuthSession<'_>,
+	db_pool: &State<DbPool>,
+	config: &State<Arc<WebConfig>>,
+) -> Result<(Status, Json<Value>), JsonError> {
+	let content_type = upload
+		.file
+		.content_type()
+		.map(|content_type| content_type.media_type().to_string())
+		.ok_or_else(|| json_error(Status::UnsupportedMediaType, "missing content type"))?;
+	if !config
+		.upload_content_types
+		.iter()
+		.any(|allowed| allowed.eq_ignore_ascii_case(&content_type))
+	{
+		return Err(json_error(
+			Status::UnsupportedMediaType,
+			format!("content type `{}` is not allowed", content_type),
+		));
+	}
+	let size = upload.file.len() as i64;
+	let mut conn = db_pool
+		.begin()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?;
+	let id = sqlx::query_scalar::<_, Uuid>(
+		"INSERT INTO web_files (owner, content_type, size) VALUES ($1, $2, $3) RETURNING id",
+	)
+	.bind(auth.user_session.id())
+	.bind(&content_type)
+	.bind(size)
+	.fetch_one(&mut conn)
+	.await
+	.map_err(|_e| json_error(Status::InternalServerError, "unable to record the upload"))?;
+	// Copied rather than moved as the temp directory may be on another filesystem, the temp file
+	// is removed when the upload is dropped
+	upload
+		.file
+		.copy_to(config.upload_dir.join(id.to_hyphenated().to_string()))
+		.await
+		.map_err(|e| {
+			error!("Failed storing upload {}: {}", id, e);
+			json_error(Status::InternalServerError, "unable to store the upload")
+		})?;
+	conn.commit()
+		.await
+		.map_err(|_e| json_error(Status::InternalServerError, "database transaction failed"))?;
+	info!(
+		"Stored upload {} of {} bytes of {} from {}",
+		id,
+		size,
+		content_type,
+		auth.user_session.id()
+	);
+	Ok((
+		Status::Created,
+		Json(
+			json!({ "id": id.to_hyphenated().to_string(), "content_type": content_type, "size": size }),
+		),
+	))
+}
+
+#[rocket::get("/files/<id>")]
+pub async fn download(
+	id: &str,
+	_auth: AuthSession<'_>,
+	db_pool: &State<DbPool>,
+	config: &State<Arc<WebConfig>>,
+) -> Result<(ContentType, NamedFile), JsonError> {
+	let id = Uuid::parse_str(id).map_err(|_e| json_error(Status::NotFound, "no such file"))?;
+	let content_type =
+		sqlx::query_scalar::<_, String>("SELECT content_type FROM web_files WHERE id = $1")
+			.bind(id)
+			.fetch_optional(db_pool.inner().as_ref())
+			.await
+			.map_err(|_e| json_error(Status::InternalServerError, "unable to access database"))?
+			.ok_or_else(|| json_error(Status::NotFound, "no such file"))?;
+	let file = NamedFile::open(config.upload_dir.join(id.to_hyphenated().to_string()))
+		.await
+		.map_err(|_e| json_error(Status::NotFound, "no such file"))?;
+	Ok((
+		ContentType::parse_flexible(&content_type).unwrap_or(ContentType::Binary),
+		file,
+	))
+}